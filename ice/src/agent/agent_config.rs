@@ -1,4 +1,6 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use util::vnet::net::*;
@@ -9,6 +11,7 @@ use crate::mdns::*;
 use crate::network_type::*;
 use crate::udp_network::UDPNetwork;
 use crate::url::*;
+use crate::util::{BufferPool, ObservedMappings, RelayMetrics};
 
 /// The interval at which the agent performs candidate checks in the connecting phase.
 pub(crate) const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_millis(200);
@@ -43,6 +46,32 @@ pub(crate) const MAX_BUFFER_SIZE: usize = 1000 * 1000; // 1MB
 /// Wait time before binding requests can be deleted.
 pub(crate) const MAX_BINDING_REQUEST_TIMEOUT: Duration = Duration::from_millis(4000);
 
+/// The default endpoint of the local relay that STUN traffic is forwarded through.
+pub(crate) const DEFAULT_RELAY_ADDR: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12345));
+
+/// The initial retransmission timeout of relayed STUN requests.
+pub(crate) const DEFAULT_RELAY_RTO: Duration = Duration::from_millis(500);
+
+/// The lowest initial retransmission timeout used for relayed STUN requests.
+pub(crate) const DEFAULT_RELAY_MIN_RTO: Duration = Duration::from_millis(100);
+
+/// The retransmission timeout of relayed STUN requests stops doubling here, RFC 6298
+/// allows an upper bound as long as it is at least 60 seconds.
+pub(crate) const MAX_RELAY_RTO: Duration = Duration::from_secs(60);
+
+/// Max retransmissions of a relayed STUN request before giving up.
+pub(crate) const DEFAULT_RELAY_MAX_RETRANSMITS: u32 = 7;
+
+/// The largest STUN message expected back through the relay, excluding its framing.
+pub(crate) const DEFAULT_RELAY_MTU: usize = 1280;
+
+/// Extra time granted to a relayed STUN request on top of its deadline.
+pub(crate) const DEFAULT_RELAY_TIMEOUT_PADDING: Duration = Duration::from_millis(200);
+
+/// Leaves relay frames untagged, as for an agent that has the relay to itself.
+pub const NO_SESSION_ID: u16 = 0;
+
 pub(crate) fn default_candidate_types() -> Vec<CandidateType> {
     vec![
         CandidateType::Host,
@@ -54,6 +83,301 @@ pub(crate) fn default_candidate_types() -> Vec<CandidateType> {
 pub type InterfaceFilterFn = Box<dyn (Fn(&str) -> bool) + Send + Sync>;
 pub type IpFilterFn = Box<dyn (Fn(IpAddr) -> bool) + Send + Sync>;
 
+/// Declarative allow and deny lists of interface names, e.g. loaded from a config file,
+/// as an alternative to writing an `InterfaceFilterFn`. Patterns may use `*` for any
+/// run of characters and `?` for a single one, like `"docker*"`.
+///
+/// A name matching an allow pattern is accepted even if it also matches a deny pattern.
+/// Otherwise names matching a deny pattern are rejected, and the remaining ones are
+/// accepted only if the allow list is empty.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InterfacePolicy {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl InterfacePolicy {
+    /// Returns whether the interface `name` passes the policy.
+    pub fn allows(&self, name: &str) -> bool {
+        let matches = |patterns: &[String]| patterns.iter().any(|p| glob_match(p, name));
+        if matches(&self.allow) {
+            return true;
+        }
+        !matches(&self.deny) && self.allow.is_empty()
+    }
+
+    /// Turns the policy into a filter for `AgentConfig::interface_filter`.
+    pub fn into_filter(self) -> InterfaceFilterFn {
+        Box::new(move |name: &str| self.allows(name))
+    }
+}
+
+/// Matches `name` against `pattern`, where `*` matches any run of characters and `?`
+/// a single character.
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was seen and how much of `name` it has swallowed
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` swallow one more character
+                Some((star, swallowed)) => {
+                    backtrack = Some((star, swallowed + 1));
+                    p = star + 1;
+                    n = swallowed + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// How STUN requests reach the STUN server.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum RelayMode {
+    /// Wrapped in relay frames and sent through the relay.
+    #[default]
+    Relayed,
+    /// Sent straight to the STUN server, e.g. to test against a public server.
+    Direct,
+}
+
+/// Selects how a relay frame encodes the length of its header contents.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RelayFrameVersion {
+    /// A single length byte, limiting the contents to 255 bytes. Understood by every relay.
+    #[default]
+    V1,
+    /// `RELAY_FRAME_V2_MARKER` followed by a 2 byte length, for larger contents such as
+    /// relayed application data.
+    V2,
+}
+
+/// Picks the relay each STUN request starts with when several are configured, so
+/// successive requests rotate over them. Clones of a `RelayConfig` share it.
+#[derive(Debug, Default)]
+pub struct RelaySelector {
+    next: AtomicUsize,
+}
+
+impl RelaySelector {
+    /// Returns the index of the relay in turn out of `count` relays.
+    pub fn next(&self, count: usize) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % count.max(1)
+    }
+}
+
+// The rotation is state, not configuration
+impl PartialEq for RelaySelector {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for RelaySelector {}
+
+/// Describes the local relay (e.g. quicheperf) the agent forwards its STUN traffic through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayConfig {
+    /// The endpoint the relay is listening on.
+    pub relay_addr: SocketAddr,
+    /// Relay endpoints that STUN requests are spread over round robin, falling back to
+    /// the next one when a relay does not answer. When empty, `relay_addr` is the only
+    /// relay.
+    pub relay_addrs: Vec<SocketAddr>,
+    /// Tracks whose turn it is among `relay_addrs`.
+    pub relay_selector: Arc<RelaySelector>,
+    /// The initial retransmission timeout, doubled after every retransmission up to
+    /// `MAX_RELAY_RTO`. A fast local relay may warrant less than the 500ms RFC 5389
+    /// recommends, but never less than `min_rto`.
+    pub rto: Duration,
+    /// The floor of `rto`, keeps a mistuned deployment from flooding the relay.
+    pub min_rto: Duration,
+    /// How often a STUN request is retransmitted before giving up.
+    pub max_retransmits: u32,
+    /// The largest STUN message that can be received, the relay framing comes on top.
+    pub mtu: usize,
+    /// Added to the deadline of a STUN request to account for the indirection through
+    /// the relay.
+    pub relay_timeout_padding: Duration,
+    /// Whether sockets bound on the real network are registered with an `OpenSocket`
+    /// frame sent to `relay_addr`, and its acknowledgement awaited, before they are used.
+    /// Without it the relay does not know where to deliver the frames for a socket.
+    pub open_socket: bool,
+    /// Whether STUN requests go through the relay at all. Without the `relay` feature
+    /// they are always sent directly.
+    pub mode: RelayMode,
+    /// Whether the sockets used for STUN share their port, so every STUN server is
+    /// queried from the same local port and sees the same NAT mapping. Ports are then
+    /// taken from the start of the port range instead of at random.
+    pub reuse_port: bool,
+    /// Sent as the SOFTWARE attribute of STUN binding requests when set, some servers
+    /// log it or refuse requests without one.
+    pub software: Option<String>,
+    /// Whether STUN binding requests carry a FINGERPRINT attribute. A FINGERPRINT on a
+    /// response is verified either way.
+    pub use_fingerprint: bool,
+    /// Reuses the receive buffers of STUN requests when set, instead of allocating one
+    /// per request.
+    pub buffer_pool: Option<Arc<BufferPool>>,
+    /// When set, gathering first sends a single `OpenSocket` frame and gives up with
+    /// `ErrRelayUnavailable` unless the relay acknowledges it within this time. This
+    /// keeps a relay that is not running from costing the full deadline per server.
+    /// The check registers the socket just like `open_socket` does.
+    pub relay_check_timeout: Option<Duration>,
+    /// Tags the frames exchanged with the relay, so a relay shared by several agents can
+    /// hand each its own frames beyond telling them apart by address. Frames tagged for
+    /// another session are rejected. `NO_SESSION_ID`, the default, sends untagged frames.
+    pub session_id: u16,
+    /// The framing of the frames sent to the relay. `V2` lifts the 255 byte limit of
+    /// the header contents, but needs a relay that understands it.
+    pub frame_version: RelayFrameVersion,
+    /// Sent to the relay as the source of our frames in place of the address
+    /// the socket is bound to. Needed when that address is not the one the relay should
+    /// answer to, e.g. a socket bound to `0.0.0.0` or behind a NAT on the host.
+    pub advertised_local_addr: Option<SocketAddr>,
+    /// Counts how the datagrams received by STUN requests are classified when set.
+    /// Clones of a `RelayConfig` share it.
+    pub relay_metrics: Option<Arc<RelayMetrics>>,
+    /// The ports of the relay that application data is sent to, by the controlling and
+    /// the controlled agent respectively, in place of the port of the relay. With
+    /// `None`, the default, it takes the same way as connectivity checks.
+    pub data_ports: Option<(u16, u16)>,
+    /// Records the address each STUN server reported for us through the relay when
+    /// set. Clones of a `RelayConfig` share it, agents always set one.
+    pub observed_mappings: Option<Arc<ObservedMappings>>,
+}
+
+impl RelayConfig {
+    /// Returns the relay endpoint to use from a socket bound to `local_addr`. A loopback
+    /// relay is reached over the loopback of the socket's address family, so a relay on
+    /// `127.0.0.1` is contacted on `::1` from IPv6 sockets and vice versa.
+    pub fn relay_addr_for(&self, local_addr: SocketAddr) -> SocketAddr {
+        relay_for_family(self.relay_addr, local_addr)
+    }
+
+    /// Returns the relays to try, in order, for the next STUN request from a socket
+    /// bound to `local_addr`: the relay in turn followed by the others as fallbacks.
+    /// Every call moves on to the next relay.
+    pub fn next_relays_for(&self, local_addr: SocketAddr) -> Vec<SocketAddr> {
+        if self.relay_addrs.is_empty() {
+            return vec![self.relay_addr_for(local_addr)];
+        }
+        let start = self.relay_selector.next(self.relay_addrs.len());
+        let (before, after) = self.relay_addrs.split_at(start);
+        after
+            .iter()
+            .chain(before)
+            .map(|&relay_addr| relay_for_family(relay_addr, local_addr))
+            .collect()
+    }
+
+    /// Returns the relay in turn for a frame sent from a socket bound to `local_addr`.
+    /// Unlike `next_relays_for` there is no fallback, as nothing waits for an answer.
+    pub fn next_relay_for(&self, local_addr: SocketAddr) -> SocketAddr {
+        if self.relay_addrs.is_empty() {
+            return self.relay_addr_for(local_addr);
+        }
+        let relay_addr = self.relay_addrs[self.relay_selector.next(self.relay_addrs.len())];
+        relay_for_family(relay_addr, local_addr)
+    }
+
+    /// Returns the config application data of the `controlling` or controlled agent is
+    /// sent with, the relays moved to the matching port of `data_ports`.
+    pub fn for_data(&self, controlling: bool) -> RelayConfig {
+        let Some((controlling_port, controlled_port)) = self.data_ports else {
+            return self.clone();
+        };
+        let port = if controlling {
+            controlling_port
+        } else {
+            controlled_port
+        };
+        let with_port = |relay_addr: SocketAddr| SocketAddr::new(relay_addr.ip(), port);
+        RelayConfig {
+            relay_addr: with_port(self.relay_addr),
+            relay_addrs: self.relay_addrs.iter().copied().map(with_port).collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the address announced to the relay as the source of frames sent from a
+    /// socket bound to `local_addr`, `advertised_local_addr` if set.
+    pub fn advertised_addr_for(&self, local_addr: SocketAddr) -> SocketAddr {
+        self.advertised_local_addr.unwrap_or(local_addr)
+    }
+
+    /// Returns the retransmission timeout of the first attempt, `rto` raised to `min_rto`.
+    pub fn initial_rto(&self) -> Duration {
+        self.rto.max(self.min_rto)
+    }
+
+    /// Returns the retransmission timeout following `rto`, twice as long but no longer
+    /// than `MAX_RELAY_RTO` unless `rto` already was.
+    pub fn next_rto(&self, rto: Duration) -> Duration {
+        rto.saturating_mul(2).min(MAX_RELAY_RTO.max(rto))
+    }
+
+    /// Returns how long a relayed STUN request with the given `deadline` waits for its
+    /// response.
+    pub fn response_timeout(&self, deadline: Duration) -> Duration {
+        deadline + self.relay_timeout_padding
+    }
+}
+
+/// Reaches a loopback `relay_addr` over the loopback of `local_addr`'s family.
+fn relay_for_family(relay_addr: SocketAddr, local_addr: SocketAddr) -> SocketAddr {
+    if !relay_addr.ip().is_loopback() || relay_addr.is_ipv4() == local_addr.is_ipv4() {
+        return relay_addr;
+    }
+    let loopback = if local_addr.is_ipv4() {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    } else {
+        IpAddr::V6(Ipv6Addr::LOCALHOST)
+    };
+    SocketAddr::new(loopback, relay_addr.port())
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            relay_addr: DEFAULT_RELAY_ADDR,
+            relay_addrs: vec![],
+            relay_selector: Arc::default(),
+            rto: DEFAULT_RELAY_RTO,
+            min_rto: DEFAULT_RELAY_MIN_RTO,
+            max_retransmits: DEFAULT_RELAY_MAX_RETRANSMITS,
+            mtu: DEFAULT_RELAY_MTU,
+            relay_timeout_padding: DEFAULT_RELAY_TIMEOUT_PADDING,
+            open_socket: true,
+            mode: RelayMode::default(),
+            reuse_port: false,
+            software: None,
+            use_fingerprint: false,
+            buffer_pool: None,
+            relay_check_timeout: None,
+            session_id: NO_SESSION_ID,
+            frame_version: RelayFrameVersion::V1,
+            advertised_local_addr: None,
+            relay_metrics: None,
+            data_ports: None,
+            observed_mappings: None,
+        }
+    }
+}
+
 /// Collects the arguments to `ice::Agent` construction into a single structure, for
 /// future-proofness of the interface.
 #[derive(Default)]
@@ -158,12 +482,31 @@ pub struct AgentConfig {
     /// the ips which are used to gather ICE candidates.
     pub ip_filter: Arc<Option<IpFilterFn>>,
 
+    /// Gathers host candidates for link-local addresses (`169.254.0.0/16`, `fe80::/10`)
+    /// as well, which are skipped by default since they are rarely reachable.
+    pub include_link_local: bool,
+
+    /// Caps the number of addresses host candidates are gathered for, which keeps the
+    /// check list small on hosts with many virtual interfaces. Global addresses are kept
+    /// over private ones. `None` gathers all addresses.
+    pub max_host_candidates: Option<usize>,
+
     /// Controls if self-signed certificates are accepted when connecting to TURN servers via TLS or
     /// DTLS.
     pub insecure_skip_verify: bool,
 
     /// Signals the IP endpoint the agent should locally relay STUN packets to
     pub relay_listener_endpoint: Option<String>,
+
+    /// Configures the local relay STUN packets are sent through. Takes precedence over
+    /// `relay_listener_endpoint` if both are set.
+    pub relay_config: Option<RelayConfig>,
+
+    /// Connects an external program to the agent's relay traffic. Frames received on the
+    /// first channel are decoded into `IceCommands` the agent carries out, and every
+    /// change of the connection state is reported on the second. Taken by `Agent::new`.
+    #[cfg(feature = "relay")]
+    pub external_channels: Option<(mpsc::Receiver<Vec<u8>>, mpsc::Sender<String>)>,
 }
 
 impl AgentConfig {
@@ -223,6 +566,32 @@ impl AgentConfig {
             a.check_interval = self.check_interval;
         }
 
+        if let Some(relay_config) = &self.relay_config {
+            a.relay_config = relay_config.clone();
+        } else if let Some(endpoint) = &self.relay_listener_endpoint {
+            match endpoint.parse::<SocketAddr>() {
+                Ok(relay_addr) => {
+                    a.relay_config = RelayConfig {
+                        relay_addr,
+                        ..Default::default()
+                    }
+                }
+                Err(err) => {
+                    log::warn!(
+                        "invalid relay listener endpoint {}: {}, falling back to {}",
+                        endpoint,
+                        err,
+                        DEFAULT_RELAY_ADDR
+                    );
+                    a.relay_config = RelayConfig::default();
+                }
+            }
+        } else {
+            a.relay_config = RelayConfig::default();
+        }
+        a.relay_config
+            .observed_mappings
+            .get_or_insert_with(Arc::default);
     }
 
     pub(crate) fn init_ext_ip_mapping(
@@ -1,14 +1,87 @@
-use std::{collections::VecDeque, io::{self, Result}, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, sync::Arc};
-use tokio::sync::Mutex;
-use log::error;
+use std::{collections::{HashMap, VecDeque}, fmt, io::{self, Result}, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6}, sync::Arc};
+use tokio::sync::{mpsc, Mutex, Notify};
+use log::{error, warn};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use stun::message::{Getter, Message, BINDING_SUCCESS};
+use stun::xoraddr::XorMappedAddress;
+
+use crate::error::Error;
+use crate::util::ObservedMappings;
 
 pub const MAX_STUN_DATA: usize = 1500;
-pub const SEND_INFO_PACKET_TYPE : u8 = 0xAA;
+pub const SEND_INFO_PACKET_TYPE : u8 = RelayPacketType::SendInfo as u8;
+pub const RECV_INFO_PACKET_TYPE : u8 = RelayPacketType::RecvInfo as u8;
+pub const OPEN_SOCKET_PACKET_TYPE : u8 = RelayPacketType::OpenSocket as u8;
+pub const OPEN_SOCKET_ACK_PACKET_TYPE : u8 = RelayPacketType::OpenSocketAck as u8;
+
+/// Family tags prefixed to every serialized address
+pub const ADDR_FAMILY_IPV4 : u8 = 0x04;
+pub const ADDR_FAMILY_IPV6 : u8 = 0x06;
+/// An IPv6 address followed by its 4 byte scope id, used for link-local addresses
+pub const ADDR_FAMILY_IPV6_SCOPED : u8 = 0x07;
+
+// Serialized address size: 1:family + IP + 2:port
+const IPV4_ADDR_LEN : usize = 1 + 4 + 2;
+const IPV6_ADDR_LEN : usize = 1 + 16 + 2;
+const IPV6_SCOPED_ADDR_LEN : usize = IPV6_ADDR_LEN + 4;
+
+/// The packet type and length bytes starting every relay frame
+pub const RELAY_HEADER_LEN : usize = 2;
+
+/// The big endian session id between packet type and length of frames tagged with one
+pub const SESSION_ID_LEN : usize = 2;
+pub use super::agent_config::{RelayFrameVersion, NO_SESSION_ID};
+
+/// Takes the place of the length byte in `RelayFrameVersion::V2` frames, which carry
+/// a big endian 2 byte length right after it. `V1` frames never announce a length
+/// this small, their shortest payload is an IPv4 address.
+pub const RELAY_FRAME_V2_MARKER : u8 = 0x02;
+/// The packet type, version marker and 2 byte length starting a `V2` frame
+pub const RELAY_HEADER_V2_LEN : usize = 4;
+
+/// The largest relay header: packet type, session id, version marker, 2 byte length
+/// and two scoped IPv6 addresses
+pub const MAX_RELAY_HEADER_LEN : usize = RELAY_HEADER_V2_LEN + SESSION_ID_LEN + 2 * IPV6_SCOPED_ADDR_LEN;
+
+/// The leading byte of every frame exchanged with the relay.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RelayPacketType {
+    /// Sent by us, tells the relay from which socket to which address to forward.
+    SendInfo = 0xAA,
+    /// Sent by the relay, tells us from which address a packet arrived on which socket.
+    RecvInfo = 0xCC,
+    /// Sent by us, registers a socket with the relay before any traffic is forwarded.
+    OpenSocket = 0xBB,
+    /// Sent by the relay once the socket of an `OpenSocket` frame is set up.
+    OpenSocketAck = 0xDD,
+}
+
+impl RelayPacketType {
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+}
+
+impl TryFrom<u8> for RelayPacketType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        match value {
+            SEND_INFO_PACKET_TYPE => Ok(RelayPacketType::SendInfo),
+            RECV_INFO_PACKET_TYPE => Ok(RelayPacketType::RecvInfo),
+            OPEN_SOCKET_PACKET_TYPE => Ok(RelayPacketType::OpenSocket),
+            OPEN_SOCKET_ACK_PACKET_TYPE => Ok(RelayPacketType::OpenSocketAck),
+            _ => Err(Error::ErrUnknownRelayPacketType),
+        }
+    }
+}
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum IceCommands {
     StunRequest {
-        data: String,
+        data: Vec<u8>,
         from: SocketAddr,
         to: SocketAddr,
     },
@@ -22,183 +95,757 @@ pub enum IceCommands {
     },
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// Ordered by `from`, then `to`, so collections of routes sort deterministically.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct SendInfo {
-    // Size as u8 should be enough: 
-    // Max. SocketAddr size == 16:IPv6 + 2:Port = 18 ; 2 * 18 = 36
+    // Size as u8 should be enough:
+    // Max. SocketAddr size == 1:Family + 16:IPv6 + 2:Port = 19 ; 2 * 19 = 38
     pub from: SocketAddr,
     pub to: SocketAddr,
 }
 
+impl IceCommands {
+    /// Decodes a frame received from the relay into the command matching its
+    /// leading packet type. Everything after the send info is the STUN message.
+    pub fn decode(buf: &[u8]) -> std::result::Result<IceCommands, Error> {
+        let packet_type = match buf.first() {
+            Some(&packet_type) => RelayPacketType::try_from(packet_type)?,
+            None => return Err(Error::ErrTruncatedRelayFrame),
+        };
+        match packet_type {
+            RelayPacketType::SendInfo => {
+                let (send_info, payload) = split_send_info(buf)?;
+                Ok(IceCommands::StunRequest {
+                    data: payload.to_vec(),
+                    from: send_info.from,
+                    to: send_info.to,
+                })
+            }
+            RelayPacketType::RecvInfo => {
+                let (send_info, payload) = split_send_info(buf)?;
+                if payload.len() > MAX_STUN_DATA {
+                    return Err(Error::ErrStunDataTooLarge);
+                }
+                let mut data = [0u8; MAX_STUN_DATA];
+                data[..payload.len()].copy_from_slice(payload);
+                Ok(IceCommands::StunResponse {
+                    data,
+                    len: payload.len(),
+                    from: send_info.from,
+                })
+            }
+            RelayPacketType::OpenSocket => Ok(IceCommands::OpenSocket {
+                addr: decode_open_socket(buf)?,
+            }),
+            // Acknowledgements are consumed by `open_relay_socket`, they carry no command
+            RelayPacketType::OpenSocketAck => Err(Error::ErrUnknownRelayPacketType),
+        }
+    }
+}
+
+/// Separates the send info header of a frame from the STUN message following it.
+pub(crate) fn split_send_info(buf: &[u8]) -> std::result::Result<(SendInfo, &[u8]), Error> {
+    let (header, payload) = split_relay_header(buf)?;
+    Ok((SendInfo::decode_payload(header)?, payload))
+}
+
+/// Returns the offset of the data following a `V1` relay header whose length byte is `len`.
+pub fn relay_payload_offset(len: u8) -> usize {
+    RELAY_HEADER_LEN + len as usize
+}
+
+/// Returns the version of the relay frame `buf`, the length of its header and the
+/// length of the header contents following it.
+pub(crate) fn relay_header_lens(buf: &[u8]) -> std::result::Result<(RelayFrameVersion, usize, usize), Error> {
+    match *buf.get(1).ok_or(Error::ErrTruncatedRelayFrame)? {
+        RELAY_FRAME_V2_MARKER => {
+            let len: [u8; 2] = buf.get(2..RELAY_HEADER_V2_LEN)
+                .and_then(|len| len.try_into().ok())
+                .ok_or(Error::ErrTruncatedRelayFrame)?;
+            Ok((RelayFrameVersion::V2, RELAY_HEADER_V2_LEN, u16::from_be_bytes(len) as usize))
+        },
+        len => Ok((RelayFrameVersion::V1, RELAY_HEADER_LEN, len as usize)),
+    }
+}
+
+/// Splits a relay frame of either version into the header contents announced by its
+/// length and the data following them, failing with `ErrTruncatedRelayFrame` if `buf`
+/// is shorter than announced.
+pub(crate) fn split_relay_header(buf: &[u8]) -> std::result::Result<(&[u8], &[u8]), Error> {
+    let (_, header_len, len) = relay_header_lens(buf)?;
+    let offset = header_len + len;
+    if buf.len() < offset {
+        return Err(Error::ErrTruncatedRelayFrame);
+    }
+    Ok((&buf[header_len..offset], &buf[offset..]))
+}
+
+/// The serialized form of `IceCommands`. The STUN data of a response is written
+/// as its first `len` bytes rather than the whole fixed size buffer.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+enum IceCommandsRepr {
+    StunRequest {
+        data: Vec<u8>,
+        from: SocketAddr,
+        to: SocketAddr,
+    },
+    StunResponse {
+        data: Vec<u8>,
+        from: SocketAddr,
+    },
+    OpenSocket {
+        addr: SocketAddr,
+    },
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for IceCommands {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let repr = match self {
+            IceCommands::StunRequest { data, from, to } => IceCommandsRepr::StunRequest {
+                data: data.clone(),
+                from: *from,
+                to: *to,
+            },
+            IceCommands::StunResponse { data, len, from } => IceCommandsRepr::StunResponse {
+                data: data[..*len].to_vec(),
+                from: *from,
+            },
+            IceCommands::OpenSocket { addr } => IceCommandsRepr::OpenSocket { addr: *addr },
+        };
+        repr.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for IceCommands {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(match IceCommandsRepr::deserialize(deserializer)? {
+            IceCommandsRepr::StunRequest { data, from, to } => {
+                IceCommands::StunRequest { data, from, to }
+            }
+            IceCommandsRepr::StunResponse { data: payload, from } => {
+                if payload.len() > MAX_STUN_DATA {
+                    return Err(serde::de::Error::custom(Error::ErrStunDataTooLarge));
+                }
+                let mut data = [0u8; MAX_STUN_DATA];
+                data[..payload.len()].copy_from_slice(&payload);
+                IceCommands::StunResponse {
+                    data,
+                    len: payload.len(),
+                    from,
+                }
+            }
+            IceCommandsRepr::OpenSocket { addr } => IceCommands::OpenSocket { addr },
+        })
+    }
+}
+
+/// How many messages each `AgentExternal` queue holds by default.
+pub const DEFAULT_EXTERNAL_QUEUE_CAPACITY: usize = 1024;
+
 pub(crate) struct AgentExternal {
     egress_msg: VecDeque<String>,
-    ingress_mgs: VecDeque<String>,
+    ingress_mgs: VecDeque<Vec<u8>>,
+    /// The maximum length of each of the two queues.
+    capacity: usize,
+    /// Wakes the egress task spawned by `spawn` once a message is queued.
+    egress_notify: Arc<Notify>,
+    /// Wakes the ingress task spawned by `spawn` once a full queue has room again.
+    ingress_notify: Arc<Notify>,
+    /// Wakes `recv_command` once a frame is queued.
+    command_notify: Arc<Notify>,
+    /// Set by `shutdown`, no further messages are accepted.
+    closed: bool,
+    /// The address each STUN server last saw us at, by server.
+    observed_mappings: Arc<ObservedMappings>,
 }
 
+/// Serializes `addr` in the original untagged format: the 4 or 16 address octets
+/// followed by the big endian port, 6 or 18 bytes in total, so the family is told
+/// apart by length alone, as `parse_recv_info` does. The scope id and flow info of
+/// IPv6 addresses are dropped.
 pub fn serialize_socket_addr(addr: SocketAddr) -> Vec<u8> {
     let mut out : Vec<u8> = Vec::new();
-    let mut ip = match addr.ip() {
-        std::net::IpAddr::V4(ip) => {
-            let octets = ip.octets();
-            let mut ip_vec : Vec<u8> = Vec::new();
-            ip_vec.extend_from_slice(&octets);
-            ip_vec
+    match addr.ip() {
+        IpAddr::V4(ip) => out.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => out.extend_from_slice(&ip.octets()),
+    };
+    out.extend_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+/// Serializes `addr` as its family byte (`ADDR_FAMILY_IPV4` or `ADDR_FAMILY_IPV6`),
+/// the address and the port, 7 or 19 bytes in total. IPv6 addresses with a scope id,
+/// e.g. link-local ones, are tagged `ADDR_FAMILY_IPV6_SCOPED` and carry the scope id
+/// between address and port, 23 bytes in total.
+///
+/// Everything is in network byte order: the address octets as they are, scope id and
+/// port big endian. The IPv6 flow info is not carried.
+pub fn serialize_socket_addr_tagged(addr: SocketAddr) -> Vec<u8> {
+    let mut out : Vec<u8> = Vec::new();
+    match addr {
+        SocketAddr::V4(addr) => {
+            out.push(ADDR_FAMILY_IPV4);
+            out.extend_from_slice(&addr.ip().octets());
         },
-        std::net::IpAddr::V6(ip) => {
-            let octets = ip.octets();
-            let mut ip_vec : Vec<u8> = Vec::new();
-            ip_vec.extend_from_slice(&octets);
-            ip_vec
+        SocketAddr::V6(addr) if addr.scope_id() != 0 => {
+            out.push(ADDR_FAMILY_IPV6_SCOPED);
+            out.extend_from_slice(&addr.ip().octets());
+            out.extend_from_slice(&addr.scope_id().to_be_bytes());
+        },
+        SocketAddr::V6(addr) => {
+            out.push(ADDR_FAMILY_IPV6);
+            out.extend_from_slice(&addr.ip().octets());
         },
     };
-    out.append(&mut ip);
     out.extend_from_slice(&addr.port().to_be_bytes());
     out
 }
 
 pub fn serialize_send_info(send_info: SendInfo) -> Result<Vec<u8>> {
-    let mut serialized = Vec::new();
-    serialized.append(&mut serialize_socket_addr(send_info.from));
-    serialized.append(&mut serialize_socket_addr(send_info.to));
-    let size = serialized.len() as u8;
-    let size_serialized = size.to_be_bytes();
-    serialized.insert(0, size_serialized[0]);
+    send_info.encode().map_err(io::Error::other)
+}
 
-    // To differentiate easily between the two packet types, include
-    // some magic number in this type of packet first
-    serialized.insert(0, SEND_INFO_PACKET_TYPE);
+/// Like `serialize_send_info`, but tags the frame with `session_id`.
+pub fn serialize_send_info_with_session(send_info: SendInfo, session_id: u16) -> Result<Vec<u8>> {
+    let mut frame = serialize_send_info(send_info)?;
+    insert_session_id(&mut frame, session_id);
+    Ok(frame)
+}
 
-    Ok(serialized)
+/// Tags `frame` by inserting `session_id` right after its packet type, so a relay
+/// shared by several agents can tell their frames apart. `NO_SESSION_ID` leaves the
+/// frame as it is.
+pub fn insert_session_id(frame: &mut Vec<u8>, session_id: u16) {
+    if session_id != NO_SESSION_ID && !frame.is_empty() {
+        frame.splice(1..1, session_id.to_be_bytes());
+    }
 }
 
-pub fn parse_recv_info(buf: &[u8], len: usize) -> Result<SocketAddr> {
-    if len < 6 {
-        return Err(io::Error::other(crate::Error::ErrAddressParseFailed));
-    }
-    let addr = match len {
-        6 => {
-            let raw_ip : [u8; 4] = buf[0..4].try_into().unwrap();
-            let raw_port : [u8; 2] = buf[4..6].try_into().unwrap();
-            let from_ip = Ipv4Addr::from(raw_ip);
-            let from_port = u16::from_be_bytes(raw_port);
-            let s_addr = SocketAddr::new(IpAddr::V4(from_ip), from_port);
-            s_addr
+/// Prefixes `payload` with the packet type and its length. The length is a
+/// single byte, so payloads above 255 bytes are rejected instead of wrapping.
+pub(crate) fn encode_frame(
+    packet_type: RelayPacketType,
+    payload: &[u8],
+) -> std::result::Result<Vec<u8>, Error> {
+    encode_frame_versioned(packet_type, payload, RelayFrameVersion::V1)
+}
+
+/// Like `encode_frame`, but encodes the length as `version` does. Payloads that do
+/// not fit its length are rejected.
+pub(crate) fn encode_frame_versioned(
+    packet_type: RelayPacketType,
+    payload: &[u8],
+    version: RelayFrameVersion,
+) -> std::result::Result<Vec<u8>, Error> {
+    let mut frame = Vec::with_capacity(RELAY_HEADER_V2_LEN + payload.len());
+    frame.push(packet_type.as_u8());
+    match version {
+        RelayFrameVersion::V1 => {
+            frame.push(u8::try_from(payload.len()).map_err(|_| Error::ErrSendInfoTooLarge)?);
         },
-        18 => {
-            let raw_ip : [u8; 16] = buf[0..16].try_into().unwrap();
-            let raw_port : [u8; 2] = buf[16..18].try_into().unwrap();
-            let from_ip = Ipv6Addr::from(raw_ip);
-            let from_port = u16::from_be_bytes(raw_port);
-            let s_addr = SocketAddr::new(IpAddr::V6(from_ip), from_port);
-            s_addr
+        RelayFrameVersion::V2 => {
+            let len = u16::try_from(payload.len()).map_err(|_| Error::ErrSendInfoTooLarge)?;
+            frame.push(RELAY_FRAME_V2_MARKER);
+            frame.extend_from_slice(&len.to_be_bytes());
         },
-        _ => {
-            return Err(io::Error::other(crate::Error::ErrAddressParseFailed));
-        }
+    }
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// Frames `addr` for registration with the relay, the relay answers with the same
+/// frame using `packet_type` `OpenSocketAck`.
+pub fn encode_open_socket(
+    packet_type: RelayPacketType,
+    addr: SocketAddr,
+) -> std::result::Result<Vec<u8>, Error> {
+    encode_frame(packet_type, &serialize_socket_addr_tagged(addr))
+}
+
+/// Parses the address of an `OpenSocket` or `OpenSocketAck` frame.
+pub fn decode_open_socket(buf: &[u8]) -> std::result::Result<SocketAddr, Error> {
+    let (payload, _) = split_relay_header(buf)?;
+    let (addr, consumed) = parse_socket_addr_tagged(payload)?;
+    if consumed != payload.len() {
+        return Err(Error::ErrAddressParseFailed);
+    }
+    Ok(addr)
+}
+
+/// Parses a single family tagged address, as written by `serialize_socket_addr_tagged`,
+/// from the start of `buf`, returning the address and the number of bytes it occupied.
+pub fn parse_socket_addr_tagged(buf: &[u8]) -> std::result::Result<(SocketAddr, usize), Error> {
+    let (ip, ip_len) = match buf.first() {
+        Some(&ADDR_FAMILY_IPV4) => {
+            let raw_ip: [u8; 4] = read_array(buf, 1)?;
+            (IpAddr::V4(Ipv4Addr::from(raw_ip)), 4)
+        },
+        Some(&ADDR_FAMILY_IPV6) => {
+            let raw_ip: [u8; 16] = read_array(buf, 1)?;
+            (IpAddr::V6(Ipv6Addr::from(raw_ip)), 16)
+        },
+        Some(&ADDR_FAMILY_IPV6_SCOPED) => {
+            let raw_ip: [u8; 16] = read_array(buf, 1)?;
+            let scope_id = u32::from_be_bytes(read_array(buf, 17)?);
+            let port = u16::from_be_bytes(read_array(buf, 21)?);
+            let addr = SocketAddrV6::new(Ipv6Addr::from(raw_ip), port, 0, scope_id);
+            return Ok((SocketAddr::V6(addr), IPV6_SCOPED_ADDR_LEN));
+        },
+        None => return Err(Error::ErrTruncatedSendInfo),
+        Some(_) => return Err(Error::ErrUnknownAddressFamily),
     };
+    let port = u16::from_be_bytes(read_array(buf, 1 + ip_len)?);
+    Ok((SocketAddr::new(ip, port), 3 + ip_len))
+}
+
+/// Reads `N` bytes starting at `offset`, failing instead of panicking on short input.
+fn read_array<const N: usize>(buf: &[u8], offset: usize) -> std::result::Result<[u8; N], Error> {
+    buf.get(offset..offset + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(Error::ErrTruncatedSendInfo)
+}
+
+/// Parses the `len` byte address at the start of `buf`, either family tagged or, at 6
+/// or 18 bytes, in the untagged format of `serialize_socket_addr`. Tagged addresses are
+/// never that long, so the two cannot be confused.
+pub fn parse_recv_info(buf: &[u8], len: usize) -> Result<SocketAddr> {
+    if len > buf.len() {
+        return Err(io::Error::other(Error::ErrTruncatedRelayFrame));
+    }
+    if let Some(addr) = parse_socket_addr_untagged(&buf[..len]) {
+        return Ok(addr);
+    }
+    let (addr, consumed) = parse_recv_info_consumed(&buf[..len])?;
+    if consumed != len {
+        return Err(io::Error::other(Error::ErrAddressParseFailed));
+    }
     Ok(addr)
 }
 
+/// Parses an address written by `serialize_socket_addr`, telling the family apart by
+/// the length of `buf`.
+fn parse_socket_addr_untagged(buf: &[u8]) -> Option<SocketAddr> {
+    let ip = match buf.len() {
+        6 => IpAddr::V4(Ipv4Addr::from(read_array::<4>(buf, 0).ok()?)),
+        18 => IpAddr::V6(Ipv6Addr::from(read_array::<16>(buf, 0).ok()?)),
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(read_array(buf, buf.len() - 2).ok()?);
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Parses the family tagged address at the start of `buf` like `parse_recv_info`, but
+/// allows trailing data and also returns the bytes the address took up, 7 for IPv4, 19
+/// for IPv6 and 23 for scoped IPv6, so parsing can continue after it.
+pub fn parse_recv_info_consumed(buf: &[u8]) -> Result<(SocketAddr, usize)> {
+    parse_socket_addr_tagged(buf).map_err(io::Error::other)
+}
+
 pub fn parse_send_info(buf: &[u8], len: usize) -> Result<SendInfo> {
-    let send_info = match len  {
-        // 2 * IPv4 = 2 * (4 + 2) = 12
-        12 => {
-            // IPv4 to IPv4
-            let raw_ip : [u8; 4] = buf[0..4].try_into().unwrap();
-            let raw_port : [u8; 2] = buf[4..6].try_into().unwrap();
-            let from_ip = Ipv4Addr::from(raw_ip);
-            let from_port = u16::from_be_bytes(raw_port);
-            let s_addr = SocketAddr::new(IpAddr::V4(from_ip), from_port);
-            
-            let raw_d_ip : [u8; 4] = buf[6..10].try_into().unwrap();
-            let raw_d_port : [u8; 2] = buf[10..12].try_into().unwrap();
-            let to_ip = Ipv4Addr::from(raw_d_ip);
-            let to_port = u16::from_be_bytes(raw_d_port);
-            let d_addr = SocketAddr::new(IpAddr::V4(to_ip), to_port);
-            let s_info = SendInfo {
-                from: s_addr,
-                to: d_addr,
-            };
-            s_info
-        },
-        // 2 * IPv6 = 2 * (16 + 2) = 36
-        36 => {
-            let raw_ip : [u8; 16] = buf[0..16].try_into().unwrap();
-            let raw_port : [u8; 2] = buf[16..18].try_into().unwrap();
-            let from_ip = Ipv6Addr::from(raw_ip);
-            let from_port = u16::from_be_bytes(raw_port);
-            let s_addr = SocketAddr::new(IpAddr::V6(from_ip), from_port);
-            
-            let raw_d_ip : [u8; 16] = buf[18..34].try_into().unwrap();
-            let raw_d_port : [u8; 2] = buf[34..36].try_into().unwrap();
-            let to_ip = Ipv6Addr::from(raw_d_ip);
-            let to_port = u16::from_be_bytes(raw_d_port);
-            let d_addr = SocketAddr::new(IpAddr::V6(to_ip), to_port);
-            let s_info = SendInfo{
-                from: s_addr,
-                to: d_addr,
-            };
-            s_info
+    if len > buf.len() {
+        error!("Given send info size {} exceeds the buffer", len);
+        return Err(io::Error::other(Error::ErrTruncatedRelayFrame));
+    }
+    SendInfo::decode_payload(&buf[..len]).map_err(|err| {
+        error!("Given send info size {} cannot be parsed: {}", len, err);
+        io::Error::other(err)
+    })
+}
+
+/// Serializes several `SendInfo`s at once, e.g. to register multiple sockets with the
+/// relay in one datagram: their count as a big endian u32 followed by the family
+/// tagged `from` and `to` address of each. Fails with `ErrSendInfoBatchTooLarge` if
+/// their count does not fit the u32.
+pub fn serialize_send_info_batch(infos: &[SendInfo]) -> std::result::Result<Vec<u8>, Error> {
+    let mut out = send_info_batch_count(infos.len())?.to_be_bytes().to_vec();
+    for send_info in infos {
+        out.extend(serialize_socket_addr_tagged(send_info.from));
+        out.extend(serialize_socket_addr_tagged(send_info.to));
+    }
+    Ok(out)
+}
+
+pub(crate) fn send_info_batch_count(len: usize) -> std::result::Result<u32, Error> {
+    u32::try_from(len).map_err(|_| Error::ErrSendInfoBatchTooLarge)
+}
+
+/// Parses the `SendInfo`s written by `serialize_send_info_batch`, which have to take
+/// up all of `buf`.
+pub fn parse_send_info_batch(buf: &[u8]) -> Result<Vec<SendInfo>> {
+    let count = u32::from_be_bytes(read_array(buf, 0).map_err(io::Error::other)?) as usize;
+    // The count is untrusted, the smallest entry is two IPv4 addresses
+    let mut infos = Vec::with_capacity(count.min(buf.len() / (2 * IPV4_ADDR_LEN)));
+    let mut offset = 4;
+    for _ in 0..count {
+        let (from, from_len) = parse_socket_addr_tagged(&buf[offset..]).map_err(io::Error::other)?;
+        offset += from_len;
+        let (to, to_len) = parse_socket_addr_tagged(&buf[offset..]).map_err(io::Error::other)?;
+        offset += to_len;
+        infos.push(SendInfo { from, to });
+    }
+    if offset != buf.len() {
+        return Err(io::Error::other(Error::ErrAddressParseFailed));
+    }
+    Ok(infos)
+}
+
+impl SendInfo {
+    /// Creates a `SendInfo` the relay can route, rejecting port 0 and unspecified
+    /// addresses with `ErrInvalidSendInfo`.
+    pub fn new(from: SocketAddr, to: SocketAddr) -> std::result::Result<SendInfo, Error> {
+        for addr in [from, to] {
+            if addr.port() == 0 || addr.ip().is_unspecified() {
+                return Err(Error::ErrInvalidSendInfo);
+            }
+        }
+        Ok(SendInfo { from, to })
+    }
+
+    /// Serializes into a relay frame: the packet type, the payload length and
+    /// both family tagged addresses.
+    pub fn encode(&self) -> std::result::Result<Vec<u8>, Error> {
+        self.encode_versioned(RelayFrameVersion::V1)
+    }
+
+    /// Like `encode`, but in the framing of `version`.
+    pub fn encode_versioned(&self, version: RelayFrameVersion) -> std::result::Result<Vec<u8>, Error> {
+        self.encode_as(RelayPacketType::SendInfo, version)
+    }
+
+    /// Like `encode_versioned`, but as a frame of `packet_type`. The relay hands over
+    /// what it receives for us in `RecvInfo` frames of the same layout.
+    pub fn encode_as(
+        &self,
+        packet_type: RelayPacketType,
+        version: RelayFrameVersion,
+    ) -> std::result::Result<Vec<u8>, Error> {
+        let mut payload = serialize_socket_addr_tagged(self.from);
+        payload.append(&mut serialize_socket_addr_tagged(self.to));
+        // To differentiate easily between the two packet types, include
+        // some magic number in this type of packet first
+        encode_frame_versioned(packet_type, &payload, version)
+    }
+
+    /// Parses a relay frame as produced by `encode` or `encode_versioned`. The packet
+    /// type byte is not checked since the relay uses the same layout in both directions.
+    pub fn decode(buf: &[u8]) -> std::result::Result<SendInfo, Error> {
+        let (header, _) = split_relay_header(buf)?;
+        Self::decode_payload(header)
+    }
+
+    /// Parses the two addresses following the frame header. Each address
+    /// carries its own family tag, so all four combinations of IPv4 and
+    /// IPv6 (14, 26 or 38 bytes) are handled the same way.
+    pub(crate) fn decode_payload(buf: &[u8]) -> std::result::Result<SendInfo, Error> {
+        let (from, from_len) = parse_socket_addr_tagged(buf)?;
+        let (to, to_len) = parse_socket_addr_tagged(&buf[from_len..])?;
+        if from_len + to_len != buf.len() {
+            return Err(Error::ErrAddressParseFailed);
+        }
+        Ok(SendInfo { from, to })
+    }
+
+    /// Whether both describe the same route, comparing IP and port only. Unlike `==`
+    /// this ignores the IPv6 scope id (and flow info), which do not change where the
+    /// relay sends a datagram.
+    pub fn same_route(&self, other: &SendInfo) -> bool {
+        let same_endpoint = |a: SocketAddr, b: SocketAddr| a.ip() == b.ip() && a.port() == b.port();
+        same_endpoint(self.from, other.from) && same_endpoint(self.to, other.to)
+    }
+
+    /// Returns the route with both addresses passed through `unmap_ipv4`.
+    pub fn unmapped(&self) -> SendInfo {
+        SendInfo {
+            from: unmap_ipv4(self.from),
+            to: unmap_ipv4(self.to),
+        }
+    }
+}
+
+/// Maps an unscoped IPv4-mapped IPv6 address back to IPv4. Some relays hand out IPv4
+/// addresses in that form, which then would not match the natively gathered IPv4
+/// candidates. The codec keeps addresses as they were sent, this is applied where
+/// relayed addresses are handed on.
+pub fn unmap_ipv4(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) if v6.scope_id() == 0 => match v6.ip().to_ipv4_mapped() {
+            Some(ipv4) => SocketAddr::new(IpAddr::V4(ipv4), v6.port()),
+            None => addr,
         },
-        _ => {
-            error!("Given send info size {} cannot be parsed", len);
-            return Err(io::Error::other(crate::Error::ErrAddressParseFailed));
+        _ => addr,
+    }
+}
+
+/// Shows the route compactly as `from → to`.
+impl fmt::Display for SendInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} → {}", self.from, self.to)
+    }
+}
+
+/// Builds the route from a `(from, to)` pair.
+impl From<(SocketAddr, SocketAddr)> for SendInfo {
+    fn from((from, to): (SocketAddr, SocketAddr)) -> Self {
+        SendInfo { from, to }
+    }
+}
+
+impl SendInfo {
+    /// Returns a `Display` of the route with the host part of both addresses blanked
+    /// out, for logs that should not reveal who talked to whom.
+    pub fn redacted(&self) -> RedactedSendInfo {
+        RedactedSendInfo(*self)
+    }
+}
+
+/// Displays a `SendInfo` as `192.0.2.x:5000 → 198.51.100.x:3478`. IPv6 addresses
+/// keep their /64 prefix.
+pub struct RedactedSendInfo(SendInfo);
+
+impl fmt::Display for RedactedSendInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_redacted(f, self.0.from)?;
+        f.write_str(" → ")?;
+        write_redacted(f, self.0.to)
+    }
+}
+
+fn write_redacted(f: &mut fmt::Formatter<'_>, addr: SocketAddr) -> fmt::Result {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            write!(f, "{}.{}.{}.x:{}", a, b, c, addr.port())
         }
-    };
-    Ok(send_info)
+        IpAddr::V6(ip) => {
+            let [a, b, c, d, ..] = ip.segments();
+            write!(f, "[{:x}:{:x}:{:x}:{:x}:x:x:x:x]:{}", a, b, c, d, addr.port())
+        }
+    }
+}
+
+/// Returns the XOR-MAPPED-ADDRESS of `payload` if it is a STUN binding success.
+fn binding_success_mapped_addr(payload: &[u8]) -> Option<SocketAddr> {
+    let mut msg = Message::new();
+    msg.raw = payload.to_vec();
+    msg.decode().ok()?;
+    if msg.typ != BINDING_SUCCESS {
+        return None;
+    }
+    let mut xor_addr = XorMappedAddress::default();
+    xor_addr.get_from(&msg).ok()?;
+    Some(SocketAddr::new(xor_addr.ip, xor_addr.port))
 }
 
 impl AgentExternal {
     pub(crate) fn new() -> AgentExternal {
-        return AgentExternal {
+        AgentExternal::with_capacity(DEFAULT_EXTERNAL_QUEUE_CAPACITY)
+    }
+
+    pub(crate) fn with_capacity(capacity: usize) -> AgentExternal {
+        AgentExternal {
             egress_msg: VecDeque::new(),
             ingress_mgs: VecDeque::new(),
+            capacity,
+            egress_notify: Arc::new(Notify::new()),
+            ingress_notify: Arc::new(Notify::new()),
+            command_notify: Arc::new(Notify::new()),
+            closed: false,
+            observed_mappings: Arc::default(),
+        }
+    }
+
+    /// Creates an `AgentExternal` whose ingress queue is fed from `rx` and whose egress
+    /// queue is drained into `tx`, each by its own task. The tasks stop once `rx` or
+    /// `tx` is closed respectively, or after `shutdown`.
+    pub(crate) fn spawn(
+        rx: mpsc::Receiver<Vec<u8>>,
+        tx: mpsc::Sender<String>,
+    ) -> Arc<Mutex<AgentExternal>> {
+        AgentExternal::spawn_with_capacity(rx, tx, DEFAULT_EXTERNAL_QUEUE_CAPACITY)
+    }
+
+    /// Like `spawn` with queues bounded to `capacity`. While the ingress queue is full
+    /// `rx` is not read, so the backpressure reaches the sender.
+    pub(crate) fn spawn_with_capacity(
+        rx: mpsc::Receiver<Vec<u8>>,
+        tx: mpsc::Sender<String>,
+        capacity: usize,
+    ) -> Arc<Mutex<AgentExternal>> {
+        AgentExternal::with_capacity(capacity).start(rx, tx)
+    }
+
+    /// Like `spawn`, recording the observed mappings into `observed_mappings` so they
+    /// are shared with the STUN requests of an agent.
+    pub(crate) fn spawn_with_mappings(
+        rx: mpsc::Receiver<Vec<u8>>,
+        tx: mpsc::Sender<String>,
+        observed_mappings: Arc<ObservedMappings>,
+    ) -> Arc<Mutex<AgentExternal>> {
+        let agent = AgentExternal {
+            observed_mappings,
+            ..AgentExternal::new()
         };
+        agent.start(rx, tx)
+    }
+
+    /// Starts the ingress and egress tasks described at `spawn` for this `AgentExternal`.
+    fn start(
+        self,
+        mut rx: mpsc::Receiver<Vec<u8>>,
+        tx: mpsc::Sender<String>,
+    ) -> Arc<Mutex<AgentExternal>> {
+        let egress_notify = Arc::clone(&self.egress_notify);
+        let ingress_notify = Arc::clone(&self.ingress_notify);
+        let external = Arc::new(Mutex::new(self));
+
+        let ingress = Arc::clone(&external);
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                loop {
+                    match ingress.lock().await.push_ingress(&msg) {
+                        Ok(()) => break,
+                        Err(Error::ErrExternalClosed) => return,
+                        Err(_) => {}
+                    }
+                    ingress_notify.notified().await;
+                }
+            }
+        });
+
+        let egress = Arc::clone(&external);
+        tokio::spawn(async move {
+            loop {
+                // Release the lock before awaiting either the notification or the send
+                let (next, closed) = {
+                    let mut agent = egress.lock().await;
+                    (agent.pop_egress(), agent.closed)
+                };
+                match next {
+                    Some(msg) => {
+                        if tx.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                    // Everything queued before the shutdown has been flushed
+                    None if closed => break,
+                    None => egress_notify.notified().await,
+                }
+            }
+        });
+
+        external
     }
-    pub(crate) fn send_message(&mut self, message: String) {
+
+    /// Queues `message` for the relay, failing with `ErrExternalQueueFull` instead of
+    /// growing the queue beyond its capacity and with `ErrExternalClosed` after `shutdown`.
+    pub(crate) fn send_message(&mut self, message: String) -> std::result::Result<(), Error> {
+        if self.closed {
+            return Err(Error::ErrExternalClosed);
+        }
+        if self.egress_msg.len() >= self.capacity {
+            return Err(Error::ErrExternalQueueFull);
+        }
         self.egress_msg.push_back(message);
+        self.egress_notify.notify_one();
+        Ok(())
+    }
+
+    /// Takes the next message queued by `send_message`.
+    pub(crate) fn pop_egress(&mut self) -> Option<String> {
+        self.egress_msg.pop_front()
+    }
+
+    /// Queues a frame received from the relay, failing with `ErrExternalQueueFull`
+    /// instead of growing the queue beyond its capacity and with `ErrExternalClosed`
+    /// after `shutdown`.
+    pub(crate) fn push_ingress(&mut self, frame: &[u8]) -> std::result::Result<(), Error> {
+        if self.closed {
+            return Err(Error::ErrExternalClosed);
+        }
+        if self.ingress_mgs.len() >= self.capacity {
+            return Err(Error::ErrExternalQueueFull);
+        }
+        // Broken frames are reported once `get_message` decodes them
+        if frame.first() == Some(&RECV_INFO_PACKET_TYPE) {
+            if let Ok((recv_info, payload)) = split_send_info(frame) {
+                self.observe_response(recv_info.from, payload);
+            }
+        }
+        self.ingress_mgs.push_back(frame.to_vec());
+        self.command_notify.notify_one();
+        Ok(())
+    }
+
+    /// Pops the next ingress frame decoded into an `IceCommands`. Frames that
+    /// cannot be decoded are logged and dropped.
+    pub(crate) fn get_message(&mut self) -> Option<IceCommands> {
+        while let Some(frame) = self.ingress_mgs.pop_front() {
+            self.ingress_notify.notify_one();
+            match IceCommands::decode(&frame) {
+                Ok(command) => return Some(command),
+                Err(err) => warn!("Dropping undecodable relay frame: {}", err),
+            }
+        }
+        None
     }
-    pub(crate) fn get_message(&mut self) -> Option<String> {
-        self.ingress_mgs.pop_front()
-    }
-}
-
-// TODO: Change this to mio polling
-// pub(crate) fn start_external_listener(external: Arc<Mutex<AgentExternal>>, rx: channel::Receiver<String>) -> Result<()> {
-//     // tokio::spawn(async move {
-//     //     let rx = rx.borrow();
-//     //     loop {
-//     //         match rx.try_recv() {
-//     //             Ok(s) => {
-//     //                 // TODO: Parse
-//     //                 external.lock().await.ingress_mgs.push_back(s);
-//     //             },
-//     //             Err(e) => {
-//     //                 thread::sleep(Duration::from_millis(100));
-//     //             }
-//     //         }
-//     //     }
-//     // });
-//     Ok(())
-// }
-
-// pub(crate) fn start_external_send(external: Arc<Mutex<AgentExternal>>, tx: channel::Sender<String>) -> Result<()> {
-//     tokio::spawn(async move{
-//         loop {
-//             let mut agent = external.lock().await;
-//             let next = agent.egress_msg.pop_front();
-//             if next.is_none() {
-//                 thread::sleep(Duration::from_millis(100));
-//             } else {
-//                 tx.send(next.unwrap()).unwrap();
-//             }
-//         }
-//     });
-//     Ok(())
-// }
+
+    /// Waits for the next ingress command, the awaiting counterpart of `get_message`.
+    /// Takes the `Mutex` rather than `&self` so the lock is released while waiting and
+    /// the ingress task can queue frames in the meantime. Fails with `ErrExternalClosed`
+    /// once `shutdown` was called and the queued commands are used up.
+    pub(crate) async fn recv_command(
+        external: &Mutex<AgentExternal>,
+    ) -> std::result::Result<IceCommands, Error> {
+        loop {
+            let notify = {
+                let mut agent = external.lock().await;
+                if let Some(command) = agent.get_message() {
+                    return Ok(command);
+                }
+                if agent.closed {
+                    return Err(Error::ErrExternalClosed);
+                }
+                Arc::clone(&agent.command_notify)
+            };
+            // A frame queued after the lock was released leaves a permit behind, so
+            // this cannot miss it
+            notify.notified().await;
+        }
+    }
+
+    /// Records the address `server` saw us at if `payload` is the binding success it
+    /// answered with, as relayed in a `RecvInfo` frame. Anything else is ignored.
+    pub(crate) fn observe_response(&mut self, server: SocketAddr, payload: &[u8]) {
+        if let Some(mapped) = binding_success_mapped_addr(payload) {
+            self.observed_mappings.record(server, mapped);
+        }
+    }
+
+    /// Returns the mapped address each STUN server last reported through the relay,
+    /// by server. Differing ports across servers indicate an address dependent
+    /// mapping.
+    pub(crate) fn observed_mappings(&self) -> HashMap<SocketAddr, SocketAddr> {
+        self.observed_mappings.snapshot()
+    }
+
+    /// Closes both queues. `send_message` and `push_ingress` fail with
+    /// `ErrExternalClosed` from now on, while messages already queued for the relay
+    /// are still flushed by the egress task before it stops.
+    pub(crate) fn shutdown(&mut self) {
+        self.closed = true;
+        self.egress_notify.notify_one();
+        self.ingress_notify.notify_one();
+        // Wake every waiting receiver, the permit covers one about to start waiting
+        self.command_notify.notify_waiters();
+        self.command_notify.notify_one();
+    }
+}
 
 pub(crate) async fn send_external(external: Arc<Mutex<AgentExternal>>, msg: String) -> Result<()> {
     let mut agent = external.lock().await;
-    agent.send_message(msg);
-    Ok(())
+    agent.send_message(msg).map_err(io::Error::other)
 }
\ No newline at end of file
@@ -0,0 +1,1086 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV6};
+use std::str::FromStr;
+
+use super::agent_external::*;
+use super::*;
+
+fn roundtrip(from: &str, to: &str) -> Result<()> {
+    let send_info = SendInfo {
+        from: SocketAddr::from_str(from)?,
+        to: SocketAddr::from_str(to)?,
+    };
+
+    let serialized = serialize_send_info(send_info)?;
+    assert_eq!(serialized[0], SEND_INFO_PACKET_TYPE);
+    let len = serialized[1] as usize;
+    assert_eq!(serialized.len(), 2 + len);
+
+    let parsed = parse_send_info(&serialized[2..], len)?;
+    assert_eq!(parsed, send_info);
+
+    Ok(())
+}
+
+#[test]
+fn test_send_info_roundtrip_v4_to_v4() -> Result<()> {
+    roundtrip("10.0.0.1:5000", "1.2.3.4:3478")
+}
+
+#[test]
+fn test_send_info_roundtrip_v6_to_v6() -> Result<()> {
+    roundtrip("[fe80::1]:5000", "[2001:db8::1]:3478")
+}
+
+#[test]
+fn test_send_info_roundtrip_v4_to_v6() -> Result<()> {
+    roundtrip("10.0.0.1:5000", "[2001:db8::1]:3478")
+}
+
+#[test]
+fn test_send_info_roundtrip_v6_to_v4() -> Result<()> {
+    roundtrip("[fe80::1]:5000", "1.2.3.4:3478")
+}
+
+#[test]
+fn test_unmap_ipv4_normalizes_ipv4_mapped_addresses() -> Result<()> {
+    let send_info = SendInfo {
+        from: SocketAddr::from_str("[::ffff:192.0.2.1]:5000")?,
+        to: SocketAddr::from_str("[2001:db8::1]:3478")?,
+    };
+    let serialized = serialize_send_info(send_info)?;
+    let len = serialized[1] as usize;
+
+    // The codec keeps the mapped form, the receiving side maps it back
+    let parsed = parse_send_info(&serialized[2..], len)?;
+    assert_eq!(parsed, send_info);
+    assert_eq!(parsed.unmapped().from, SocketAddr::from_str("192.0.2.1:5000")?);
+    assert_eq!(parsed.unmapped().to, send_info.to);
+
+    // Scoped addresses are link-local IPv6 ones, whatever their bits say
+    let scoped = SocketAddr::from_str("[::ffff:192.0.2.1%3]:258")?;
+    assert_eq!(unmap_ipv4(scoped), scoped);
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_recv_info_consumed() -> Result<()> {
+    for (addr, consumed) in [("192.0.2.1:5000", 7), ("[2001:db8::1]:3478", 19)] {
+        let addr = SocketAddr::from_str(addr)?;
+        let mut buf = serialize_socket_addr_tagged(addr);
+        buf.extend_from_slice(b"stun");
+
+        assert_eq!(parse_recv_info_consumed(&buf)?, (addr, consumed));
+        assert_eq!(&buf[consumed..], b"stun");
+        assert!(parse_recv_info_consumed(&buf[..consumed - 1]).is_err());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_socket_addr_tagged_roundtrip() -> Result<()> {
+    for (addr, family, len) in [
+        ("192.0.2.1:5000", ADDR_FAMILY_IPV4, 7),
+        ("[2001:db8::1]:3478", ADDR_FAMILY_IPV6, 19),
+    ] {
+        let addr = SocketAddr::from_str(addr)?;
+        let mut serialized = serialize_socket_addr_tagged(addr);
+        assert_eq!(serialized[0], family);
+        assert_eq!(serialized.len(), len);
+
+        // Trailing bytes belong to whatever follows the address
+        serialized.extend_from_slice(&[0xAB; 3]);
+        assert_eq!(parse_socket_addr_tagged(&serialized)?, (addr, len));
+        assert_eq!(
+            parse_socket_addr_tagged(&serialized[..len - 1]),
+            Err(Error::ErrTruncatedSendInfo)
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_socket_addr_tagged_byte_order() -> Result<()> {
+    let serialized = serialize_socket_addr_tagged(SocketAddr::from_str("192.0.2.1:4660")?);
+    assert_eq!(serialized, [ADDR_FAMILY_IPV4, 192, 0, 2, 1, 0x12, 0x34]);
+
+    let serialized = serialize_socket_addr_tagged(SocketAddr::from_str("[2001:db8::1%258]:4660")?);
+    assert_eq!(serialized[1..3], [0x20, 0x01]);
+    assert_eq!(serialized[17..], [0, 0, 0x01, 0x02, 0x12, 0x34]);
+
+    Ok(())
+}
+
+#[test]
+fn test_serialize_socket_addr() -> Result<()> {
+    let serialized = serialize_socket_addr(SocketAddr::from_str("192.0.2.1:4660")?);
+    assert_eq!(serialized, [192, 0, 2, 1, 0x12, 0x34]);
+
+    let serialized = serialize_socket_addr(SocketAddr::from_str("[2001:db8::1]:4660")?);
+    assert_eq!(serialized.len(), 18);
+    assert_eq!(serialized[..2], [0x20, 0x01]);
+    assert_eq!(serialized[16..], [0x12, 0x34]);
+
+    // The untagged format has no room for a scope id
+    let scoped = serialize_socket_addr(SocketAddr::from_str("[fe80::1%2]:4660")?);
+    assert_eq!(
+        scoped,
+        serialize_socket_addr(SocketAddr::from_str("[fe80::1]:4660")?)
+    );
+
+    Ok(())
+}
+
+/// Draws an address with edge case ports every now and then. IPv6 addresses get a
+/// scope id when `scoped` is set.
+fn arbitrary_socket_addr(rng: &mut impl rand::Rng, i: usize, scoped: bool) -> SocketAddr {
+    let edge_ports = [0, 1, 0x00FF, 0xFF00, 0x0102, u16::MAX];
+    let port = match edge_ports.get(i % 64) {
+        Some(&port) => port,
+        None => rng.gen(),
+    };
+    if rng.gen() {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::from(rng.gen::<u32>())), port)
+    } else {
+        let ip = Ipv6Addr::from(rng.gen::<u128>());
+        let scope_id = if scoped && rng.gen() { rng.gen() } else { 0 };
+        SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id))
+    }
+}
+
+/// The all-zero, all-ones and IPv4-mapped forms random draws would hardly ever hit.
+const EDGE_SOCKET_ADDRS: [&str; 5] = [
+    "0.0.0.0:0",
+    "255.255.255.255:65535",
+    "[::]:0",
+    "[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff]:65535",
+    "[::ffff:192.0.2.1]:258",
+];
+
+#[test]
+fn test_socket_addr_roundtrip_arbitrary_addrs() -> Result<()> {
+    let mut rng = rand::thread_rng();
+    for i in 0..10000 {
+        let addr = arbitrary_socket_addr(&mut rng, i, false);
+        let serialized = serialize_socket_addr(addr);
+        assert_eq!(
+            parse_recv_info(&serialized, serialized.len())?,
+            addr,
+            "{addr} serialized as {serialized:02x?}"
+        );
+        assert_eq!(serialized[serialized.len() - 2..], addr.port().to_be_bytes());
+    }
+
+    for addr in EDGE_SOCKET_ADDRS {
+        let addr = SocketAddr::from_str(addr)?;
+        let serialized = serialize_socket_addr(addr);
+        assert_eq!(parse_recv_info(&serialized, serialized.len())?, addr);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_socket_addr_tagged_roundtrip_arbitrary_addrs() -> Result<()> {
+    let mut rng = rand::thread_rng();
+    for i in 0..10000 {
+        let addr = arbitrary_socket_addr(&mut rng, i, true);
+        let serialized = serialize_socket_addr_tagged(addr);
+        assert_eq!(
+            parse_recv_info(&serialized, serialized.len())?,
+            addr,
+            "{addr} serialized as {serialized:02x?}"
+        );
+        assert_eq!(serialized[serialized.len() - 2..], addr.port().to_be_bytes());
+    }
+
+    let scoped = "[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff%4294967295]:65535";
+    for addr in EDGE_SOCKET_ADDRS.into_iter().chain([scoped, "[::ffff:192.0.2.1%3]:258"]) {
+        let addr = SocketAddr::from_str(addr)?;
+        let serialized = serialize_socket_addr_tagged(addr);
+        assert_eq!(parse_recv_info(&serialized, serialized.len())?, addr);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_socket_addr_tagged_preserves_scope_id() -> Result<()> {
+    // fe80::1%eth0, with eth0 being interface 2
+    let addr = SocketAddr::from_str("[fe80::1%2]:5000")?;
+    let mut serialized = serialize_socket_addr_tagged(addr);
+    assert_eq!(serialized[0], ADDR_FAMILY_IPV6_SCOPED);
+    assert_eq!(serialized.len(), 23);
+    assert_eq!(&serialized[17..21], &2u32.to_be_bytes());
+
+    serialized.extend_from_slice(b"stun");
+    let (parsed, consumed) = parse_recv_info_consumed(&serialized)?;
+    assert_eq!(consumed, 23);
+    match parsed {
+        SocketAddr::V6(parsed) => assert_eq!(parsed.scope_id(), 2),
+        SocketAddr::V4(_) => panic!("scoped address parsed as IPv4"),
+    }
+    assert_eq!(parsed, addr);
+    assert_eq!(parse_recv_info(&serialized, 23)?, addr);
+    assert!(parse_recv_info_consumed(&serialized[..22]).is_err());
+
+    // Relayed frames carry the scope in both directions
+    let send_info = SendInfo {
+        from: addr,
+        to: SocketAddr::from_str("[fe80::2%2]:3478")?,
+    };
+    assert_eq!(SendInfo::decode(&send_info.encode()?)?, send_info);
+
+    // Unscoped addresses keep the plain IPv6 encoding
+    let unscoped = serialize_socket_addr_tagged(SocketAddr::from_str("[fe80::1]:5000")?);
+    assert_eq!(unscoped[0], ADDR_FAMILY_IPV6);
+    assert_eq!(unscoped.len(), 19);
+
+    Ok(())
+}
+
+#[test]
+fn test_send_info_same_route_ignores_scope_id() -> Result<()> {
+    let send_info = SendInfo {
+        from: SocketAddr::from_str("[fe80::1%2]:5000")?,
+        to: SocketAddr::from_str("[fe80::2%2]:3478")?,
+    };
+    let rescoped = SendInfo {
+        from: SocketAddr::from_str("[fe80::1%3]:5000")?,
+        to: SocketAddr::from_str("[fe80::2]:3478")?,
+    };
+    assert!(send_info.same_route(&rescoped));
+    assert_ne!(send_info, rescoped);
+
+    // A different port or address is a different route
+    let other_port = SendInfo {
+        to: SocketAddr::from_str("[fe80::2%2]:3479")?,
+        ..send_info
+    };
+    assert!(!send_info.same_route(&other_port));
+    let other_ip = SendInfo {
+        from: SocketAddr::from_str("[fe80::3%2]:5000")?,
+        ..send_info
+    };
+    assert!(!send_info.same_route(&other_ip));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_info_from_tuple() -> Result<()> {
+    let from = SocketAddr::from_str("10.0.0.1:5000")?;
+    let to = SocketAddr::from_str("[2001:db8::1]:3478")?;
+
+    let send_info: SendInfo = (from, to).into();
+    assert_eq!(send_info.from, from);
+    assert_eq!(send_info.to, to);
+
+    Ok(())
+}
+
+#[test]
+fn test_send_info_batch_roundtrip() -> Result<()> {
+    let infos = [
+        ("10.0.0.1:5000", "1.2.3.4:3478"),
+        ("[2001:db8::2]:5001", "[2001:db8::1]:3478"),
+        ("10.0.0.1:5002", "[2001:db8::1]:3478"),
+        ("[fe80::1%2]:5003", "1.2.3.4:3478"),
+    ]
+    .into_iter()
+    .map(|(from, to)| {
+        Ok(SendInfo {
+            from: SocketAddr::from_str(from)?,
+            to: SocketAddr::from_str(to)?,
+        })
+    })
+    .collect::<Result<Vec<_>>>()?;
+
+    let serialized = serialize_send_info_batch(&infos)?;
+    assert_eq!(serialized[..4], 4u32.to_be_bytes());
+    assert_eq!(serialized.len(), 4 + 14 + 38 + 26 + 30);
+    assert_eq!(parse_send_info_batch(&serialized)?, infos);
+
+    assert_eq!(
+        parse_send_info_batch(&serialize_send_info_batch(&[])?)?,
+        vec![]
+    );
+    // Truncated entries and trailing bytes are rejected
+    assert!(parse_send_info_batch(&serialized[..serialized.len() - 1]).is_err());
+    let mut trailing = serialized.clone();
+    trailing.push(0);
+    assert!(parse_send_info_batch(&trailing).is_err());
+    assert!(parse_send_info_batch(&[0, 0]).is_err());
+
+    // A count beyond the u32 is an error rather than a panic
+    assert_eq!(send_info_batch_count(u32::MAX as usize), Ok(u32::MAX));
+    #[cfg(target_pointer_width = "64")]
+    assert_eq!(
+        send_info_batch_count(u32::MAX as usize + 1),
+        Err(Error::ErrSendInfoBatchTooLarge)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_send_info_rejects_unknown_family() -> Result<()> {
+    let send_info = SendInfo {
+        from: SocketAddr::from_str("10.0.0.1:5000")?,
+        to: SocketAddr::from_str("1.2.3.4:3478")?,
+    };
+    let mut serialized = serialize_send_info(send_info)?;
+    serialized[2] = 0x05;
+
+    let len = serialized[1] as usize;
+    assert!(parse_send_info(&serialized[2..], len).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_send_info_new() -> Result<()> {
+    let from = SocketAddr::from_str("10.0.0.1:5000")?;
+    let to = SocketAddr::from_str("[2001:db8::1]:3478")?;
+    assert_eq!(SendInfo::new(from, to)?, SendInfo { from, to });
+
+    for (from, to) in [
+        ("10.0.0.1:0", "1.2.3.4:3478"),
+        ("10.0.0.1:5000", "1.2.3.4:0"),
+        ("0.0.0.0:5000", "1.2.3.4:3478"),
+        ("10.0.0.1:5000", "[::]:3478"),
+    ] {
+        assert_eq!(
+            SendInfo::new(SocketAddr::from_str(from)?, SocketAddr::from_str(to)?),
+            Err(Error::ErrInvalidSendInfo),
+            "{from} -> {to}"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_send_info_encode_decode() -> Result<()> {
+    let send_info = SendInfo {
+        from: SocketAddr::from_str("[fe80::1]:5000")?,
+        to: SocketAddr::from_str("[2001:db8::1]:3478")?,
+    };
+
+    let encoded = send_info.encode()?;
+    assert_eq!(encoded.len(), 40);
+    assert_eq!(SendInfo::decode(&encoded)?, send_info);
+
+    Ok(())
+}
+
+#[test]
+fn test_send_info_ordering() -> Result<()> {
+    let send_info = |from: &str, to: &str| -> Result<SendInfo> {
+        Ok(SendInfo {
+            from: SocketAddr::from_str(from)?,
+            to: SocketAddr::from_str(to)?,
+        })
+    };
+    let mut infos = vec![
+        send_info("[::1]:5000", "1.2.3.4:3478")?,
+        send_info("10.0.0.2:5000", "1.2.3.4:3478")?,
+        send_info("10.0.0.1:5001", "1.2.3.4:3478")?,
+        send_info("10.0.0.1:5000", "5.6.7.8:3478")?,
+        send_info("10.0.0.1:5000", "1.2.3.4:3478")?,
+    ];
+    let mut expected = infos.clone();
+    expected.reverse();
+
+    infos.sort();
+    assert_eq!(infos, expected);
+
+    Ok(())
+}
+
+#[test]
+fn test_serialize_send_info_with_session() -> Result<()> {
+    let send_info = SendInfo {
+        from: SocketAddr::from_str("10.0.0.1:5000")?,
+        to: SocketAddr::from_str("1.2.3.4:3478")?,
+    };
+    let plain = serialize_send_info(send_info)?;
+
+    // Without a session the framing is unchanged
+    assert_eq!(
+        serialize_send_info_with_session(send_info, NO_SESSION_ID)?,
+        plain
+    );
+
+    let tagged = serialize_send_info_with_session(send_info, 0x1234)?;
+    assert_eq!(tagged.len(), plain.len() + SESSION_ID_LEN);
+    assert_eq!(tagged[..3], [SEND_INFO_PACKET_TYPE, 0x12, 0x34]);
+    assert_eq!(tagged[3..], plain[1..]);
+
+    Ok(())
+}
+
+#[test]
+fn test_send_info_decode_truncated() -> Result<()> {
+    let send_info = SendInfo {
+        from: SocketAddr::from_str("[fe80::1]:5000")?,
+        to: SocketAddr::from_str("[2001:db8::1]:3478")?,
+    };
+    let encoded = send_info.encode()?;
+
+    for len in [0, 5, 11, 35] {
+        assert_eq!(
+            SendInfo::decode(&encoded[..len]),
+            Err(Error::ErrTruncatedRelayFrame),
+            "buffer of length {len}"
+        );
+    }
+
+    // A declared length that cannot hold the addresses it claims to
+    let mut short = encoded.clone();
+    short[1] = 11;
+    assert_eq!(SendInfo::decode(&short), Err(Error::ErrTruncatedSendInfo));
+
+    Ok(())
+}
+
+#[test]
+fn test_encode_frame_too_large() -> Result<()> {
+    let frame = encode_frame(RelayPacketType::SendInfo, &[0; 255])?;
+    assert_eq!(frame[1], 255);
+    assert_eq!(frame.len(), 257);
+
+    assert_eq!(
+        encode_frame(RelayPacketType::SendInfo, &[0; 256]),
+        Err(Error::ErrSendInfoTooLarge)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_send_info_encode_versioned() -> Result<()> {
+    let send_info = SendInfo {
+        from: SocketAddr::from_str("10.0.0.1:5000")?,
+        to: SocketAddr::from_str("[2001:db8::1]:3478")?,
+    };
+
+    let v1 = send_info.encode_versioned(RelayFrameVersion::V1)?;
+    assert_eq!(v1, send_info.encode()?);
+    assert_eq!(v1[1], 26);
+    assert_eq!(SendInfo::decode(&v1)?, send_info);
+
+    let v2 = send_info.encode_versioned(RelayFrameVersion::V2)?;
+    assert_eq!(
+        v2[..4],
+        [SEND_INFO_PACKET_TYPE, RELAY_FRAME_V2_MARKER, 0, 26]
+    );
+    assert_eq!(v2[4..], v1[2..]);
+    assert_eq!(SendInfo::decode(&v2)?, send_info);
+    assert_eq!(
+        SendInfo::decode(&v2[..3]),
+        Err(Error::ErrTruncatedRelayFrame)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_encode_frame_v2_large_payload() -> Result<()> {
+    let payload: Vec<u8> = (0..1000_u16).map(|i| i as u8).collect();
+    assert_eq!(
+        encode_frame_versioned(RelayPacketType::SendInfo, &payload, RelayFrameVersion::V1),
+        Err(Error::ErrSendInfoTooLarge)
+    );
+
+    let mut frame =
+        encode_frame_versioned(RelayPacketType::SendInfo, &payload, RelayFrameVersion::V2)?;
+    assert_eq!(frame[1..4], [RELAY_FRAME_V2_MARKER, 0x03, 0xe8]);
+    frame.extend_from_slice(b"rest");
+    assert_eq!(split_relay_header(&frame)?, (&payload[..], &b"rest"[..]));
+
+    assert_eq!(
+        encode_frame_versioned(
+            RelayPacketType::SendInfo,
+            &vec![0; 65536],
+            RelayFrameVersion::V2
+        ),
+        Err(Error::ErrSendInfoTooLarge)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_declared_length_exceeds_buffer() -> Result<()> {
+    let send_info = SendInfo {
+        from: SocketAddr::from_str("10.0.0.1:5000")?,
+        to: SocketAddr::from_str("1.2.3.4:3478")?,
+    };
+    let encoded = send_info.encode()?;
+    let payload = &encoded[2..];
+
+    assert!(parse_send_info(payload, payload.len() + 1).is_err());
+    assert!(parse_send_info(&payload[..5], payload.len()).is_err());
+    assert!(parse_recv_info(&payload[..5], 7).is_err());
+    assert_eq!(
+        parse_recv_info(&payload[..7], 7)?,
+        SocketAddr::from_str("10.0.0.1:5000")?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_send_info_decode_unknown_family() -> Result<()> {
+    let send_info = SendInfo {
+        from: SocketAddr::from_str("10.0.0.1:5000")?,
+        to: SocketAddr::from_str("1.2.3.4:3478")?,
+    };
+    let mut encoded = send_info.encode()?;
+    encoded[9] = 0x05;
+
+    assert_eq!(
+        SendInfo::decode(&encoded),
+        Err(Error::ErrUnknownAddressFamily)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_relay_packet_type() {
+    assert_eq!(
+        RelayPacketType::try_from(0xAA),
+        Ok(RelayPacketType::SendInfo)
+    );
+    assert_eq!(
+        RelayPacketType::try_from(0xCC),
+        Ok(RelayPacketType::RecvInfo)
+    );
+    assert_eq!(RelayPacketType::SendInfo.as_u8(), SEND_INFO_PACKET_TYPE);
+    assert_eq!(RelayPacketType::RecvInfo.as_u8(), RECV_INFO_PACKET_TYPE);
+    assert_eq!(
+        RelayPacketType::try_from(0xBB),
+        Ok(RelayPacketType::OpenSocket)
+    );
+    assert_eq!(
+        RelayPacketType::try_from(0xDD),
+        Ok(RelayPacketType::OpenSocketAck)
+    );
+    assert_eq!(
+        RelayPacketType::try_from(0x00),
+        Err(Error::ErrUnknownRelayPacketType)
+    );
+}
+
+#[test]
+fn test_relay_addr_for_local_family() -> Result<()> {
+    let relay_config = RelayConfig::default();
+    let v4 = SocketAddr::from_str("10.0.0.1:5000")?;
+    let v6 = SocketAddr::from_str("[fe80::1]:5000")?;
+
+    assert_eq!(relay_config.relay_addr_for(v4), DEFAULT_RELAY_ADDR);
+    assert_eq!(
+        relay_config.relay_addr_for(v6),
+        SocketAddr::from_str("[::1]:12345")?
+    );
+
+    // Relays away from the loopback are used as configured
+    let remote = RelayConfig {
+        relay_addr: SocketAddr::from_str("10.0.0.9:12345")?,
+        ..Default::default()
+    };
+    assert_eq!(remote.relay_addr_for(v6), remote.relay_addr);
+
+    Ok(())
+}
+
+#[test]
+fn test_relay_response_timeout() {
+    let deadline = Duration::from_secs(1);
+    assert_eq!(
+        RelayConfig::default().response_timeout(deadline),
+        deadline + DEFAULT_RELAY_TIMEOUT_PADDING
+    );
+
+    let relay_config = RelayConfig {
+        relay_timeout_padding: Duration::from_secs(0),
+        ..Default::default()
+    };
+    assert_eq!(relay_config.response_timeout(deadline), deadline);
+}
+
+#[test]
+fn test_relay_next_rto() {
+    let relay_config = RelayConfig::default();
+    assert_eq!(
+        relay_config.next_rto(Duration::from_millis(500)),
+        Duration::from_secs(1)
+    );
+    assert_eq!(relay_config.next_rto(Duration::from_secs(40)), MAX_RELAY_RTO);
+
+    // Many retransmits without a deadline neither overflow nor grow past the cap
+    let mut rto = relay_config.initial_rto();
+    for _ in 0..100 {
+        rto = relay_config.next_rto(rto);
+    }
+    assert_eq!(rto, MAX_RELAY_RTO);
+    let huge = Duration::MAX / 2 + Duration::from_secs(1);
+    assert_eq!(relay_config.next_rto(huge), huge);
+}
+
+fn relay_frame(packet_type: RelayPacketType, payload: &[u8]) -> Result<Vec<u8>> {
+    let mut frame = SendInfo {
+        from: SocketAddr::from_str("10.0.0.1:5000")?,
+        to: SocketAddr::from_str("1.2.3.4:3478")?,
+    }
+    .encode()?;
+    frame[0] = packet_type.as_u8();
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+#[test]
+fn test_ice_commands_decode_stun_request() -> Result<()> {
+    let frame = relay_frame(RelayPacketType::SendInfo, b"stun")?;
+
+    match IceCommands::decode(&frame)? {
+        IceCommands::StunRequest { data, from, to } => {
+            assert_eq!(data, b"stun");
+            assert_eq!(from, SocketAddr::from_str("10.0.0.1:5000")?);
+            assert_eq!(to, SocketAddr::from_str("1.2.3.4:3478")?);
+        }
+        command => panic!("unexpected command {command:?}"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_ice_commands_decode_stun_response() -> Result<()> {
+    let frame = relay_frame(RelayPacketType::RecvInfo, b"stun")?;
+
+    match IceCommands::decode(&frame)? {
+        IceCommands::StunResponse { data, len, from } => {
+            assert_eq!(&data[..len], b"stun");
+            assert_eq!(from, SocketAddr::from_str("10.0.0.1:5000")?);
+        }
+        command => panic!("unexpected command {command:?}"),
+    }
+
+    let oversized = relay_frame(RelayPacketType::RecvInfo, &[0; MAX_STUN_DATA + 1])?;
+    assert_eq!(
+        IceCommands::decode(&oversized).unwrap_err(),
+        Error::ErrStunDataTooLarge
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_ice_commands_decode_open_socket() -> Result<()> {
+    let addr = SocketAddr::from_str("10.0.0.1:5000")?;
+
+    let open = encode_open_socket(RelayPacketType::OpenSocket, addr)?;
+    assert_eq!(
+        IceCommands::decode(&open)?,
+        IceCommands::OpenSocket { addr }
+    );
+
+    let ack = encode_open_socket(RelayPacketType::OpenSocketAck, addr)?;
+    assert_eq!(
+        IceCommands::decode(&ack).unwrap_err(),
+        Error::ErrUnknownRelayPacketType
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_ice_commands_decode_invalid() -> Result<()> {
+    assert_eq!(
+        IceCommands::decode(&[]).unwrap_err(),
+        Error::ErrTruncatedRelayFrame
+    );
+    let mut frame = relay_frame(RelayPacketType::SendInfo, b"stun")?;
+    frame[0] = 0x00;
+    assert_eq!(
+        IceCommands::decode(&frame).unwrap_err(),
+        Error::ErrUnknownRelayPacketType
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_external_spawn_roundtrip() -> Result<()> {
+    let (ingress_tx, ingress_rx) = mpsc::channel(8);
+    let (egress_tx, mut egress_rx) = mpsc::channel(8);
+    let external = AgentExternal::spawn(ingress_rx, egress_tx);
+
+    // Undecodable frames are skipped
+    ingress_tx.send(vec![0x00]).await.unwrap();
+    ingress_tx
+        .send(relay_frame(RelayPacketType::SendInfo, b"ping")?)
+        .await
+        .unwrap();
+    let received = tokio::time::timeout(
+        Duration::from_secs(1),
+        AgentExternal::recv_command(&external),
+    )
+    .await
+    .expect("ingress message not queued")?;
+    match received {
+        IceCommands::StunRequest { data, .. } => assert_eq!(data, b"ping"),
+        command => panic!("unexpected command {command:?}"),
+    }
+
+    external.lock().await.send_message("pong".to_owned())?;
+    let sent = tokio::time::timeout(Duration::from_secs(1), egress_rx.recv())
+        .await
+        .expect("egress message not drained");
+    assert_eq!(sent.as_deref(), Some("pong"));
+
+    Ok(())
+}
+
+#[test]
+fn test_agent_external_queue_full() -> Result<()> {
+    let mut external = AgentExternal::with_capacity(2);
+
+    external.send_message("1".to_owned())?;
+    external.send_message("2".to_owned())?;
+    assert_eq!(
+        external.send_message("3".to_owned()),
+        Err(Error::ErrExternalQueueFull)
+    );
+
+    assert_eq!(external.pop_egress().as_deref(), Some("1"));
+    assert_eq!(external.pop_egress().as_deref(), Some("2"));
+    assert_eq!(external.pop_egress(), None);
+    external.send_message("3".to_owned())?;
+    assert_eq!(external.pop_egress().as_deref(), Some("3"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_external_ingress_backpressure() -> Result<()> {
+    let (ingress_tx, ingress_rx) = mpsc::channel(1);
+    let (egress_tx, _egress_rx) = mpsc::channel(1);
+    let external = AgentExternal::spawn_with_capacity(ingress_rx, egress_tx, 1);
+
+    // One frame fills the queue, one waits in the ingress task and one in the channel
+    for _ in 0..3 {
+        ingress_tx
+            .send(relay_frame(RelayPacketType::SendInfo, b"stun")?)
+            .await
+            .unwrap();
+    }
+    let frame = relay_frame(RelayPacketType::SendInfo, b"stun")?;
+    assert!(ingress_tx.try_send(frame.clone()).is_err());
+
+    // Draining the queue lets the ingress task read from the channel again
+    for _ in 0..4 {
+        let command = tokio::time::timeout(
+            Duration::from_secs(1),
+            AgentExternal::recv_command(&external),
+        )
+        .await
+        .expect("ingress frame not queued")?;
+        assert!(matches!(command, IceCommands::StunRequest { .. }));
+        let _ = ingress_tx.try_send(frame.clone());
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_external_recv_command_wakes() -> Result<()> {
+    let external = Arc::new(Mutex::new(AgentExternal::new()));
+    assert_eq!(external.lock().await.get_message(), None);
+
+    let receiver = tokio::spawn({
+        let external = Arc::clone(&external);
+        async move {
+            let command = AgentExternal::recv_command(&external).await;
+            (command, Instant::now())
+        }
+    });
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!receiver.is_finished());
+    let pushed_at = Instant::now();
+    external
+        .lock()
+        .await
+        .push_ingress(&relay_frame(RelayPacketType::SendInfo, b"late")?)?;
+
+    let (command, received_at) = tokio::time::timeout(Duration::from_secs(1), receiver)
+        .await
+        .expect("receiver not woken")
+        .unwrap();
+    assert!(matches!(command, Ok(IceCommands::StunRequest { data, .. }) if data == b"late"));
+    assert!(received_at.duration_since(pushed_at) < Duration::from_millis(50));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_external_shutdown() -> Result<()> {
+    let (_ingress_tx, ingress_rx) = mpsc::channel(8);
+    let (egress_tx, mut egress_rx) = mpsc::channel(1);
+    let external = AgentExternal::spawn(ingress_rx, egress_tx);
+
+    let receiver = tokio::spawn({
+        let external = Arc::clone(&external);
+        async move { AgentExternal::recv_command(&external).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!receiver.is_finished());
+
+    // More than the channel holds, the rest waits in the queue while shutting down
+    {
+        let mut agent = external.lock().await;
+        for msg in ["1", "2", "3"] {
+            agent.send_message(msg.to_owned())?;
+        }
+        agent.shutdown();
+        assert_eq!(
+            agent.send_message("4".to_owned()),
+            Err(Error::ErrExternalClosed)
+        );
+        assert_eq!(
+            agent.push_ingress(&relay_frame(RelayPacketType::SendInfo, b"stun")?),
+            Err(Error::ErrExternalClosed)
+        );
+    }
+
+    let received = tokio::time::timeout(Duration::from_secs(1), receiver)
+        .await
+        .expect("receiver not woken")
+        .unwrap();
+    assert_eq!(received.err(), Some(Error::ErrExternalClosed));
+
+    // The queued messages are flushed, then the egress task closes the channel
+    let mut sent = vec![];
+    while let Some(msg) = tokio::time::timeout(Duration::from_secs(1), egress_rx.recv())
+        .await
+        .expect("egress not flushed")
+    {
+        sent.push(msg);
+    }
+    assert_eq!(sent, ["1", "2", "3"]);
+
+    Ok(())
+}
+
+#[test]
+fn test_agent_external_observed_mappings() -> Result<()> {
+    use stun::agent::TransactionId;
+    use stun::message::{Message, BINDING_REQUEST, BINDING_SUCCESS};
+    use stun::xoraddr::XorMappedAddress;
+
+    use crate::util::test_support::recv_info_frame;
+
+    let mut external = AgentExternal::new();
+    let local = SocketAddr::from_str("10.0.0.1:5000")?;
+    let (server_a, server_b) = (
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        SocketAddr::from_str("5.6.7.8:3478")?,
+    );
+    let recv_info = |from, typ, mapped: SocketAddr| -> Result<Vec<u8>> {
+        let mut msg = Message::new();
+        msg.build(&[
+            Box::new(typ),
+            Box::new(TransactionId::new()),
+            Box::new(XorMappedAddress {
+                ip: mapped.ip(),
+                port: mapped.port(),
+            }),
+        ])?;
+        Ok(recv_info_frame(SendInfo { from, to: local }, &msg.raw))
+    };
+
+    external.push_ingress(&recv_info(
+        server_a,
+        BINDING_SUCCESS,
+        SocketAddr::from_str("203.0.113.7:40000")?,
+    )?)?;
+    external.push_ingress(&recv_info(
+        server_b,
+        BINDING_SUCCESS,
+        SocketAddr::from_str("203.0.113.7:40001")?,
+    )?)?;
+    // Only binding successes report a mapping, neither requests nor anything that
+    // is not STUN do
+    external.push_ingress(&recv_info(
+        server_b,
+        BINDING_REQUEST,
+        SocketAddr::from_str("203.0.113.7:40009")?,
+    )?)?;
+    external.push_ingress(&relay_frame(RelayPacketType::SendInfo, b"stun")?)?;
+    assert_eq!(
+        external.observed_mappings(),
+        HashMap::from([
+            (server_a, SocketAddr::from_str("203.0.113.7:40000")?),
+            (server_b, SocketAddr::from_str("203.0.113.7:40001")?),
+        ])
+    );
+
+    // The most recent response wins
+    external.push_ingress(&recv_info(
+        server_a,
+        BINDING_SUCCESS,
+        SocketAddr::from_str("203.0.113.7:40002")?,
+    )?)?;
+    assert_eq!(
+        external.observed_mappings()[&server_a],
+        SocketAddr::from_str("203.0.113.7:40002")?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_agent_external_push_ingress_full() -> Result<()> {
+    let mut external = AgentExternal::with_capacity(1);
+    let frame = relay_frame(RelayPacketType::SendInfo, b"stun")?;
+
+    external.push_ingress(&frame)?;
+    assert_eq!(
+        external.push_ingress(&frame),
+        Err(Error::ErrExternalQueueFull)
+    );
+    assert!(external.get_message().is_some());
+    external.push_ingress(&frame)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_send_info_display() -> Result<()> {
+    let send_info = SendInfo {
+        from: SocketAddr::from_str("192.0.2.1:5000")?,
+        to: SocketAddr::from_str("198.51.100.2:3478")?,
+    };
+    assert_eq!(send_info.to_string(), "192.0.2.1:5000 → 198.51.100.2:3478");
+    assert_eq!(
+        send_info.redacted().to_string(),
+        "192.0.2.x:5000 → 198.51.100.x:3478"
+    );
+
+    let send_info = SendInfo {
+        from: SocketAddr::from_str("[2001:db8:1:2:3:4:5:6]:5000")?,
+        to: SocketAddr::from_str("198.51.100.2:3478")?,
+    };
+    assert_eq!(
+        send_info.to_string(),
+        "[2001:db8:1:2:3:4:5:6]:5000 → 198.51.100.2:3478"
+    );
+    assert_eq!(
+        send_info.redacted().to_string(),
+        "[2001:db8:1:2:x:x:x:x]:5000 → 198.51.100.x:3478"
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_send_info_serde() -> Result<()> {
+    let send_info = SendInfo {
+        from: SocketAddr::from_str("10.0.0.1:5000")?,
+        to: SocketAddr::from_str("[2001:db8::1]:3478")?,
+    };
+
+    let json = serde_json::to_string(&send_info).unwrap();
+    assert_eq!(
+        json,
+        r#"{"from":"10.0.0.1:5000","to":"[2001:db8::1]:3478"}"#
+    );
+    assert_eq!(serde_json::from_str::<SendInfo>(&json).unwrap(), send_info);
+
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_ice_commands_serde() -> Result<()> {
+    let mut data = [0u8; MAX_STUN_DATA];
+    data[..4].copy_from_slice(b"stun");
+    let commands = vec![
+        IceCommands::StunRequest {
+            data: b"stun".to_vec(),
+            from: SocketAddr::from_str("10.0.0.1:5000")?,
+            to: SocketAddr::from_str("1.2.3.4:3478")?,
+        },
+        IceCommands::StunResponse {
+            data,
+            len: 4,
+            from: SocketAddr::from_str("1.2.3.4:3478")?,
+        },
+        IceCommands::OpenSocket {
+            addr: SocketAddr::from_str("[fe80::1]:5000")?,
+        },
+    ];
+
+    for command in commands {
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(serde_json::from_str::<IceCommands>(&json).unwrap(), command);
+    }
+
+    // Only the used part of the response buffer is written out
+    let json = serde_json::to_string(&IceCommands::StunResponse {
+        data,
+        len: 4,
+        from: SocketAddr::from_str("1.2.3.4:3478")?,
+    })
+    .unwrap();
+    assert_eq!(
+        json,
+        r#"{"StunResponse":{"data":[115,116,117,110],"from":"1.2.3.4:3478"}}"#
+    );
+
+    let oversized = format!(
+        r#"{{"StunResponse":{{"data":{:?},"from":"1.2.3.4:3478"}}}}"#,
+        vec![0u8; MAX_STUN_DATA + 1]
+    );
+    assert!(serde_json::from_str::<IceCommands>(&oversized).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_parsers_never_panic_on_random_input() {
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+    for _ in 0..10000 {
+        let mut buf = vec![0u8; rng.gen_range(0..48)];
+        rng.fill(&mut buf[..]);
+        // Steer the header towards valid values so the address parsing is reached
+        for (i, byte) in buf.iter_mut().enumerate().take(3) {
+            *byte = match (i, rng.gen_range(0..3)) {
+                (0, 0) => SEND_INFO_PACKET_TYPE,
+                (0, 1) => RECV_INFO_PACKET_TYPE,
+                (1, 0 | 1) => rng.gen_range(0..48),
+                (2, 0) => ADDR_FAMILY_IPV4,
+                (2, 1) => ADDR_FAMILY_IPV6,
+                _ => *byte,
+            };
+        }
+        let len = rng.gen_range(0..64);
+
+        let _ = SendInfo::decode(&buf);
+        let _ = IceCommands::decode(&buf);
+        let _ = parse_send_info(&buf, len);
+        let _ = parse_recv_info(&buf, len);
+        if buf.len() > 2 {
+            let _ = parse_send_info(&buf[2..], len);
+        }
+    }
+}
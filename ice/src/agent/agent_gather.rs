@@ -228,7 +228,15 @@ impl Agent {
             return;
         }
 
-        let ips = local_interfaces(&net, &interface_filter, &ip_filter, &network_types).await;
+        let ips = local_interfaces(
+            &net,
+            &interface_filter,
+            &ip_filter,
+            &network_types,
+            agent_internal.include_link_local,
+        )
+        .await;
+        let ips = cap_host_addrs(ips, agent_internal.max_host_candidates);
         for ip in ips {
             let mut mapped_ip = ip;
 
@@ -282,7 +290,6 @@ impl Agent {
                     ephemeral_config.port_max(),
                     ephemeral_config.port_min(),
                     SocketAddr::new(ip, 0),
-                    agent_internal.relay_listener_port,
                 )
                 .await
                 {
@@ -299,6 +306,25 @@ impl Agent {
                     }
                 };
 
+                #[cfg(feature = "relay")]
+                if let Err(err) = register_relay_socket(
+                    &net,
+                    &conn,
+                    &agent_internal.relay_config,
+                    Some(&agent_internal.stun_cancel),
+                )
+                .await
+                {
+                    log::warn!(
+                        "[{}]: could not open relay socket {} {}: {}",
+                        agent_internal.get_name(),
+                        network,
+                        ip,
+                        err
+                    );
+                    continue;
+                }
+
                 let port = match conn.local_addr() {
                     Ok(addr) => addr.port(),
                     Err(err) => {
@@ -393,8 +419,15 @@ impl Agent {
 
         let udp_mux = Arc::clone(&udp_mux);
 
-        let local_ips =
-            local_interfaces(&net, &interface_filter, &ip_filter, &relevant_network_types).await;
+        let local_ips = local_interfaces(
+            &net,
+            &interface_filter,
+            &ip_filter,
+            &relevant_network_types,
+            agent_internal.include_link_local,
+        )
+        .await;
+        let local_ips = cap_host_addrs(local_ips, agent_internal.max_host_candidates);
 
         let candidate_ips: Vec<std::net::IpAddr> = ext_ip_mapper
             .as_ref() // Arc
@@ -421,7 +454,7 @@ impl Agent {
                         .collect(),
                 )
             })
-            .unwrap_or_else(|| local_ips.iter().copied().collect());
+            .unwrap_or(local_ips);
 
         if candidate_ips.is_empty() {
             return Err(Error::ErrCandidateIpNotFound);
@@ -493,7 +526,6 @@ impl Agent {
                     } else {
                         SocketAddr::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into(), 0)
                     },
-                    agent_internal2.relay_listener_port,
                 )
                 .await
                 {
@@ -509,6 +541,24 @@ impl Agent {
                     }
                 };
 
+                #[cfg(feature = "relay")]
+                if let Err(err) = register_relay_socket(
+                    &net2,
+                    &conn,
+                    &agent_internal2.relay_config,
+                    Some(&agent_internal2.stun_cancel),
+                )
+                .await
+                {
+                    log::warn!(
+                        "[{}]: could not open relay socket {}: {}",
+                        agent_internal2.get_name(),
+                        network,
+                        err
+                    );
+                    return Ok(());
+                }
+
                 let laddr = conn.local_addr()?;
                 let mapped_ip = {
                     if let Some(ext_ip_mapper3) = &*ext_ip_mapper2 {
@@ -628,7 +678,18 @@ impl Agent {
                         }
                     };
 
-                    let conn: Arc<dyn Conn + Send + Sync> = match listen_udp_in_port_range(
+                    let reuse_port = agent_internal2.relay_config.reuse_port;
+                    let options = ListenOptions {
+                        // Rebinding the same port only works if it is picked deterministically
+                        strategy: if reuse_port {
+                            PortAllocationStrategy::Sequential
+                        } else {
+                            PortAllocationStrategy::Random
+                        },
+                        reuse_port,
+                        ..Default::default()
+                    };
+                    let conn: Arc<dyn Conn + Send + Sync> = match listen_udp_in_port_range_with_options(
                         &net2,
                         port_max,
                         port_min,
@@ -637,7 +698,7 @@ impl Agent {
                         } else {
                             SocketAddr::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into(), 0)
                         },
-                        agent_internal2.relay_listener_port,
+                        &options,
                     )
                     .await
                     {
@@ -652,10 +713,55 @@ impl Agent {
                             return Ok(());
                         }
                     };
-                    info!("Bound to port range at: {}", conn.local_addr().unwrap());
+                    match bound_addr(&conn) {
+                        Ok(addr) => info!("Bound to port range at: {}", addr),
+                        Err(err) => {
+                            log::warn!(
+                                "[{}]: could not get local addr: {}",
+                                agent_internal2.get_name(),
+                                err
+                            );
+                            return Ok(());
+                        }
+                    }
+
+                    #[cfg(feature = "relay")]
+                    if let Err(err) = check_relay_available(
+                        &conn,
+                        &agent_internal2.relay_config,
+                        Some(&agent_internal2.stun_cancel),
+                    )
+                    .await
+                    {
+                        log::warn!(
+                            "[{}]: skipping {}: {}",
+                            agent_internal2.get_name(),
+                            url,
+                            err
+                        );
+                        return Ok(());
+                    }
+
+                    #[cfg(feature = "relay")]
+                    if let Err(err) = register_relay_socket(
+                        &net2,
+                        &conn,
+                        &agent_internal2.relay_config,
+                        Some(&agent_internal2.stun_cancel),
+                    )
+                    .await
+                    {
+                        log::warn!(
+                            "[{}]: could not open relay socket for {}: {}",
+                            agent_internal2.get_name(),
+                            url,
+                            err
+                        );
+                        return Ok(());
+                    }
 
                     let xoraddr_recvon =
-                        match get_xormapped_addr(&conn, server_addr, STUN_GATHER_TIMEOUT, agent_internal2.relay_listener_port).await {
+                        match get_xormapped_addr(&conn, server_addr, STUN_GATHER_TIMEOUT, &agent_internal2.relay_config, Some(&agent_internal2.stun_metrics), None, Some(&agent_internal2.stun_cancel)).await {
                             Ok(xoraddr) => xoraddr,
                             Err(err) => {
                                 log::warn!(
@@ -783,7 +889,7 @@ impl Agent {
 
                 let (loc_conn, rel_addr, rel_port) =
                     if url.proto == ProtoType::Udp && url.scheme == SchemeType::Turn {
-                        let loc_conn = match net2.bind(SocketAddr::from_str("0.0.0.0:0")?, agent_internal2.relay_listener_port).await {
+                        let loc_conn = match net2.bind(SocketAddr::from_str("0.0.0.0:0")?).await {
                             Ok(c) => c,
                             Err(err) => {
                                 log::warn!(
@@ -795,6 +901,24 @@ impl Agent {
                             }
                         };
 
+                        #[cfg(feature = "relay")]
+                        if let Err(err) = register_relay_socket(
+                            &net2,
+                            &loc_conn,
+                            &agent_internal2.relay_config,
+                            Some(&agent_internal2.stun_cancel),
+                        )
+                        .await
+                        {
+                            log::warn!(
+                                "[{}]: could not open relay socket for {}: {}",
+                                agent_internal2.get_name(),
+                                turn_server_addr,
+                                err
+                            );
+                            return Ok(());
+                        }
+
                         let local_addr = loc_conn.local_addr()?;
                         let rel_addr = local_addr.ip().to_string();
                         let rel_port = local_addr.port();
@@ -24,6 +24,7 @@ async fn test_vnet_gather_no_local_ip_address() -> Result<()> {
         &a.interface_filter,
         &a.ip_filter,
         &[NetworkType::Udp4],
+        false,
     )
     .await;
     assert!(local_ips.is_empty(), "should return no local IP");
@@ -51,8 +52,14 @@ async fn test_vnet_gather_dynamic_ip_address() -> Result<()> {
     })
     .await?;
 
-    let local_ips =
-        local_interfaces(&nw, &a.interface_filter, &a.ip_filter, &[NetworkType::Udp4]).await;
+    let local_ips = local_interfaces(
+        &nw,
+        &a.interface_filter,
+        &a.ip_filter,
+        &[NetworkType::Udp4],
+        false,
+    )
+    .await;
     assert!(!local_ips.is_empty(), "should have one local IP");
 
     for ip in &local_ips {
@@ -85,20 +92,26 @@ async fn test_vnet_gather_listen_udp() -> Result<()> {
     })
     .await?;
 
-    let local_ips =
-        local_interfaces(&nw, &a.interface_filter, &a.ip_filter, &[NetworkType::Udp4]).await;
+    let local_ips = local_interfaces(
+        &nw,
+        &a.interface_filter,
+        &a.ip_filter,
+        &[NetworkType::Udp4],
+        false,
+    )
+    .await;
     assert!(!local_ips.is_empty(), "should have one local IP");
 
     for ip in local_ips {
-        let _ = listen_udp_in_port_range(&nw, 0, 0, SocketAddr::new(ip, 0), 0).await?;
+        let _ = listen_udp_in_port_range(&nw, 0, 0, SocketAddr::new(ip, 0)).await?;
 
-        let result = listen_udp_in_port_range(&nw, 4999, 5000, SocketAddr::new(ip, 0), 0).await;
+        let result = listen_udp_in_port_range(&nw, 4999, 5000, SocketAddr::new(ip, 0)).await;
         assert!(
             result.is_err(),
             "listenUDP with invalid port range did not return ErrPort"
         );
 
-        let conn = listen_udp_in_port_range(&nw, 5000, 5000, SocketAddr::new(ip, 0), 0).await?;
+        let conn = listen_udp_in_port_range(&nw, 5000, 5000, SocketAddr::new(ip, 0)).await?;
         let port = conn.local_addr()?.port();
         assert_eq!(
             port, 5000,
@@ -340,8 +353,14 @@ async fn test_vnet_gather_with_interface_filter() -> Result<()> {
         })
         .await?;
 
-        let local_ips =
-            local_interfaces(&nw, &a.interface_filter, &a.ip_filter, &[NetworkType::Udp4]).await;
+        let local_ips = local_interfaces(
+            &nw,
+            &a.interface_filter,
+            &a.ip_filter,
+            &[NetworkType::Udp4],
+            false,
+        )
+        .await;
         assert!(
             local_ips.is_empty(),
             "InterfaceFilter should have excluded everything"
@@ -361,8 +380,14 @@ async fn test_vnet_gather_with_interface_filter() -> Result<()> {
         })
         .await?;
 
-        let local_ips =
-            local_interfaces(&nw, &a.interface_filter, &a.ip_filter, &[NetworkType::Udp4]).await;
+        let local_ips = local_interfaces(
+            &nw,
+            &a.interface_filter,
+            &a.ip_filter,
+            &[NetworkType::Udp4],
+            false,
+        )
+        .await;
         assert_eq!(
             local_ips.len(),
             1,
@@ -375,6 +400,101 @@ async fn test_vnet_gather_with_interface_filter() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_interface_policy_glob() {
+    for (pattern, name, expected) in [
+        ("docker*", "docker0", true),
+        ("docker*", "docker", true),
+        ("docker*", "eth0", false),
+        ("*0", "docker0", true),
+        ("eth?", "eth1", true),
+        ("eth?", "eth10", false),
+        ("*br-*", "virbr-lan", true),
+        ("eth0", "eth0", true),
+        ("eth0", "eth01", false),
+        ("*", "", true),
+        ("", "eth0", false),
+    ] {
+        assert_eq!(
+            glob_match(pattern, name),
+            expected,
+            "{pattern} against {name}"
+        );
+    }
+}
+
+#[test]
+fn test_interface_policy_precedence() {
+    let deny_only = InterfacePolicy {
+        deny: vec!["docker*".to_owned(), "veth*".to_owned()],
+        ..Default::default()
+    };
+    assert!(deny_only.allows("eth0"));
+    assert!(!deny_only.allows("docker0"));
+    assert!(!deny_only.allows("veth1234"));
+
+    // An allowed name wins over a matching deny pattern, everything else is rejected
+    let filter = InterfacePolicy {
+        allow: vec!["eth*".to_owned(), "docker1".to_owned()],
+        deny: vec!["docker*".to_owned()],
+    }
+    .into_filter();
+    assert!(filter("eth0"));
+    assert!(filter("docker1"));
+    assert!(!filter("docker0"));
+    assert!(!filter("wlan0"));
+
+    assert!(InterfacePolicy::default().allows("anything"));
+}
+
+#[tokio::test]
+async fn test_vnet_gather_with_interface_policy() -> Result<()> {
+    let r = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "1.2.3.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let nw = Arc::new(net::Net::new(Some(net::NetConfig::default())));
+    connect_net2router(&nw, &r).await?;
+
+    for (policy, expected) in [
+        (
+            InterfacePolicy {
+                deny: vec!["eth*".to_owned()],
+                ..Default::default()
+            },
+            0,
+        ),
+        (
+            InterfacePolicy {
+                allow: vec!["eth0".to_owned()],
+                deny: vec!["eth*".to_owned()],
+            },
+            1,
+        ),
+    ] {
+        let a = Agent::new(AgentConfig {
+            net: Some(Arc::clone(&nw)),
+            interface_filter: Arc::new(Some(policy.clone().into_filter())),
+            ..Default::default()
+        })
+        .await?;
+
+        let local_ips = local_interfaces(
+            &nw,
+            &a.interface_filter,
+            &a.ip_filter,
+            &[NetworkType::Udp4],
+            false,
+        )
+        .await;
+        assert_eq!(local_ips.len(), expected, "{policy:?}");
+
+        a.close().await?;
+    }
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_vnet_gather_turn_connection_leak() -> Result<()> {
     let turn_server_url = Url {
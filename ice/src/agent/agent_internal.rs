@@ -1,17 +1,20 @@
 use std::borrow::BorrowMut;
 use std::sync::atomic::{AtomicBool, AtomicU64};
 
-use agent_internal::agent_external::{parse_recv_info, parse_send_info};
 use arc_swap::ArcSwapOption;
-use log::{debug, info};
+use log::info;
+use tokio_util::sync::CancellationToken;
 use util::sync::Mutex as SyncMutex;
 
-use self::agent_external::{AgentExternal};
+#[cfg(feature = "relay")]
+use self::agent_external::{AgentExternal, IceCommands};
 
 use super::agent_transport::*;
 use super::*;
 use crate::candidate::candidate_base::CandidateBaseConfig;
 use crate::candidate::candidate_peer_reflexive::CandidatePeerReflexiveConfig;
+#[cfg(feature = "relay")]
+use crate::util::relayed_conn::RelayedConn;
 use crate::util::*;
 
 pub type ChanCandidateTx =
@@ -49,6 +52,11 @@ pub struct AgentInternal {
     pub(crate) tie_breaker: AtomicU64,
     pub(crate) is_controlling: AtomicBool,
     pub(crate) lite: AtomicBool,
+    pub(crate) include_link_local: bool,
+    pub(crate) max_host_candidates: Option<usize>,
+    pub(crate) stun_metrics: StunMetrics,
+    /// Canceled on close to abort STUN requests still waiting for a response.
+    pub(crate) stun_cancel: CancellationToken,
 
     pub(crate) start_time: SyncMutex<Instant>,
     pub(crate) nominated_pair: Mutex<Option<Arc<CandidatePair>>>,
@@ -63,10 +71,10 @@ pub struct AgentInternal {
     pub(crate) remote_candidates:
         Mutex<HashMap<NetworkType, Vec<Arc<dyn Candidate + Send + Sync>>>>,
     
-    // These channels can be used to move the sending and receiving
-    // of STNU etc. out of the agent into an external program
-    // This might be relevant if STUN and other data should be multiplexed
-    // on the same socket
+    // Moves the sending and receiving of STUN etc. out of the agent into an external
+    // program, set from `AgentConfig::external_channels`. This might be relevant if
+    // STUN and other data should be multiplexed on the same socket
+    #[cfg(feature = "relay")]
     pub(crate) external_comm: Option<Arc<Mutex<AgentExternal>>>,
 
     // LRU of outbound Binding request Transaction IDs
@@ -94,7 +102,7 @@ pub struct AgentInternal {
     pub(crate) check_interval: Duration,
 
     // Where to relay the STUN requests to
-    pub(crate) relay_listener_port: u16,
+    pub(crate) relay_config: RelayConfig,
 }
 
 impl AgentInternal {
@@ -107,25 +115,6 @@ impl AgentInternal {
         let (force_candidate_contact_tx, force_candidate_contact_rx) = mpsc::channel(1);
         let (started_ch_tx, _) = broadcast::channel(1);
 
-        let mut agent_external = None;        
-        // if tx.is_some() {
-        agent_external = Some(Arc::new(Mutex::new(AgentExternal::new())));
-        //     let agent2 = Arc::clone(&agent_external.unwrap());
-        //     let agent3 = Arc::clone(&agent2);
-        //     let agent4 = Arc::clone(&agent3);
-        //     start_external_listener(agent2, rx.unwrap()).unwrap();
-        //     start_external_send(agent3, tx.unwrap()).unwrap();
-        //     agent_external = Some(Arc::clone(&agent4));
-        // }
-
-        let mut relay_listener_endpoint = 12345;
-        if let Some(listen_endpoint) = &config.relay_listener_endpoint {
-            let ip_and_port = listen_endpoint.split(":");
-            let parts = ip_and_port.collect::<Vec<&str>>();
-            let port = parts[1].parse::<u16>().unwrap();
-            relay_listener_endpoint = port;
-        }
-
         let ai = AgentInternal {
             on_connected_tx: Mutex::new(Some(on_connected_tx)),
             on_connected_rx: Mutex::new(Some(on_connected_rx)),
@@ -148,6 +137,10 @@ impl AgentInternal {
             tie_breaker: AtomicU64::new(rand::random::<u64>()),
             is_controlling: AtomicBool::new(config.is_controlling),
             lite: AtomicBool::new(config.lite),
+            include_link_local: config.include_link_local,
+            max_host_candidates: config.max_host_candidates,
+            stun_metrics: StunMetrics::default(),
+            stun_cancel: CancellationToken::new(),
 
             start_time: SyncMutex::new(Instant::now()),
             nominated_pair: Mutex::new(None),
@@ -180,7 +173,7 @@ impl AgentInternal {
             // How often should we run our internal taskLoop to check for state changes when connecting
             check_interval: Duration::from_millis(200),
 
-            relay_listener_port: relay_listener_endpoint,
+            relay_config: RelayConfig::default(),
 
             ufrag_pwd: Mutex::new(UfragPwd::default()),
 
@@ -191,7 +184,8 @@ impl AgentInternal {
             // Register a send channel with the ICE agent. This will move all communication out of the
             // ICE agent itself and the receiver of the channel is responsible for opening, sending and receiving data
             // (e.g. STUN requests) instead of the agent itself
-            external_comm: agent_external,
+            #[cfg(feature = "relay")]
+            external_comm: None,
 
             // LRU of outbound Binding request Transaction IDs
             pending_binding_requests: Mutex::new(vec![]),
@@ -230,6 +224,9 @@ impl AgentInternal {
         self.set_remote_credentials(remote_ufrag, remote_pwd)
             .await?;
         self.is_controlling.store(is_controlling, Ordering::SeqCst);
+        self.agent_conn
+            .relay_config
+            .store(Some(Arc::new(self.relay_config.clone())));
         self.start().await;
         {
             let mut started_ch_tx = self.started_ch_tx.lock().await;
@@ -347,8 +344,6 @@ impl AgentInternal {
     }
 
     pub(crate) async fn update_connection_state(&self, new_state: ConnectionState) {
-        // info!("Sending message from ICE");
-        // self.external_comm.as_ref().unwrap().lock().await.send_message("Test".to_string());
         if self.connection_state.load(Ordering::SeqCst) != new_state as u8 {
             // Connection has gone to failed, release all gathered candidates
             if new_state == ConnectionState::Failed {
@@ -363,6 +358,17 @@ impl AgentInternal {
             self.connection_state
                 .store(new_state as u8, Ordering::SeqCst);
 
+            #[cfg(feature = "relay")]
+            if let Some(external) = &self.external_comm {
+                if let Err(err) = external.lock().await.send_message(new_state.to_string()) {
+                    log::warn!(
+                        "[{}]: Failed to report the connection state: {}",
+                        self.get_name(),
+                        err
+                    );
+                }
+            }
+
             // Call handler after finishing current task since we may be holding the agent lock
             // and the handler may also require it
             {
@@ -668,6 +674,7 @@ impl AgentInternal {
             }
             done_tx.take();
         };
+        self.stun_cancel.cancel();
         self.delete_all_candidates().await;
         {
             let mut started_ch_tx = self.started_ch_tx.lock().await;
@@ -678,6 +685,12 @@ impl AgentInternal {
 
         self.update_connection_state(ConnectionState::Closed).await;
 
+        // Flushes the closed state to the external program and ends its command routine
+        #[cfg(feature = "relay")]
+        if let Some(external) = &self.external_comm {
+            external.lock().await.shutdown();
+        }
+
         {
             let mut chan_candidate_tx = self.chan_candidate_tx.lock().await;
             chan_candidate_tx.take();
@@ -1063,7 +1076,7 @@ impl AgentInternal {
         remote: &Arc<dyn Candidate + Send + Sync>,
     ) {
         // TODO: Fix sending stun to remote, send relay to the quicheperf socket
-        if let Err(err) = local.write_to(&msg.raw, &**remote, self.relay_listener_port).await {
+        if let Err(err) = local.write_to(&msg.raw, &**remote, &self.relay_config).await {
             log::trace!(
                 "[{}]: failed to send STUN message: {}",
                 self.get_name(),
@@ -1100,6 +1113,63 @@ impl AgentInternal {
         }
     }
 
+    /// Carries out the commands the external program sends over `external_comm` until
+    /// the agent is closed. Each command runs in a task of its own, so an `OpenSocket`
+    /// waiting for its acknowledgement does not hold up the ones behind it.
+    #[cfg(feature = "relay")]
+    pub(super) fn start_external_routine(self: &Arc<Self>) {
+        let Some(external) = self.external_comm.clone() else {
+            return;
+        };
+        let ai = Arc::clone(self);
+        tokio::spawn(async move {
+            while let Ok(command) = AgentExternal::recv_command(&external).await {
+                let ai = Arc::clone(&ai);
+                tokio::spawn(async move {
+                    if let Err(err) = ai.handle_external_command(command).await {
+                        log::warn!(
+                            "[{}]: Failed to carry out relay command: {}",
+                            ai.get_name(),
+                            err
+                        );
+                    }
+                });
+            }
+        });
+    }
+
+    #[cfg(feature = "relay")]
+    async fn handle_external_command(&self, command: IceCommands) -> Result<()> {
+        match command {
+            IceCommands::StunRequest { data, from, to } => {
+                let conn = self.find_local_conn(from).await?;
+                let relayed = RelayedConn::with_source_addr(conn, &self.relay_config, from);
+                util::Conn::send_to(&relayed, &data, to).await?;
+            }
+            IceCommands::OpenSocket { addr } => {
+                let conn = self.find_local_conn(addr).await?;
+                open_relay_socket(&conn, &self.relay_config, Some(&self.stun_cancel)).await?;
+            }
+            // The mapping it reports was recorded as the frame was queued
+            IceCommands::StunResponse { from, .. } => {
+                log::trace!("[{}]: Relayed STUN response from {}", self.get_name(), from);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the conn of the local candidate at `addr`.
+    #[cfg(feature = "relay")]
+    async fn find_local_conn(&self, addr: SocketAddr) -> Result<Arc<dyn util::Conn + Send + Sync>> {
+        let local_candidates = self.local_candidates.lock().await;
+        local_candidates
+            .values()
+            .flatten()
+            .find(|c| c.addr() == addr)
+            .and_then(|c| c.get_conn().cloned())
+            .ok_or(Error::ErrUnknownLocalCandidate(addr))
+    }
+
     pub(super) fn start_on_connection_state_change_routine(
         self: &Arc<Self>,
         mut chan_state_rx: mpsc::Receiver<ConnectionState>,
@@ -1177,6 +1247,11 @@ impl AgentInternal {
             }
         }
 
+        // The relay frames are unwrapped by the conn, the loop sees the original peers
+        #[cfg(feature = "relay")]
+        let conn: Arc<dyn util::Conn + Send + Sync> =
+            Arc::new(RelayedConn::with_source_addr(conn, &self.relay_config, addr));
+
         let mut buffer = vec![0_u8; RECEIVE_MTU];
         let mut n;
         let mut src_addr;
@@ -1194,22 +1269,8 @@ impl AgentInternal {
                 _  = closed_ch_rx.recv() => return Err(Error::ErrClosed),
             }
 
-            let p_type = buffer[0];
-            match p_type {
-                0xCC => {
-                    debug!("Received relayed packet in ICE, extracting relay information");
-                    let len = buffer[1];
-                    let recv_info = parse_send_info(&buffer[2..], len as usize).unwrap();
-                    src_addr = recv_info.from;
-
-                    self.handle_inbound_candidate_msg(&candidate, &buffer[(2 + len as usize)..n], src_addr, addr)
-                    .await;
-                },
-                _ => {
-                    self.handle_inbound_candidate_msg(&candidate, &buffer[..n], src_addr, addr)
-                    .await;
-                }
-            }
+            self.handle_inbound_candidate_msg(&candidate, &buffer[..n], src_addr, addr)
+            .await;
         }
     }
 
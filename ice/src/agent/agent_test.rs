@@ -870,6 +870,347 @@ async fn test_inbound_validity() -> Result<()> {
     Ok(())
 }
 
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_relayed_binding_carries_session_id() -> Result<()> {
+    use tokio::net::UdpSocket;
+
+    use crate::agent::agent_config::RelayConfig;
+    use crate::agent::agent_external::{
+        insert_session_id, split_send_info, SendInfo, SESSION_ID_LEN,
+    };
+    use crate::util::relay_codec::strip_session_id;
+    use crate::util::test_support::recv_info_frame;
+
+    const SESSION_ID: u16 = 0x0707;
+
+    let relay = UdpSocket::bind("127.0.0.1:0").await?;
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let local_addr = conn.local_addr()?;
+    let relay_config = RelayConfig {
+        relay_addr: relay.local_addr()?,
+        session_id: SESSION_ID,
+        ..Default::default()
+    };
+    let a = Agent::new(AgentConfig {
+        relay_config: Some(relay_config),
+        ..Default::default()
+    })
+    .await?;
+    let local: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: local_addr.ip().to_string(),
+                port: local_addr.port(),
+                component: 1,
+                conn: Some(conn),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host()?,
+    );
+    a.internal.add_candidate(&local).await?;
+    a.internal
+        .start_connectivity_checks(false, "remoteufrag".to_owned(), "remotepwd".to_owned())
+        .await?;
+
+    // The relay hands us a binding request of the peer, tagged for our session
+    let (username, local_pwd) = {
+        let ufrag_pwd = a.internal.ufrag_pwd.lock().await;
+        (
+            format!("{}:{}", ufrag_pwd.local_ufrag, ufrag_pwd.remote_ufrag),
+            ufrag_pwd.local_pwd.clone(),
+        )
+    };
+    let request = build_msg(CLASS_REQUEST, username, local_pwd)?;
+    let peer = SocketAddr::from_str("172.17.0.3:999")?;
+    let mut frame = recv_info_frame(SendInfo::new(peer, local_addr)?, &request.raw);
+    insert_session_id(&mut frame, SESSION_ID);
+    relay.send_to(&frame, local_addr).await?;
+
+    // The answer comes back through the relay, tagged for the same session
+    let mut buf = vec![0_u8; 1500];
+    let (n, from) = tokio::time::timeout(Duration::from_secs(5), relay.recv_from(&mut buf))
+        .await
+        .expect("binding success through the relay")?;
+    assert_eq!(from, local_addr);
+    assert_eq!(buf[1..1 + SESSION_ID_LEN], SESSION_ID.to_be_bytes());
+    let n = strip_session_id(&mut buf[..n], SESSION_ID)?;
+    let (send_info, payload) = split_send_info(&buf[..n])?;
+    assert_eq!(send_info.from, local_addr);
+    assert_eq!(send_info.to, peer);
+    let mut response = Message::new();
+    response.raw = payload.to_vec();
+    response.decode()?;
+    assert_eq!(response.typ, BINDING_SUCCESS);
+    assert_eq!(response.transaction_id, request.transaction_id);
+
+    a.close().await?;
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_relayed_binding_success_is_observed() -> Result<()> {
+    use tokio::net::UdpSocket;
+
+    use crate::util::get_xormapped_addr;
+    use crate::util::relay_server_test::TestRelay;
+    use crate::util::test_support::binding_success_to;
+
+    // Each server sees us on a port of its own, an address dependent mapping
+    let server_a = SocketAddr::from_str("192.0.2.1:3478")?;
+    let server_b = SocketAddr::from_str("192.0.2.2:3478")?;
+    let mapped_a = SocketAddr::from_str("203.0.113.7:40000")?;
+    let mapped_b = SocketAddr::from_str("203.0.113.7:40001")?;
+    let relay = TestRelay::start(Box::new(move |request, send_info| {
+        let mapped = if send_info.to == server_a {
+            mapped_a
+        } else {
+            mapped_b
+        };
+        Some(binding_success_to(request, mapped))
+    }))
+    .await?;
+    let a = Agent::new(AgentConfig {
+        relay_config: Some(relay.relay_config()),
+        ..Default::default()
+    })
+    .await?;
+    assert!(a.get_observed_mappings().is_empty());
+
+    // Gathering queries the servers with the agent's relay config
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    for server in [server_a, server_b] {
+        get_xormapped_addr(
+            &conn,
+            server,
+            Duration::from_secs(1),
+            &a.internal.relay_config,
+            None,
+            None,
+            None,
+        )
+        .await?;
+    }
+
+    assert_eq!(
+        a.get_observed_mappings(),
+        HashMap::from([(server_a, mapped_a), (server_b, mapped_b)])
+    );
+
+    a.close().await?;
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_agent_reports_connection_state_to_external() -> Result<()> {
+    let (_ingress_tx, ingress_rx) = mpsc::channel(8);
+    let (egress_tx, mut egress_rx) = mpsc::channel(8);
+    let a = Agent::new(AgentConfig {
+        external_channels: Some((ingress_rx, egress_tx)),
+        ..Default::default()
+    })
+    .await?;
+    a.close().await?;
+
+    let state = tokio::time::timeout(Duration::from_secs(5), egress_rx.recv())
+        .await
+        .expect("connection state reported to the external program");
+    assert_eq!(state.as_deref(), Some("Closed"));
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_agent_close_shuts_down_external() -> Result<()> {
+    let (ingress_tx, ingress_rx) = mpsc::channel(8);
+    let (egress_tx, mut egress_rx) = mpsc::channel(8);
+    let a = Agent::new(AgentConfig {
+        external_channels: Some((ingress_rx, egress_tx)),
+        ..Default::default()
+    })
+    .await?;
+    let external = a.internal.external_comm.clone().expect("external channels");
+    a.close().await?;
+
+    // The closed state is still flushed, then the egress task lets go of its channel
+    tokio::time::timeout(Duration::from_secs(5), async {
+        assert_eq!(egress_rx.recv().await.as_deref(), Some("Closed"));
+        assert_eq!(egress_rx.recv().await, None);
+    })
+    .await
+    .expect("egress closed after the agent");
+
+    assert_eq!(
+        external.lock().await.send_message("late".to_owned()),
+        Err(Error::ErrExternalClosed)
+    );
+    // The ingress task stops at the next frame and lets go of its channel as well
+    let _ = ingress_tx.send(vec![]).await;
+    tokio::time::timeout(Duration::from_secs(5), ingress_tx.closed())
+        .await
+        .expect("ingress closed after the agent");
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_agent_carries_out_external_commands() -> Result<()> {
+    use tokio::net::UdpSocket;
+
+    use crate::agent::agent_config::RelayConfig;
+    use crate::agent::agent_external::{encode_open_socket, RelayPacketType, SendInfo};
+    use crate::util::relay_codec::{decode_relay_frame, RelayFrame};
+
+    let relay = UdpSocket::bind("127.0.0.1:0").await?;
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let local_addr = conn.local_addr()?;
+    let (ingress_tx, ingress_rx) = mpsc::channel(8);
+    let (egress_tx, _egress_rx) = mpsc::channel(8);
+    let a = Agent::new(AgentConfig {
+        relay_config: Some(RelayConfig {
+            relay_addr: relay.local_addr()?,
+            open_socket: false,
+            ..Default::default()
+        }),
+        external_channels: Some((ingress_rx, egress_tx)),
+        ..Default::default()
+    })
+    .await?;
+    let local: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: local_addr.ip().to_string(),
+                port: local_addr.port(),
+                component: 1,
+                conn: Some(conn),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host()?,
+    );
+    a.internal.add_candidate(&local).await?;
+
+    // The program has the relay open the candidate's socket
+    ingress_tx
+        .send(encode_open_socket(RelayPacketType::OpenSocket, local_addr)?)
+        .await
+        .expect("agent listening for commands");
+    let mut buf = vec![0_u8; 1500];
+    let (n, from) = tokio::time::timeout(Duration::from_secs(5), relay.recv_from(&mut buf))
+        .await
+        .expect("OpenSocket at the relay")?;
+    assert_eq!(from, local_addr);
+    assert!(matches!(
+        decode_relay_frame(&buf[..n])?,
+        RelayFrame::OpenSocket(addr) if addr == local_addr
+    ));
+
+    // and sends a STUN request from it through the relay
+    let server = SocketAddr::from_str("192.0.2.1:3478")?;
+    let request = Message::new().raw;
+    let mut frame = SendInfo::new(local_addr, server)?.encode()?;
+    frame.extend_from_slice(&request);
+    ingress_tx
+        .send(frame)
+        .await
+        .expect("agent listening for commands");
+    // The unacknowledged OpenSocket may be retransmitted in between
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let (n, from) = relay.recv_from(&mut buf).await?;
+            assert_eq!(from, local_addr);
+            if let RelayFrame::SendInfo { send_info, payload } = decode_relay_frame(&buf[..n])? {
+                assert_eq!(send_info, SendInfo::new(local_addr, server)?);
+                assert_eq!(payload, &request[..]);
+                return Result::<()>::Ok(());
+            }
+        }
+    })
+    .await
+    .expect("STUN request at the relay")?;
+
+    a.close().await?;
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_application_data_goes_to_the_configured_relay() -> Result<()> {
+    use tokio::net::UdpSocket;
+
+    use crate::agent::agent_external::split_send_info;
+
+    // Two agents on one host, each with a relay of its own
+    let relays = [
+        UdpSocket::bind("127.0.0.1:0").await?,
+        UdpSocket::bind("127.0.0.1:0").await?,
+    ];
+    for relay in &relays {
+        let relay_addr = relay.local_addr()?;
+        let a = Agent::new(AgentConfig {
+            relay_listener_endpoint: Some(relay_addr.to_string()),
+            ..Default::default()
+        })
+        .await?;
+
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let local_addr = conn.local_addr()?;
+        let local = CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: local_addr.ip().to_string(),
+                port: local_addr.port(),
+                component: 1,
+                conn: Some(conn),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host()?;
+        let remote = CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: "192.0.2.1".to_owned(),
+                port: 5000,
+                component: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host()?;
+        a.internal
+            .start_connectivity_checks(true, "remoteufrag".to_owned(), "remotepwd".to_owned())
+            .await?;
+        let pair = CandidatePair::new(Arc::new(local), Arc::new(remote), true);
+        a.internal.set_selected_pair(Some(Arc::new(pair))).await;
+
+        a.internal.agent_conn.send(b"data").await?;
+
+        let mut buf = vec![0_u8; 1500];
+        let (n, from) = tokio::time::timeout(Duration::from_secs(5), relay.recv_from(&mut buf))
+            .await
+            .expect("application data at the agent's relay")?;
+        assert_eq!(from, local_addr);
+        let (send_info, payload) = split_send_info(&buf[..n])?;
+        assert_eq!(send_info.from, local_addr);
+        assert_eq!(send_info.to, SocketAddr::from_str("192.0.2.1:5000")?);
+        assert_eq!(payload, b"data");
+
+        a.close().await?;
+    }
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_invalid_agent_starts() -> Result<()> {
     let a = Agent::new(AgentConfig::default()).await?;
@@ -6,6 +6,7 @@ use async_trait::async_trait;
 use util::Conn;
 
 use super::*;
+use crate::agent::agent_config::RelayConfig;
 use crate::error::*;
 
 impl Agent {
@@ -84,6 +85,8 @@ impl Agent {
 pub(crate) struct AgentConn {
     pub(crate) selected_pair: ArcSwapOption<CandidatePair>,
     pub(crate) checklist: Mutex<Vec<Arc<CandidatePair>>>,
+    /// The relay config application data is sent with, set once the agent is started.
+    pub(crate) relay_config: ArcSwapOption<RelayConfig>,
 
     pub(crate) buffer: Buffer,
     pub(crate) bytes_received: AtomicUsize,
@@ -96,6 +99,7 @@ impl AgentConn {
         Self {
             selected_pair: ArcSwapOption::empty(),
             checklist: Mutex::new(vec![]),
+            relay_config: ArcSwapOption::empty(),
             // Make sure the buffer doesn't grow indefinitely.
             // NOTE: We actually won't get anywhere close to this limit.
             // SRTP will constantly read from the endpoint and drop packets if it's full.
@@ -203,10 +207,11 @@ impl Conn for AgentConn {
             return Err(util::Error::Other("ErrIceWriteStunMessage".into()));
         }
 
+        let relay_config = self.relay_config.load_full().unwrap_or_default();
         let result = if let Some(pair) = self.get_selected_pair() {
-            pair.write(buf).await
+            pair.write(buf, &relay_config).await
         } else if let Some(pair) = self.get_best_available_candidate_pair().await {
-            pair.write(buf).await
+            pair.write(buf, &relay_config).await
         } else {
             Ok(0)
         };
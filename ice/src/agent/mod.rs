@@ -1,3 +1,5 @@
+#[cfg(all(test, feature = "relay"))]
+mod agent_external_test;
 #[cfg(test)]
 mod agent_gather_test;
 #[cfg(test)]
@@ -13,6 +15,7 @@ pub(crate) mod agent_internal;
 pub mod agent_selector;
 pub mod agent_stats;
 pub mod agent_transport;
+#[cfg(feature = "relay")]
 pub mod agent_external;
 
 use std::collections::HashMap;
@@ -50,6 +53,7 @@ use crate::tcp_type::TcpType;
 use crate::udp_mux::UDPMux;
 use crate::udp_network::UDPNetwork;
 use crate::url::*;
+use crate::util::StunMetricsSnapshot;
 
 #[derive(Debug, Clone)]
 pub(crate) struct BindingRequest {
@@ -125,7 +129,7 @@ pub struct Agent {
 
 impl Agent {
     /// Creates a new Agent.
-    pub async fn new(config: AgentConfig) -> Result<Self> {
+    pub async fn new(mut config: AgentConfig) -> Result<Self> {
         let mut mdns_name = config.multicast_dns_host_name.clone();
         if mdns_name.is_empty() {
             mdns_name = generate_multicast_dns_name();
@@ -157,6 +161,18 @@ impl Agent {
 
         config.init_with_defaults(&mut ai);
 
+        // The mappings relayed to the external program are recorded with the ones of
+        // gathering
+        #[cfg(feature = "relay")]
+        if let Some((rx, tx)) = config.external_channels.take() {
+            let observed_mappings = ai.relay_config.observed_mappings.clone().unwrap_or_default();
+            ai.external_comm = Some(agent_external::AgentExternal::spawn_with_mappings(
+                rx,
+                tx,
+                observed_mappings,
+            ));
+        }
+
         let candidate_types = if config.candidate_types.is_empty() {
             default_candidate_types()
         } else {
@@ -224,6 +240,8 @@ impl Agent {
             chan_candidate_rx,
             chan_candidate_pair_rx,
         );
+        #[cfg(feature = "relay")]
+        agent.internal.start_external_routine();
 
         // Restart is also used to initialize the agent for the first time
         if let Err(err) = agent.restart(config.local_ufrag, config.local_pwd).await {
@@ -243,6 +261,22 @@ impl Agent {
         self.internal.agent_conn.bytes_sent()
     }
 
+    /// Returns the outcomes of the STUN requests sent while gathering.
+    pub fn get_stun_metrics(&self) -> StunMetricsSnapshot {
+        self.internal.stun_metrics.snapshot()
+    }
+
+    /// Returns the address each STUN server last reported for us through the relay, by
+    /// server. Differing ports across servers indicate an address dependent mapping.
+    pub fn get_observed_mappings(&self) -> HashMap<SocketAddr, SocketAddr> {
+        self.internal
+            .relay_config
+            .observed_mappings
+            .as_ref()
+            .map(|observed_mappings| observed_mappings.snapshot())
+            .unwrap_or_default()
+    }
+
     /// Sets a handler that is fired when the connection state changes.
     pub fn on_connection_state_change(&self, f: OnConnectionStateChangeHdlrFn) {
         self.internal
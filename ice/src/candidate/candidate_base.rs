@@ -1,5 +1,4 @@
 use std::fmt;
-use std::net::Ipv4Addr;
 use std::ops::Add;
 use std::sync::atomic::{AtomicU16, AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
@@ -12,12 +11,14 @@ use tokio::sync::{broadcast, Mutex};
 use util::sync::Mutex as SyncMutex;
 
 use super::*;
-use crate::agent::agent_external::{serialize_send_info, SendInfo};
+use crate::agent::agent_config::RelayConfig;
 use crate::candidate::candidate_host::CandidateHostConfig;
 use crate::candidate::candidate_peer_reflexive::CandidatePeerReflexiveConfig;
 use crate::candidate::candidate_relay::CandidateRelayConfig;
 use crate::candidate::candidate_server_reflexive::CandidateServerReflexiveConfig;
 use crate::error::*;
+#[cfg(feature = "relay")]
+use crate::util::relayed_conn::RelayedConn;
 use crate::util::*;
 
 #[derive(Default)]
@@ -186,9 +187,11 @@ impl Candidate for CandidateBase {
         // candidates for a particular component for a particular data stream
         // that have the same type, the local preference MUST be unique for each
         // one.
-        (1 << 24) * u32::from(self.candidate_type().preference())
-            + (1 << 8) * u32::from(self.local_preference())
-            + (256 - u32::from(self.component()))
+        candidate_priority(
+            self.candidate_type(),
+            self.local_preference(),
+            self.component(),
+        )
     }
 
     /// Returns `Option<CandidateRelatedAddress>`.
@@ -270,30 +273,14 @@ impl Candidate for CandidateBase {
         }
     }
 
-    async fn write_to(&self, raw: &[u8], dst: &(dyn Candidate + Send + Sync), relay_port: u16) -> Result<usize> {
+    async fn write_to(
+        &self,
+        raw: &[u8],
+        dst: &(dyn Candidate + Send + Sync),
+        relay_config: &RelayConfig,
+    ) -> Result<usize> {
         let n = if let Some(conn) = &self.conn {
-            // info!("Found socket");
-            let mut addr = dst.addr();
-            // Sending all packets to the quichperf relay.
-            // Include a SendInfo re-purposed to signal quicheperf from which socket
-            // and to which socket to send the relayed packet
-            addr.set_ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
-            addr.set_port(relay_port);
-            let mut from = self.addr();
-            // In case we are using a STUN resolved addr, send the related addr info
-            // so the relay has info which socket to use
-            if let Some(related) = self.related_address() {
-                let ip : IpAddr = related.address.parse().unwrap();
-                let port = related.port;
-                from = SocketAddr::new(ip, port);
-            }
-            let send_info = SendInfo {
-                from: from,
-                to: dst.addr(),
-            };
-            let mut serialized = serialize_send_info(send_info).unwrap();
-            serialized.extend_from_slice(&raw);
-            conn.send_to(&serialized, addr).await?
+            self.send_to_dst(conn, raw, dst, relay_config).await?
         } else {
             // info!("Socket not found");
             0
@@ -319,7 +306,7 @@ impl Candidate for CandidateBase {
             .store(network_type as u8, Ordering::SeqCst);
 
         let addr = create_addr(network_type, *ip, self.port);
-        *self.resolved_addr.lock() = addr;
+        *self.resolved_addr.lock() = addr.addr;
 
         Ok(())
     }
@@ -334,6 +321,42 @@ impl Candidate for CandidateBase {
 }
 
 impl CandidateBase {
+    /// Sends `raw` to `dst` through the relay in turn of `relay_config`.
+    #[cfg(feature = "relay")]
+    async fn send_to_dst(
+        &self,
+        conn: &Arc<dyn util::Conn + Send + Sync>,
+        raw: &[u8],
+        dst: &(dyn Candidate + Send + Sync),
+        relay_config: &RelayConfig,
+    ) -> Result<usize> {
+        // Sending all packets to the quichperf relay.
+        // Include a SendInfo re-purposed to signal quicheperf from which socket
+        // and to which socket to send the relayed packet
+        let mut from = self.addr();
+        // In case we are using a STUN resolved addr, send the related addr info
+        // so the relay has info which socket to use
+        if let Some(related) = self.related_address() {
+            let ip : IpAddr = related.address.parse().unwrap();
+            let port = related.port;
+            from = SocketAddr::new(ip, port);
+        }
+        let relayed = RelayedConn::with_source_addr(Arc::clone(conn), relay_config, from);
+        Ok(util::Conn::send_to(&relayed, raw, dst.addr()).await?)
+    }
+
+    /// Sends `raw` straight to `dst`.
+    #[cfg(not(feature = "relay"))]
+    async fn send_to_dst(
+        &self,
+        conn: &Arc<dyn util::Conn + Send + Sync>,
+        raw: &[u8],
+        dst: &(dyn Candidate + Send + Sync),
+        _relay_config: &RelayConfig,
+    ) -> Result<usize> {
+        Ok(conn.send_to(raw, dst.addr()).await?)
+    }
+
     pub fn set_last_received(&self, d: Duration) {
         #[allow(clippy::cast_possible_truncation)]
         self.last_received
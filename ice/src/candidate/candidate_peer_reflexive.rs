@@ -37,7 +37,7 @@ impl CandidatePeerReflexiveConfig {
             candidate_type: CandidateType::PeerReflexive,
             address: self.base_config.address,
             port: self.base_config.port,
-            resolved_addr: SyncMutex::new(create_addr(network_type, ip, self.base_config.port)),
+            resolved_addr: SyncMutex::new(create_addr(network_type, ip, self.base_config.port).addr),
             component: AtomicU16::new(self.base_config.component),
             foundation_override: self.base_config.foundation,
             priority_override: self.base_config.priority,
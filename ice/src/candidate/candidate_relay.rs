@@ -39,7 +39,7 @@ impl CandidateRelayConfig {
             candidate_type: CandidateType::Relay,
             address: self.base_config.address,
             port: self.base_config.port,
-            resolved_addr: SyncMutex::new(create_addr(network_type, ip, self.base_config.port)),
+            resolved_addr: SyncMutex::new(create_addr(network_type, ip, self.base_config.port).addr),
             component: AtomicU16::new(self.base_config.component),
             foundation_override: self.base_config.foundation,
             priority_override: self.base_config.priority,
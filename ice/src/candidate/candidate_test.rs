@@ -1,6 +1,13 @@
 use std::time::UNIX_EPOCH;
 
 use super::*;
+#[cfg(feature = "relay")]
+use util::sync::Mutex as SyncMutex;
+
+#[cfg(feature = "relay")]
+use crate::agent::agent_external::{split_send_info, RelayFrameVersion, RELAY_FRAME_V2_MARKER};
+#[cfg(feature = "relay")]
+use crate::candidate::candidate_host::CandidateHostConfig;
 
 #[test]
 fn test_candidate_priority() -> Result<()> {
@@ -110,6 +117,78 @@ fn test_candidate_priority() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_candidate_priority_formula() {
+    // RFC 8445 recommended type preferences with a single local IP address
+    assert_eq!(
+        candidate_priority(CandidateType::Host, DEFAULT_LOCAL_PREFERENCE, COMPONENT_RTP),
+        2130706431
+    );
+    assert_eq!(
+        candidate_priority(
+            CandidateType::ServerReflexive,
+            DEFAULT_LOCAL_PREFERENCE,
+            COMPONENT_RTP
+        ),
+        1694498815
+    );
+    assert_eq!(
+        candidate_priority(
+            CandidateType::PeerReflexive,
+            DEFAULT_LOCAL_PREFERENCE,
+            COMPONENT_RTP
+        ),
+        1862270975
+    );
+    assert_eq!(
+        candidate_priority(
+            CandidateType::Relay,
+            DEFAULT_LOCAL_PREFERENCE,
+            COMPONENT_RTP
+        ),
+        16777215
+    );
+    assert_eq!(
+        candidate_priority(CandidateType::Host, DEFAULT_LOCAL_PREFERENCE, 2),
+        2130706430
+    );
+}
+
+#[test]
+fn test_interface_local_preference() {
+    assert_eq!(
+        interface_local_preference(NetworkType::Udp6, "eth0"),
+        DEFAULT_LOCAL_PREFERENCE
+    );
+    let ordered = [
+        interface_local_preference(NetworkType::Udp6, "eth0"),
+        interface_local_preference(NetworkType::Udp4, "enp3s0"),
+        interface_local_preference(NetworkType::Udp6, "wlan0"),
+        interface_local_preference(NetworkType::Udp4, "wlp2s0"),
+        interface_local_preference(NetworkType::Udp4, "lo0"),
+        interface_local_preference(NetworkType::Udp4, "tun0"),
+        interface_local_preference(NetworkType::Udp4, "docker0"),
+    ];
+    assert!(
+        ordered.windows(2).all(|pair| pair[0] >= pair[1]),
+        "{ordered:?}"
+    );
+    assert!(ordered[0] > ordered[1] && ordered[1] > ordered[2]);
+
+    // Wired beats wireless in the final priority as well
+    assert!(
+        candidate_priority(
+            CandidateType::Host,
+            interface_local_preference(NetworkType::Udp4, "eth0"),
+            COMPONENT_RTP
+        ) > candidate_priority(
+            CandidateType::Host,
+            interface_local_preference(NetworkType::Udp6, "wlan0"),
+            COMPONENT_RTP
+        )
+    );
+}
+
 #[test]
 fn test_candidate_last_sent() -> Result<()> {
     let candidate = CandidateBase::default();
@@ -233,6 +312,7 @@ fn test_candidate_foundation() -> Result<()> {
     Ok(())
 }
 
+#[cfg(feature = "serde")]
 #[test]
 fn test_candidate_pair_state_serialization() {
     let tests = vec![
@@ -266,6 +346,7 @@ fn test_candidate_pair_state_to_string() {
     }
 }
 
+#[cfg(feature = "serde")]
 #[test]
 fn test_candidate_type_serialization() {
     let tests = vec![
@@ -409,3 +490,151 @@ fn test_candidate_marshal() -> Result<()> {
 
     Ok(())
 }
+
+/// Records what is sent through it instead of sending it.
+#[cfg(feature = "relay")]
+#[derive(Default)]
+struct RecordingConn {
+    sent: SyncMutex<Vec<(Vec<u8>, SocketAddr)>>,
+}
+
+#[cfg(feature = "relay")]
+#[async_trait]
+impl util::Conn for RecordingConn {
+    async fn connect(&self, _addr: SocketAddr) -> std::result::Result<(), util::Error> {
+        Err(util::Error::Other("not applicable".to_owned()))
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> std::result::Result<usize, util::Error> {
+        Err(util::Error::Other("not applicable".to_owned()))
+    }
+
+    async fn recv_from(
+        &self,
+        _buf: &mut [u8],
+    ) -> std::result::Result<(usize, SocketAddr), util::Error> {
+        Err(util::Error::Other("not applicable".to_owned()))
+    }
+
+    async fn send(&self, _buf: &[u8]) -> std::result::Result<usize, util::Error> {
+        Err(util::Error::Other("not applicable".to_owned()))
+    }
+
+    async fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> std::result::Result<usize, util::Error> {
+        self.sent.lock().push((buf.to_vec(), target));
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> std::result::Result<SocketAddr, util::Error> {
+        Ok(SocketAddr::from(([192, 168, 1, 10], 5000)))
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    async fn close(&self) -> std::result::Result<(), util::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_candidate_write_to_goes_through_configured_relay() -> Result<()> {
+    let conn = Arc::new(RecordingConn::default());
+    let local = CandidateHostConfig {
+        base_config: CandidateBaseConfig {
+            network: "udp".to_owned(),
+            address: "192.168.1.10".to_owned(),
+            port: 5000,
+            component: COMPONENT_RTP,
+            conn: Some(conn.clone()),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+    .new_candidate_host()?;
+    let remote = CandidateHostConfig {
+        base_config: CandidateBaseConfig {
+            network: "udp".to_owned(),
+            address: "192.168.1.20".to_owned(),
+            port: 6000,
+            component: COMPONENT_RTP,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+    .new_candidate_host()?;
+
+    let relay_addr = SocketAddr::from(([10, 1, 2, 3], 23456));
+    let relay_config = RelayConfig {
+        relay_addr,
+        ..Default::default()
+    };
+    let n = local.write_to(b"ping", &remote, &relay_config).await?;
+
+    let sent = conn.sent.lock().clone();
+    assert_eq!(sent.len(), 1);
+    let (frame, target) = &sent[0];
+    // Like any conn, the length of what was given is returned rather than of the frame
+    assert_eq!(n, 4);
+    assert_eq!(*target, relay_addr);
+    let (send_info, payload) = split_send_info(frame).expect("a SendInfo frame");
+    assert_eq!(send_info.from, local.addr());
+    assert_eq!(send_info.to, remote.addr());
+    assert_eq!(payload, b"ping");
+
+    // Application data goes to the configured relay unless data ports are set, then to
+    // the data port of the role on the same relay.
+    local
+        .write_to(b"data", &remote, &relay_config.for_data(false))
+        .await?;
+    assert_eq!(conn.sent.lock()[1].1, relay_addr);
+    let with_data_ports = RelayConfig {
+        data_ports: Some((23460, 23461)),
+        ..relay_config.clone()
+    };
+    local
+        .write_to(b"data", &remote, &with_data_ports.for_data(false))
+        .await?;
+    assert_eq!(
+        conn.sent.lock()[2].1,
+        SocketAddr::new(relay_addr.ip(), 23461)
+    );
+
+    // The advertised address is announced in place of the candidate's socket.
+    let advertised = SocketAddr::from(([203, 0, 113, 7], 7000));
+    let advertising = RelayConfig {
+        advertised_local_addr: Some(advertised),
+        ..relay_config.clone()
+    };
+    local.write_to(b"ping", &remote, &advertising).await?;
+    let (send_info, _) = split_send_info(&conn.sent.lock()[3].0).expect("a SendInfo frame");
+    assert_eq!(send_info.from, advertised);
+
+    // Connectivity checks are framed in the configured version.
+    let v2 = RelayConfig {
+        frame_version: RelayFrameVersion::V2,
+        ..relay_config.clone()
+    };
+    local.write_to(b"ping", &remote, &v2).await?;
+    let frame = conn.sent.lock()[4].0.clone();
+    assert_eq!(frame[1], RELAY_FRAME_V2_MARKER);
+    let (send_info, payload) = split_send_info(&frame).expect("a V2 SendInfo frame");
+    assert_eq!(send_info.to, remote.addr());
+    assert_eq!(payload, b"ping");
+
+    // Payloads the relay cannot forward are not sent at all.
+    let small_mtu = RelayConfig {
+        mtu: 3,
+        ..relay_config
+    };
+    assert!(local.write_to(b"ping", &remote, &small_mtu).await.is_err());
+    assert_eq!(conn.sent.lock().len(), 5);
+
+    Ok(())
+}
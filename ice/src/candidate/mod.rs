@@ -21,9 +21,11 @@ use std::time::SystemTime;
 
 use async_trait::async_trait;
 use candidate_base::*;
+#[cfg(feature = "serde")]
 use serde::Serialize;
 use tokio::sync::{broadcast, Mutex};
 
+use crate::agent::agent_config::RelayConfig;
 use crate::error::Result;
 use crate::network_type::*;
 use crate::tcp_type::*;
@@ -80,7 +82,14 @@ pub trait Candidate: fmt::Display {
     async fn close(&self) -> Result<()>;
     fn seen(&self, outbound: bool);
 
-    async fn write_to(&self, raw: &[u8], dst: &(dyn Candidate + Send + Sync), relay_port:  u16) -> Result<usize>;
+    /// Sends `raw` to `dst`, through the relay described by `relay_config` when the
+    /// `relay` feature is enabled.
+    async fn write_to(
+        &self,
+        raw: &[u8],
+        dst: &(dyn Candidate + Send + Sync),
+        relay_config: &RelayConfig,
+    ) -> Result<usize>;
     fn equal(&self, other: &dyn Candidate) -> bool;
     fn set_ip(&self, ip: &IpAddr) -> Result<()>;
     fn get_conn(&self) -> Option<&Arc<dyn util::Conn + Send + Sync>>;
@@ -88,17 +97,18 @@ pub trait Candidate: fmt::Display {
 }
 
 /// Represents the type of candidate `CandidateType` enum.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum CandidateType {
-    #[serde(rename = "unspecified")]
+    #[cfg_attr(feature = "serde", serde(rename = "unspecified"))]
     Unspecified,
-    #[serde(rename = "host")]
+    #[cfg_attr(feature = "serde", serde(rename = "host"))]
     Host,
-    #[serde(rename = "srflx")]
+    #[cfg_attr(feature = "serde", serde(rename = "srflx"))]
     ServerReflexive,
-    #[serde(rename = "prflx")]
+    #[cfg_attr(feature = "serde", serde(rename = "prflx"))]
     PeerReflexive,
-    #[serde(rename = "relay")]
+    #[cfg_attr(feature = "serde", serde(rename = "relay"))]
     Relay,
 }
 
@@ -140,6 +150,41 @@ impl CandidateType {
     }
 }
 
+/// Computes the priority of a candidate with the RFC 8445 5.1.2.1 formula:
+///
+/// priority = (2^24)*(type preference) + (2^8)*(local preference) + (2^0)*(256 - component ID)
+#[must_use]
+pub fn candidate_priority(candidate_type: CandidateType, local_pref: u16, component: u16) -> u32 {
+    (1 << 24) * u32::from(candidate_type.preference())
+        + (1 << 8) * u32::from(local_pref)
+        + (256 - u32::from(component))
+}
+
+/// Derives the local preference of a UDP host candidate from the kind of interface it
+/// was gathered on, so that wired interfaces are preferred over wireless ones and
+/// both over tunnels and virtual bridges. IPv6 is preferred over IPv4 on the same
+/// kind of interface (RFC 8421). A wired IPv6 candidate gets `DEFAULT_LOCAL_PREFERENCE`.
+///
+/// The preferences stay unique per interface kind and family only, multi-homed hosts
+/// with several interfaces of one kind need to tell them apart themselves.
+#[must_use]
+pub fn interface_local_preference(network_type: NetworkType, interface_name: &str) -> u16 {
+    const VIRTUAL: [&str; 9] = [
+        "tun", "tap", "wg", "ppp", "utun", "docker", "veth", "br-", "virbr",
+    ];
+    let kind_pref: u16 = if VIRTUAL.iter().any(|p| interface_name.starts_with(p)) {
+        3
+    } else if interface_name.starts_with("wl") {
+        6
+    } else if interface_name.starts_with("eth") || interface_name.starts_with("en") {
+        7
+    } else {
+        5
+    };
+    let family_pref: u16 = if network_type.is_ipv6() { 1 } else { 0 };
+    (kind_pref << 13) + (family_pref << 12) + 0xfff
+}
+
 pub(crate) fn contains_candidate_type(
     candidate_type: CandidateType,
     candidate_type_list: &[CandidateType],
@@ -170,26 +215,27 @@ impl fmt::Display for CandidateRelatedAddress {
 }
 
 /// Represent the ICE candidate pair state.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum CandidatePairState {
-    #[serde(rename = "unspecified")]
+    #[cfg_attr(feature = "serde", serde(rename = "unspecified"))]
     Unspecified = 0,
 
     /// Means a check has not been performed for this pair.
-    #[serde(rename = "waiting")]
+    #[cfg_attr(feature = "serde", serde(rename = "waiting"))]
     Waiting = 1,
 
     /// Means a check has been sent for this pair, but the transaction is in progress.
-    #[serde(rename = "in-progress")]
+    #[cfg_attr(feature = "serde", serde(rename = "in-progress"))]
     InProgress = 2,
 
     /// Means a check for this pair was already done and failed, either never producing any response
     /// or producing an unrecoverable failure response.
-    #[serde(rename = "failed")]
+    #[cfg_attr(feature = "serde", serde(rename = "failed"))]
     Failed = 3,
 
     /// Means a check for this pair was already done and produced a successful result.
-    #[serde(rename = "succeeded")]
+    #[cfg_attr(feature = "serde", serde(rename = "succeeded"))]
     Succeeded = 4,
 }
 
@@ -318,8 +364,11 @@ impl CandidatePair {
             + u64::from(g > d)
     }
 
-    pub async fn write(&self, b: &[u8]) -> Result<usize> {
-        let port = if self.ice_role_controlling.load(Ordering::SeqCst) { 12345 } else { 12346 };
-        self.local.write_to(b, &*self.remote, port).await
+    /// Sends application data `b` to the remote candidate through the data port of
+    /// `relay_config` matching the role of the pair.
+    pub async fn write(&self, b: &[u8], relay_config: &RelayConfig) -> Result<usize> {
+        let controlling = self.ice_role_controlling.load(Ordering::SeqCst);
+        let relay_config = relay_config.for_data(controlling);
+        self.local.write_to(b, &*self.remote, &relay_config).await
     }
 }
@@ -1,3 +1,4 @@
+use std::net::SocketAddr;
 use std::num::ParseIntError;
 use std::time::SystemTimeError;
 use std::{io, net};
@@ -192,6 +193,72 @@ pub enum Error {
     ErrUrlParse,
     #[error("Candidate IP could not be found")]
     ErrCandidateIpNotFound,
+    #[error("relay send info is truncated")]
+    ErrTruncatedSendInfo,
+    #[error("relay frame is shorter than its header announces")]
+    ErrTruncatedRelayFrame,
+    #[error("unknown address family in relay send info")]
+    ErrUnknownAddressFamily,
+    #[error("unknown relay packet type")]
+    ErrUnknownRelayPacketType,
+    #[error("STUN response transaction ID does not match the request")]
+    ErrTransactionMismatch,
+    #[error("no free port in range [{0}, {1}]")]
+    ErrPortRangeExhausted(u16, u16),
+    #[error("port {0} is already reserved")]
+    ErrPortReserved(u16),
+    #[error("no port range given and ephemeral ports are forbidden")]
+    ErrEphemeralPortForbidden,
+    #[error("all STUN servers failed: {0:?}")]
+    ErrStunServersFailed(Vec<(SocketAddr, Error)>),
+    #[error("STUN response for {0} was sent by {1}")]
+    ErrStunServerMismatch(SocketAddr, SocketAddr),
+    #[error("relay delivered the STUN response for {0} from {1}")]
+    ErrRelayMisroute(SocketAddr, SocketAddr),
+    #[error("relay delivered a frame of session {1} to session {0}")]
+    ErrRelaySessionMismatch(u16, u16),
+    #[error("relay sent our own SendInfo frame back, check that it is not looping traffic")]
+    ErrRelayLoopback,
+    #[error("{0} cannot be used as a STUN server")]
+    ErrInvalidStunServer(SocketAddr),
+    #[error("no STUN response within the deadline")]
+    ErrStunTimeout,
+    #[error("STUN response is shorter than a STUN header")]
+    ErrShortStunResponse,
+    #[error("expected a STUN binding success response")]
+    ErrUnexpectedStunMessage,
+    #[error("STUN server rejected the request: {code} {reason}")]
+    ErrStunErrorResponse { code: u16, reason: String },
+    #[error("failed to receive STUN response: {0}")]
+    ErrStunRecv(util::Error),
+    #[error("failed to decode STUN response: {0}")]
+    ErrStunDecode(Box<Error>),
+    #[error("connection has no local address")]
+    ErrNoLocalAddr,
+    #[error("relay send info needs a port and a specified address")]
+    ErrInvalidSendInfo,
+    #[error("relay did not acknowledge the socket")]
+    ErrRelayHandshakeFailed,
+    #[error("relay is not available")]
+    ErrRelayUnavailable,
+    #[error("STUN request canceled")]
+    ErrCanceled,
+    #[error("mapped address family differs from the STUN server's")]
+    ErrAddressFamilyMismatch,
+    #[error("external message queue is full")]
+    ErrExternalQueueFull,
+    #[error("external message queue is closed")]
+    ErrExternalClosed,
+    #[error("relay send info exceeds 255 bytes")]
+    ErrSendInfoTooLarge,
+    #[error("more send infos than fit one batch")]
+    ErrSendInfoBatchTooLarge,
+    #[error("relayed STUN message does not fit into a StunResponse")]
+    ErrStunDataTooLarge,
+    #[error("relayed payload exceeds the relay MTU")]
+    ErrRelayPayloadTooLarge,
+    #[error("no local candidate at {0}")]
+    ErrUnknownLocalCandidate(SocketAddr),
 
     #[error("parse int: {0}")]
     ParseInt(#[from] ParseIntError),
@@ -4,6 +4,7 @@ mod network_type_test;
 use std::fmt;
 use std::net::IpAddr;
 
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
 use crate::error::*;
@@ -22,25 +23,26 @@ pub fn supported_network_types() -> Vec<NetworkType> {
 }
 
 /// Represents the type of network.
-#[derive(PartialEq, Debug, Copy, Clone, Eq, Hash, Serialize, Deserialize)]
+#[derive(PartialEq, Debug, Copy, Clone, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum NetworkType {
-    #[serde(rename = "unspecified")]
+    #[cfg_attr(feature = "serde", serde(rename = "unspecified"))]
     Unspecified,
 
     /// Indicates UDP over IPv4.
-    #[serde(rename = "udp4")]
+    #[cfg_attr(feature = "serde", serde(rename = "udp4"))]
     Udp4,
 
     /// Indicates UDP over IPv6.
-    #[serde(rename = "udp6")]
+    #[cfg_attr(feature = "serde", serde(rename = "udp6"))]
     Udp6,
 
     /// Indicates TCP over IPv4.
-    #[serde(rename = "tcp4")]
+    #[cfg_attr(feature = "serde", serde(rename = "tcp4"))]
     Tcp4,
 
     /// Indicates TCP over IPv6.
-    #[serde(rename = "tcp6")]
+    #[cfg_attr(feature = "serde", serde(rename = "tcp6"))]
     Tcp6,
 }
 
@@ -61,6 +61,7 @@ fn test_network_type_is_tcp() -> Result<()> {
     Ok(())
 }
 
+#[cfg(feature = "serde")]
 #[test]
 fn test_network_type_serialization() {
     let tests = vec![
@@ -0,0 +1,131 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+use util::vnet::interface::Interface;
+use util::vnet::net::Net;
+
+use super::filter_interface_addrs;
+use crate::agent::agent_config::{InterfaceFilterFn, IpFilterFn};
+use crate::network_type::NetworkType;
+
+/// How often `InterfaceWatcher` lists the interfaces by default.
+pub const DEFAULT_INTERFACE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A change of the local addresses seen by `InterfaceWatcher`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum InterfaceEvent {
+    Added(IpAddr),
+    Removed(IpAddr),
+}
+
+/// Lists the local interfaces for `InterfaceWatcher`, implemented by `Net`.
+#[async_trait]
+pub trait InterfaceSource {
+    async fn get_interfaces(&self) -> Vec<Interface>;
+}
+
+#[async_trait]
+impl InterfaceSource for Net {
+    async fn get_interfaces(&self) -> Vec<Interface> {
+        Net::get_interfaces(self).await
+    }
+}
+
+/// Polls the local interfaces and reports addresses that appeared or disappeared
+/// since the previous poll, e.g. when a VPN comes up, so candidates can be gathered
+/// again. Addresses are filtered the same way `local_interfaces` filters them.
+pub struct InterfaceWatcher {
+    source: Arc<dyn InterfaceSource + Send + Sync>,
+    interface_filter: Arc<Option<InterfaceFilterFn>>,
+    ip_filter: Arc<Option<IpFilterFn>>,
+    network_types: Vec<NetworkType>,
+    include_link_local: bool,
+    poll_interval: Duration,
+}
+
+impl InterfaceWatcher {
+    pub fn new(
+        source: Arc<dyn InterfaceSource + Send + Sync>,
+        interface_filter: Arc<Option<InterfaceFilterFn>>,
+        ip_filter: Arc<Option<IpFilterFn>>,
+        network_types: Vec<NetworkType>,
+    ) -> Self {
+        InterfaceWatcher {
+            source,
+            interface_filter,
+            ip_filter,
+            network_types,
+            include_link_local: false,
+            poll_interval: DEFAULT_INTERFACE_POLL_INTERVAL,
+        }
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn with_link_local(mut self, include_link_local: bool) -> Self {
+        self.include_link_local = include_link_local;
+        self
+    }
+
+    /// Returns the addresses currently passing the filters.
+    pub async fn poll(&self) -> HashSet<IpAddr> {
+        filter_interface_addrs(
+            &self.source.get_interfaces().await,
+            &self.interface_filter,
+            &self.ip_filter,
+            &self.network_types,
+            self.include_link_local,
+        )
+        .into_iter()
+        .map(|(_, ip)| ip)
+        .collect()
+    }
+
+    /// Starts polling in a task of its own. The addresses found by the first poll
+    /// are the baseline and not reported. The task ends once the returned receiver
+    /// is dropped.
+    pub fn spawn(self) -> (mpsc::Receiver<InterfaceEvent>, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(16);
+        let handle = tokio::spawn(async move {
+            let mut known = self.poll().await;
+            let mut ticker = tokio::time::interval(self.poll_interval);
+            // The first tick completes immediately
+            ticker.tick().await;
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = tx.closed() => return,
+                }
+                let current = self.poll().await;
+                for event in diff_interfaces(&known, &current) {
+                    if tx.send(event).await.is_err() {
+                        return;
+                    }
+                }
+                known = current;
+            }
+        });
+        (rx, handle)
+    }
+}
+
+/// Lists the removed addresses followed by the added ones, each in ascending order.
+pub(crate) fn diff_interfaces(old: &HashSet<IpAddr>, new: &HashSet<IpAddr>) -> Vec<InterfaceEvent> {
+    let mut removed: Vec<IpAddr> = old.difference(new).copied().collect();
+    let mut added: Vec<IpAddr> = new.difference(old).copied().collect();
+    removed.sort();
+    added.sort();
+    removed
+        .into_iter()
+        .map(InterfaceEvent::Removed)
+        .chain(added.into_iter().map(InterfaceEvent::Added))
+        .collect()
+}
@@ -0,0 +1,137 @@
+use std::str::FromStr;
+
+use ipnet::IpNet;
+use tokio::sync::{mpsc, Mutex};
+
+use super::interface_watcher::*;
+use super::*;
+
+/// An `InterfaceSource` whose interfaces the test replaces between polls.
+struct MockNet {
+    interfaces: Mutex<Vec<Interface>>,
+}
+
+impl MockNet {
+    fn new(addrs: &[&str]) -> Arc<Self> {
+        Arc::new(MockNet {
+            interfaces: Mutex::new(vec![interface("eth0", addrs)]),
+        })
+    }
+
+    async fn set(&self, interfaces: Vec<Interface>) {
+        *self.interfaces.lock().await = interfaces;
+    }
+}
+
+#[async_trait::async_trait]
+impl InterfaceSource for MockNet {
+    async fn get_interfaces(&self) -> Vec<Interface> {
+        self.interfaces.lock().await.clone()
+    }
+}
+
+fn interface(name: &str, addrs: &[&str]) -> Interface {
+    Interface::new(
+        name.to_owned(),
+        addrs
+            .iter()
+            .map(|addr| IpNet::from_str(addr).unwrap())
+            .collect(),
+    )
+}
+
+fn ip(addr: &str) -> IpAddr {
+    IpAddr::from_str(addr).unwrap()
+}
+
+async fn next_event(rx: &mut mpsc::Receiver<InterfaceEvent>) -> InterfaceEvent {
+    tokio::time::timeout(Duration::from_secs(1), rx.recv())
+        .await
+        .expect("no interface event")
+        .expect("watcher stopped")
+}
+
+#[tokio::test]
+async fn test_interface_watcher_reports_changes() -> Result<()> {
+    let net = MockNet::new(&["10.0.0.1/24", "127.0.0.1/8"]);
+    let watcher = InterfaceWatcher::new(
+        Arc::clone(&net) as _,
+        Arc::new(None),
+        Arc::new(None),
+        vec![NetworkType::Udp4, NetworkType::Udp6],
+    )
+    .with_poll_interval(Duration::from_millis(10));
+    let (mut rx, handle) = watcher.spawn();
+
+    // A VPN comes up next to the existing interface
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    net.set(vec![
+        interface("eth0", &["10.0.0.1/24", "127.0.0.1/8"]),
+        interface("tun0", &["172.16.0.2/16", "2001:db8::2/64"]),
+    ])
+    .await;
+    assert_eq!(
+        vec![next_event(&mut rx).await, next_event(&mut rx).await],
+        vec![
+            InterfaceEvent::Added(ip("172.16.0.2")),
+            InterfaceEvent::Added(ip("2001:db8::2")),
+        ]
+    );
+
+    // The Wi-Fi address changes and the VPN goes away
+    net.set(vec![interface("eth0", &["10.0.0.7/24", "127.0.0.1/8"])])
+        .await;
+    let mut events = vec![];
+    for _ in 0..4 {
+        events.push(next_event(&mut rx).await);
+    }
+    assert_eq!(
+        events,
+        vec![
+            InterfaceEvent::Removed(ip("10.0.0.1")),
+            InterfaceEvent::Removed(ip("172.16.0.2")),
+            InterfaceEvent::Removed(ip("2001:db8::2")),
+            InterfaceEvent::Added(ip("10.0.0.7")),
+        ]
+    );
+
+    drop(rx);
+    tokio::time::timeout(Duration::from_secs(1), handle)
+        .await
+        .expect("watcher did not stop")
+        .unwrap();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_interface_watcher_applies_filters() -> Result<()> {
+    let net = MockNet::new(&["10.0.0.1/24"]);
+    let watcher = InterfaceWatcher::new(
+        Arc::clone(&net) as _,
+        Arc::new(Some(Box::new(|name: &str| name != "docker0"))),
+        Arc::new(Some(Box::new(|ip: IpAddr| {
+            ip != IpAddr::from_str("10.0.0.9").unwrap()
+        }))),
+        vec![NetworkType::Udp4],
+    )
+    .with_poll_interval(Duration::from_millis(10));
+    assert_eq!(watcher.poll().await, HashSet::from([ip("10.0.0.1")]));
+    let (mut rx, _handle) = watcher.spawn();
+
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    net.set(vec![
+        interface("eth0", &["10.0.0.1/24", "10.0.0.9/24", "2001:db8::1/64"]),
+        interface("docker0", &["172.17.0.1/16"]),
+        interface("wlan0", &["192.168.1.5/24"]),
+    ])
+    .await;
+    assert_eq!(
+        next_event(&mut rx).await,
+        InterfaceEvent::Added(ip("192.168.1.5"))
+    );
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(rx.try_recv().is_err());
+
+    Ok(())
+}
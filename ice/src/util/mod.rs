@@ -1,34 +1,106 @@
 #[cfg(test)]
+mod interface_watcher_test;
+#[cfg(test)]
+mod port_pool_test;
+#[cfg(all(test, feature = "relay"))]
+mod relay_codec_test;
+#[cfg(all(test, feature = "relay"))]
+pub(crate) mod relay_server_test;
+#[cfg(test)]
+mod relay_transport_test;
+#[cfg(all(test, feature = "relay"))]
+mod relayed_conn_test;
+#[cfg(test)]
+mod stun_request_test;
+#[cfg(test)]
+pub(crate) mod test_support;
+#[cfg(test)]
+mod tracked_conn_test;
+#[cfg(test)]
 mod util_test;
 
-use std::collections::HashSet;
+pub mod interface_watcher;
+pub mod port_pool;
+#[cfg(feature = "relay")]
+pub mod relay_codec;
+pub mod relay_transport;
+#[cfg(feature = "relay")]
+pub mod relayed_conn;
+pub mod tracked_conn;
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
-use std::ops::Add;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
-use log::{debug, info};
+use log::{debug, info, warn};
+use stun::addr::*;
 use stun::agent::*;
 use stun::attributes::*;
+use stun::error_code::*;
+use stun::fingerprint::*;
 use stun::integrity::*;
 use stun::message::*;
 use stun::textattrs::*;
 use stun::xoraddr::*;
-use tokio::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use util::vnet::interface::Interface;
 use util::vnet::net::*;
 use util::Conn;
 
-use crate::agent::agent_config::{InterfaceFilterFn, IpFilterFn};
-use crate::agent::agent_external::{parse_recv_info, parse_send_info, serialize_send_info, SendInfo};
+#[cfg(feature = "relay")]
+use crate::agent::agent_config::RelayMode;
+use crate::agent::agent_config::{InterfaceFilterFn, IpFilterFn, RelayConfig};
+use crate::candidate::candidate_base::{CandidateBase, CandidateBaseConfig};
+use crate::candidate::candidate_server_reflexive::CandidateServerReflexiveConfig;
+use crate::candidate::COMPONENT_RTP;
+#[cfg(feature = "relay")]
+use relay_codec::{
+    decode_relay_frame, encode_relay_frame, strip_session_ids, trace_relay_frame, RelayFrame,
+    RelayFrameReader,
+};
+
+#[cfg(feature = "relay")]
+use crate::agent::agent_external::{
+    encode_open_socket, insert_session_id, RelayPacketType, MAX_RELAY_HEADER_LEN,
+};
 use crate::error::*;
 use crate::network_type::*;
+use port_pool::{PooledConn, PortPool};
+use relay_transport::{is_read_timeout, RelayTransport};
+use tracked_conn::TrackedConn;
+
+/// A candidate address together with the network it is reached over, so TCP and UDP
+/// candidates on the same IP and port stay distinguishable.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CandidateAddr {
+    pub addr: SocketAddr,
+    pub network: NetworkType,
+}
+
+impl CandidateAddr {
+    /// Returns whether a connection for this address must be a TCP one.
+    pub fn is_tcp(&self) -> bool {
+        self.network.is_tcp()
+    }
+}
+
+impl From<CandidateAddr> for SocketAddr {
+    fn from(candidate_addr: CandidateAddr) -> Self {
+        candidate_addr.addr
+    }
+}
 
-pub fn create_addr(_network: NetworkType, ip: IpAddr, port: u16) -> SocketAddr {
-    /*if network.is_tcp(){
-        return &net.TCPAddr{IP: ip, Port: port}
-    default:
-        return &net.UDPAddr{IP: ip, Port: port}
-    }*/
-    SocketAddr::new(ip, port)
+pub fn create_addr(network: NetworkType, ip: IpAddr, port: u16) -> CandidateAddr {
+    CandidateAddr {
+        addr: SocketAddr::new(ip, port),
+        network,
+    }
 }
 
 pub fn assert_inbound_username(m: &Message, expected_username: &str) -> Result<()> {
@@ -52,23 +124,659 @@ pub fn assert_inbound_message_integrity(m: &mut Message, key: &[u8]) -> Result<(
     Ok(message_integrity_attr.check(m)?)
 }
 
+/// Short-term credentials (RFC 5389 10.1.1) for STUN servers that require them. The
+/// password doubles as the MESSAGE-INTEGRITY key in both directions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn key(&self) -> &[u8] {
+        self.password.as_bytes()
+    }
+}
+
+/// A STUN response as returned by `stun_request`.
+pub struct StunResponse {
+    pub message: Message,
+    /// The local address the relay delivered the response to, unspecified if the
+    /// server answered directly.
+    pub local_addr: SocketAddr,
+    /// The STUN server that answered, as reported by the relay.
+    pub from: SocketAddr,
+    /// The round trip time through the relay, measured from the last transmission
+    /// of the request.
+    pub rtt: Duration,
+    /// Whether the response arrived in a relay frame. An unframed response while
+    /// relaying is on means the relay is not wrapping traffic as expected.
+    pub was_relayed: bool,
+    /// The transaction ID of the request, generated unless the caller passed one.
+    pub transaction_id: TransactionId,
+}
+
+/// A response frame decoded by `decode_stun_response`, before it is checked further.
+struct DecodedStunResponse {
+    message: Message,
+    local_addr: SocketAddr,
+    from: SocketAddr,
+    was_relayed: bool,
+}
+
+/// How many buffers a `BufferPool` keeps by default.
+pub const DEFAULT_BUFFER_POOL_CAPACITY: usize = 16;
+
+/// Recycles the receive buffers of `stun_request`, to be shared by all requests of an
+/// agent through `RelayConfig::buffer_pool`. Clones of a `RelayConfig` share it.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: std::sync::Mutex<Vec<Vec<u8>>>,
+    capacity: usize,
+}
+
+impl BufferPool {
+    /// Creates a pool keeping at most `capacity` buffers, further ones are dropped.
+    pub fn new(capacity: usize) -> Self {
+        BufferPool {
+            buffers: std::sync::Mutex::new(Vec::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Takes a zeroed buffer of `len` bytes, allocating one if the pool is empty.
+    pub fn take(&self, len: usize) -> Vec<u8> {
+        let mut buf = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        buf.clear();
+        buf.resize(len, 0);
+        buf
+    }
+
+    /// Returns `buf` to the pool for the next `take`.
+    pub fn give_back(&self, buf: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.capacity {
+            buffers.push(buf);
+        }
+    }
+
+    /// The number of buffers waiting to be taken.
+    pub fn len(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        BufferPool::new(DEFAULT_BUFFER_POOL_CAPACITY)
+    }
+}
+
+/// The pool holds runtime state rather than configuration, any two compare equal.
+impl PartialEq for BufferPool {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for BufferPool {}
+
+/// A buffer taken from a `BufferPool`, or allocated without one, that goes back to
+/// its pool when dropped.
+struct PooledBuffer<'a> {
+    buf: Vec<u8>,
+    pool: Option<&'a BufferPool>,
+}
+
+impl<'a> PooledBuffer<'a> {
+    fn new(len: usize, pool: Option<&'a BufferPool>) -> Self {
+        let buf = match pool {
+            Some(pool) => pool.take(len),
+            None => vec![0_u8; len],
+        };
+        PooledBuffer { buf, pool }
+    }
+}
+
+impl std::ops::Deref for PooledBuffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl std::ops::DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool {
+            pool.give_back(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+/// Counts the outcomes of `stun_request`, to be shared by all requests of an agent.
+#[derive(Debug, Default)]
+pub struct StunMetrics {
+    requests_sent: AtomicU64,
+    responses_ok: AtomicU64,
+    timeouts: AtomicU64,
+    parse_errors: AtomicU64,
+}
+
+/// A point in time copy of the `StunMetrics` counters.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct StunMetricsSnapshot {
+    /// STUN requests started, retransmissions are not counted separately.
+    pub requests_sent: u64,
+    /// Requests answered by a response matching their transaction.
+    pub responses_ok: u64,
+    /// Requests that got no response before the deadline or the last retransmission.
+    pub timeouts: u64,
+    /// Requests aborted by a response that could not be decoded or failed the
+    /// integrity check.
+    pub parse_errors: u64,
+}
+
+impl StunMetrics {
+    pub fn snapshot(&self) -> StunMetricsSnapshot {
+        StunMetricsSnapshot {
+            requests_sent: self.requests_sent.load(Ordering::Relaxed),
+            responses_ok: self.responses_ok.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            parse_errors: self.parse_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts how the datagrams `stun_request` receives are classified, to be shared by all
+/// requests of an agent through `RelayConfig::relay_metrics`. A relay that is
+/// misconfigured shows up as raw STUN where relayed frames are expected, or as unknown
+/// packet types.
+#[derive(Debug, Default)]
+pub struct RelayMetrics {
+    relayed: AtomicU64,
+    raw_stun: AtomicU64,
+    unknown_type: AtomicU64,
+    too_short: AtomicU64,
+}
+
+/// A point in time copy of the `RelayMetrics` counters.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct RelayMetricsSnapshot {
+    /// `RecvInfo` frames the relay delivered on behalf of our sockets.
+    pub relayed: u64,
+    /// STUN messages that came straight from a server, without relay framing.
+    pub raw_stun: u64,
+    /// Frames that are neither a relay frame nor a STUN message.
+    pub unknown_type: u64,
+    /// Datagrams too short to hold a STUN header.
+    pub too_short: u64,
+}
+
+impl RelayMetrics {
+    pub fn snapshot(&self) -> RelayMetricsSnapshot {
+        RelayMetricsSnapshot {
+            relayed: self.relayed.load(Ordering::Relaxed),
+            raw_stun: self.raw_stun.load(Ordering::Relaxed),
+            unknown_type: self.unknown_type.load(Ordering::Relaxed),
+            too_short: self.too_short.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The metrics hold runtime state rather than configuration, any two compare equal.
+impl PartialEq for RelayMetrics {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for RelayMetrics {}
+
+/// Records the address each STUN server last saw us at, to be shared by all requests of
+/// an agent through `RelayConfig::observed_mappings`. Differing ports across servers
+/// indicate an address dependent mapping.
+#[derive(Debug, Default)]
+pub struct ObservedMappings {
+    mappings: util::sync::Mutex<HashMap<SocketAddr, SocketAddr>>,
+}
+
+impl ObservedMappings {
+    /// Records that `server` reported `mapped` as our address.
+    pub fn record(&self, server: SocketAddr, mapped: SocketAddr) {
+        self.mappings.lock().insert(server, mapped);
+    }
+
+    /// Returns the mapped address each server last reported, by server.
+    pub fn snapshot(&self) -> HashMap<SocketAddr, SocketAddr> {
+        self.mappings.lock().clone()
+    }
+}
+
+/// The mappings hold runtime state rather than configuration, any two compare equal.
+impl PartialEq for ObservedMappings {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for ObservedMappings {}
+
+/// Outcome of a binding request. Besides the mapping, RFC 5780 servers report the address
+/// they answered from in RESPONSE-ORIGIN and an alternate address in OTHER-ADDRESS, both
+/// are needed to classify the NAT in front of us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BindingResult {
+    /// The address the server saw our request come from.
+    pub mapped: SocketAddr,
+    /// The local address the response was relayed to.
+    pub local_addr: SocketAddr,
+    pub rtt: Duration,
+    pub response_origin: Option<SocketAddr>,
+    pub other_address: Option<SocketAddr>,
+}
+
 /// Initiates a stun requests to `server_addr` using conn, reads the response and returns the
-/// `XORMappedAddress` returned by the stun server.
+/// `XORMappedAddress` returned by the stun server, the local address the response was
+/// relayed to and the round trip time. With `credentials` the request is authenticated
+/// and the response must carry a valid MESSAGE-INTEGRITY.
 /// Adapted from stun v0.2.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "get_xormapped_addr", skip_all, fields(%server_addr))
+)]
 pub async fn get_xormapped_addr(
     conn: &Arc<dyn Conn + Send + Sync>,
     server_addr: SocketAddr,
     deadline: Duration,
-    relay_port: u16,
-) -> Result<(XorMappedAddress, SocketAddr)> {
-    let resp = stun_request(conn, server_addr, deadline, relay_port).await?;
+    relay_config: &RelayConfig,
+    metrics: Option<&StunMetrics>,
+    credentials: Option<&Credentials>,
+    cancel: Option<&CancellationToken>,
+) -> Result<(XorMappedAddress, SocketAddr, Duration)> {
+    let res = get_binding_result(
+        conn,
+        server_addr,
+        deadline,
+        relay_config,
+        metrics,
+        credentials,
+        cancel,
+    )
+    .await?;
+    let addr = XorMappedAddress {
+        ip: res.mapped.ip(),
+        port: res.mapped.port(),
+    };
+    Ok((addr, res.local_addr, res.rtt))
+}
+
+/// Like `get_xormapped_addr`, but also returns the RESPONSE-ORIGIN and OTHER-ADDRESS
+/// attributes when the server sends them.
+pub async fn get_binding_result(
+    conn: &Arc<dyn Conn + Send + Sync>,
+    server_addr: SocketAddr,
+    deadline: Duration,
+    relay_config: &RelayConfig,
+    metrics: Option<&StunMetrics>,
+    credentials: Option<&Credentials>,
+    cancel: Option<&CancellationToken>,
+) -> Result<BindingResult> {
+    let resp = stun_request(
+        conn,
+        server_addr,
+        deadline,
+        relay_config,
+        metrics,
+        credentials,
+        cancel,
+    )
+    .await?;
     // info!("Stun request successful...");
-    let mut addr = XorMappedAddress::default();
-    addr.get_from(&resp.0)?;
-    Ok((addr, resp.1))
+    let mapped = mapped_addr_from(&resp.message)?;
+    // The mapping is seen by the server, so it shares the server's address family
+    if mapped.is_ipv4() != server_addr.is_ipv4() {
+        return Err(Error::ErrAddressFamilyMismatch);
+    }
+    if resp.was_relayed {
+        if let Some(observed_mappings) = &relay_config.observed_mappings {
+            observed_mappings.record(resp.from, mapped);
+        }
+    }
+    Ok(BindingResult {
+        mapped,
+        local_addr: resp.local_addr,
+        rtt: resp.rtt,
+        response_origin: optional_addr_from(&resp.message, ATTR_RESPONSE_ORIGIN)?,
+        other_address: optional_addr_from(&resp.message, ATTR_OTHER_ADDRESS)?,
+    })
+}
+
+/// Reads the address attribute `t` of `msg`, `None` if the server did not send it.
+fn optional_addr_from(msg: &Message, t: AttrType) -> Result<Option<SocketAddr>> {
+    let mut addr = MappedAddress::default();
+    match addr.get_from_as(msg, t) {
+        Ok(()) => Ok(Some(SocketAddr::new(addr.ip, addr.port))),
+        Err(stun::Error::ErrAttributeNotFound) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Checks that `addr` could be a STUN server before gathering from it. Unspecified and
+/// loopback addresses never reach a server through the relay and port zero cannot be
+/// sent to, those fail with `ErrInvalidStunServer`.
+pub fn validate_stun_server(addr: SocketAddr) -> Result<()> {
+    if addr.ip().is_unspecified() || addr.ip().is_loopback() || addr.port() == 0 {
+        return Err(Error::ErrInvalidStunServer(addr));
+    }
+    Ok(())
+}
+
+/// Reads the mapped address of a binding response, falling back to the MAPPED-ADDRESS
+/// that legacy RFC 3489 servers send in place of an XOR-MAPPED-ADDRESS.
+pub fn mapped_addr_from(msg: &Message) -> Result<SocketAddr> {
+    let mut xor_addr = XorMappedAddress::default();
+    match xor_addr.get_from(msg) {
+        Ok(()) => Ok(SocketAddr::new(xor_addr.ip, xor_addr.port)),
+        Err(stun::Error::ErrAttributeNotFound) => {
+            let mut addr = MappedAddress::default();
+            addr.get_from(msg)?;
+            Ok(SocketAddr::new(addr.ip, addr.port))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Queries the `servers` in order and returns the `XorMappedAddress` of the first
+/// one that answers. Fails with the error of every server if none does, or with
+/// `ErrRelayUnavailable` up front if the relay check fails.
+pub async fn gather_from_servers(
+    conn: &Arc<dyn Conn + Send + Sync>,
+    servers: &[SocketAddr],
+    deadline: Duration,
+    relay_config: &RelayConfig,
+    metrics: Option<&StunMetrics>,
+    cancel: Option<&CancellationToken>,
+) -> Result<(XorMappedAddress, SocketAddr, Duration)> {
+    #[cfg(feature = "relay")]
+    check_relay_available(conn, relay_config, cancel).await?;
+    let mut errors = vec![];
+    for server_addr in servers {
+        let result = get_xormapped_addr(
+            conn,
+            *server_addr,
+            deadline,
+            relay_config,
+            metrics,
+            None,
+            cancel,
+        )
+        .await;
+        match result {
+            // Shutting down, the remaining servers would be canceled as well
+            Err(Error::ErrCanceled) => return Err(Error::ErrCanceled),
+            Ok(resp) => return Ok(resp),
+            Err(err) => {
+                debug!(
+                    "STUN server {} failed, trying the next one: {}",
+                    server_addr, err
+                );
+                errors.push((*server_addr, err));
+            }
+        }
+    }
+    Err(Error::ErrStunServersFailed(errors))
+}
+
+/// Like `gather_from_servers`, but `total_deadline` bounds the whole gather instead of
+/// every request. The time left is split evenly among the servers not yet queried, so
+/// a server that fails early leaves more for the next ones, and the servers still
+/// waiting when the budget is spent are skipped.
+pub async fn gather_with_budget(
+    conn: &Arc<dyn Conn + Send + Sync>,
+    servers: &[SocketAddr],
+    total_deadline: Duration,
+    relay_config: &RelayConfig,
+    metrics: Option<&StunMetrics>,
+    cancel: Option<&CancellationToken>,
+) -> Result<(XorMappedAddress, SocketAddr, Duration)> {
+    let budget_end = Instant::now() + total_deadline;
+    #[cfg(feature = "relay")]
+    tokio::time::timeout_at(
+        budget_end,
+        check_relay_available(conn, relay_config, cancel),
+    )
+    .await
+    .map_err(|_| Error::ErrStunTimeout)??;
+    let mut errors = vec![];
+    for (i, server_addr) in servers.iter().enumerate() {
+        let remaining = budget_end.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            debug!(
+                "Gather budget of {:?} spent, skipping {} STUN servers",
+                total_deadline,
+                servers.len() - i
+            );
+            break;
+        }
+        let share = remaining / u32::try_from(servers.len() - i).unwrap_or(u32::MAX);
+        // The relay padding comes on top of the share, the budget still ends the request
+        let result = tokio::time::timeout_at(
+            budget_end,
+            get_xormapped_addr(
+                conn,
+                *server_addr,
+                share,
+                relay_config,
+                metrics,
+                None,
+                cancel,
+            ),
+        )
+        .await
+        .unwrap_or(Err(Error::ErrStunTimeout));
+        match result {
+            Err(Error::ErrCanceled) => return Err(Error::ErrCanceled),
+            Ok(resp) => return Ok(resp),
+            Err(err) => {
+                debug!(
+                    "STUN server {} failed, trying the next one: {}",
+                    server_addr, err
+                );
+                errors.push((*server_addr, err));
+            }
+        }
+    }
+    Err(Error::ErrStunServersFailed(errors))
+}
+
+/// How a NAT maps a local address to public ones (RFC 4787 4.1), as far as it can be
+/// told from the mappings two STUN servers report.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NatMappingBehavior {
+    /// Both servers see the same mapping, the NAT reuses it for every destination.
+    EndpointIndependent,
+    /// The servers see different mappings, the NAT allocates one per destination
+    /// address (or address and port, which two servers cannot tell apart).
+    AddressDependent,
+}
+
+/// Sends binding requests from `conn` to `server_a` and `server_b`, which must be
+/// distinct servers, and classifies the NAT by comparing the mapped addresses.
+pub async fn probe_nat_mapping(
+    conn: &Arc<dyn Conn + Send + Sync>,
+    server_a: SocketAddr,
+    server_b: SocketAddr,
+    deadline: Duration,
+    relay_config: &RelayConfig,
+) -> Result<NatMappingBehavior> {
+    let (mapped_a, _, _) =
+        get_xormapped_addr(conn, server_a, deadline, relay_config, None, None, None).await?;
+    let (mapped_b, _, _) =
+        get_xormapped_addr(conn, server_b, deadline, relay_config, None, None, None).await?;
+    debug!(
+        "NAT mapping seen by {}: {}, by {}: {}",
+        server_a, mapped_a, server_b, mapped_b
+    );
+    if (mapped_a.ip, mapped_a.port) == (mapped_b.ip, mapped_b.port) {
+        Ok(NatMappingBehavior::EndpointIndependent)
+    } else {
+        Ok(NatMappingBehavior::AddressDependent)
+    }
+}
+
+/// How many STUN requests `gather_from_servers_concurrent` keeps in flight by default.
+pub const DEFAULT_MAX_CONCURRENT_STUN_REQUESTS: usize = 4;
+
+/// The outcome of querying one server in `gather_from_servers_concurrent`.
+pub type ServerResult = (SocketAddr, Result<(XorMappedAddress, SocketAddr, Duration)>);
+
+/// Queries all `servers` with at most `max_concurrent` (at least one) requests in
+/// flight, sending each result on the returned channel as soon as it arrives. Every
+/// request gets a socket of its own from `bind`, as a request discards responses
+/// to other transactions arriving on its socket.
+pub fn gather_from_servers_concurrent<B, Fut>(
+    servers: Vec<SocketAddr>,
+    deadline: Duration,
+    relay_config: RelayConfig,
+    max_concurrent: usize,
+    cancel: Option<CancellationToken>,
+    bind: B,
+) -> mpsc::Receiver<ServerResult>
+where
+    B: Fn(SocketAddr) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Arc<dyn Conn + Send + Sync>>> + Send,
+{
+    let (tx, rx) = mpsc::channel(servers.len().max(1));
+    let semaphore = Arc::new(Semaphore::new(max_concurrent.max(1)));
+    let relay_config = Arc::new(relay_config);
+    let bind = Arc::new(bind);
+    tokio::spawn(async move {
+        for server_addr in servers {
+            // Only start the next request once one of the running ones is done
+            let Ok(permit) = Arc::clone(&semaphore).acquire_owned().await else {
+                return;
+            };
+            let (tx, relay_config, bind, cancel) = (
+                tx.clone(),
+                Arc::clone(&relay_config),
+                Arc::clone(&bind),
+                cancel.clone(),
+            );
+            tokio::spawn(async move {
+                let _permit = permit;
+                let result = match bind(server_addr).await {
+                    Ok(conn) => {
+                        get_xormapped_addr(
+                            &conn,
+                            server_addr,
+                            deadline,
+                            &relay_config,
+                            None,
+                            None,
+                            cancel.as_ref(),
+                        )
+                        .await
+                    }
+                    Err(err) => Err(err),
+                };
+                let _ = tx.send((server_addr, result)).await;
+            });
+        }
+    });
+    rx
+}
+
+/// A candidate address emitted by `trickle_candidates` as soon as it is known.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TrickleCandidate {
+    /// The address of a local interface.
+    Host(IpAddr),
+    /// The mapping `server` reported for the socket bound to `local_addr`.
+    ServerReflexive {
+        server: SocketAddr,
+        mapped: SocketAddr,
+        local_addr: SocketAddr,
+    },
+}
+
+/// Trickles candidates on the returned channel instead of returning them all at once:
+/// the `host_ips`, e.g. from `local_interfaces`, go out right away, followed by a
+/// server reflexive candidate for each server in `srflx_results`, e.g. from
+/// `gather_from_servers_concurrent`, as its response arrives. Servers that failed are
+/// logged and skipped.
+pub fn trickle_candidates(
+    host_ips: Vec<IpAddr>,
+    mut srflx_results: mpsc::Receiver<ServerResult>,
+) -> mpsc::Receiver<TrickleCandidate> {
+    let (tx, rx) = mpsc::channel(host_ips.len().max(1));
+    tokio::spawn(async move {
+        for ip in host_ips {
+            if tx.send(TrickleCandidate::Host(ip)).await.is_err() {
+                return;
+            }
+        }
+        while let Some((server, result)) = srflx_results.recv().await {
+            let candidate = match result {
+                Ok((mapped, local_addr, _)) => TrickleCandidate::ServerReflexive {
+                    server,
+                    mapped: SocketAddr::new(mapped.ip, mapped.port),
+                    local_addr,
+                },
+                Err(err) => {
+                    debug!("No server reflexive candidate from {}: {}", server, err);
+                    continue;
+                }
+            };
+            if tx.send(candidate).await.is_err() {
+                return;
+            }
+        }
+    });
+    rx
 }
 
-const MAX_MESSAGE_SIZE: usize = 1280;
+/// Gathers a server reflexive candidate on a socket the application already holds,
+/// instead of binding a new one. The candidate sends over `conn`, its related address
+/// is the local address the response was relayed to.
+pub async fn gather_srflx_on(
+    conn: Arc<dyn Conn + Send + Sync>,
+    server_addr: SocketAddr,
+    deadline: Duration,
+    relay_config: &RelayConfig,
+) -> Result<CandidateBase> {
+    let (mapped, local_addr, _) =
+        get_xormapped_addr(&conn, server_addr, deadline, relay_config, None, None, None).await?;
+    // Direct responses do not tell which local address they reached
+    let rel_addr = if local_addr.ip().is_unspecified() {
+        conn.local_addr()?
+    } else {
+        local_addr
+    };
+    CandidateServerReflexiveConfig {
+        base_config: CandidateBaseConfig {
+            network: UDP.to_owned(),
+            address: mapped.ip.to_string(),
+            port: mapped.port,
+            component: COMPONENT_RTP,
+            conn: Some(conn),
+            ..CandidateBaseConfig::default()
+        },
+        rel_addr: rel_addr.ip().to_string(),
+        rel_port: rel_addr.port(),
+    }
+    .new_candidate_server_reflexive()
+}
 
 // Idea: Replace the binding of the socket to the correct address with a
 // binding to a localhost socket and insert the correct address mapping
@@ -77,79 +785,748 @@ const MAX_MESSAGE_SIZE: usize = 1280;
 // packet to the socket which then know where to forward this informatiosn to.
 // The external application needs to store the to and from mapping (very)
 // similar to the actual NAT we are trying to navigate and allows sending
-// the packet back to the socket opened by ice. To allow for an easy 
+// the packet back to the socket opened by ice. To allow for an easy
 // management and differentiation bind to different ports. ~10000 addresses
 // should be enough for anything to work with
 pub async fn stun_request(
-    conn: &Arc<dyn Conn + Send + Sync>,
+    transport: &dyn RelayTransport,
     server_addr: SocketAddr,
     deadline: Duration,
-    relay_port: u16,
-) -> Result<(Message, SocketAddr)> {
+    relay_config: &RelayConfig,
+    metrics: Option<&StunMetrics>,
+    credentials: Option<&Credentials>,
+    cancel: Option<&CancellationToken>,
+) -> Result<StunResponse> {
+    stun_request_with_transaction_id(
+        transport,
+        server_addr,
+        deadline,
+        relay_config,
+        metrics,
+        credentials,
+        cancel,
+        None,
+    )
+    .await
+}
+
+/// Like `stun_request`, but sends the request with `transaction_id` if given, e.g. to
+/// correlate it with the logs of the relay. Without one a random ID is generated.
+#[allow(clippy::too_many_arguments)]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "stun_request",
+        skip_all,
+        fields(
+            %server_addr,
+            relay_addr = tracing::field::Empty,
+            transaction_id = tracing::field::Empty,
+            outcome = tracing::field::Empty,
+        )
+    )
+)]
+pub async fn stun_request_with_transaction_id(
+    transport: &dyn RelayTransport,
+    server_addr: SocketAddr,
+    deadline: Duration,
+    relay_config: &RelayConfig,
+    metrics: Option<&StunMetrics>,
+    credentials: Option<&Credentials>,
+    cancel: Option<&CancellationToken>,
+    transaction_id: Option<TransactionId>,
+) -> Result<StunResponse> {
+    // Not `unwrap_or_default`, the default ID is all zeros rather than random
+    let transaction_id = match transaction_id {
+        Some(transaction_id) => transaction_id,
+        None => TransactionId::new(),
+    };
+    let result = send_stun_request(
+        transport,
+        server_addr,
+        deadline,
+        relay_config,
+        metrics,
+        credentials,
+        cancel,
+        transaction_id,
+    )
+    .await;
+    #[cfg(feature = "tracing")]
+    {
+        let outcome = match &result {
+            Ok(_) => "ok".to_owned(),
+            Err(err) => err.to_string(),
+        };
+        tracing::Span::current().record("outcome", outcome.as_str());
+        tracing::debug!(outcome, "STUN request finished");
+    }
+    result
+}
+
+/// Runs the future `$fut` inside `$span` when built with the `tracing` feature.
+macro_rules! traced {
+    ($fut:expr, $span:expr) => {{
+        #[cfg(feature = "tracing")]
+        let fut = tracing::Instrument::instrument($fut, $span);
+        #[cfg(not(feature = "tracing"))]
+        let fut = $fut;
+        fut
+    }};
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_stun_request(
+    transport: &dyn RelayTransport,
+    server_addr: SocketAddr,
+    deadline: Duration,
+    relay_config: &RelayConfig,
+    metrics: Option<&StunMetrics>,
+    credentials: Option<&Credentials>,
+    cancel: Option<&CancellationToken>,
+    transaction_id: TransactionId,
+) -> Result<StunResponse> {
+    let record = |counter: fn(&StunMetrics) -> &AtomicU64| {
+        if let Some(metrics) = metrics {
+            counter(metrics).fetch_add(1, Ordering::Relaxed);
+        }
+    };
+    let classify = |counter: fn(&RelayMetrics) -> &AtomicU64| {
+        if let Some(metrics) = &relay_config.relay_metrics {
+            counter(metrics).fetch_add(1, Ordering::Relaxed);
+        }
+    };
+
     // Modifying the 'server' addr to be contained in the packet
     // The packet is also relayed via quicheperf to obtain control
     // over the socket
-    let relayed_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), relay_port);
-    let send_info = SendInfo {
-        from: conn.local_addr().unwrap(),
-        to: server_addr,
+    #[cfg(feature = "relay")]
+    let local_addr = transport.local_addr().map_err(|_| Error::ErrNoLocalAddr)?;
+    let mut request = Message::new();
+    {
+        // The setters are not Send, keep them out of the awaits below
+        let mut setters: Vec<Box<dyn Setter>> =
+            vec![Box::new(BINDING_REQUEST), Box::new(transaction_id)];
+        if let Some(credentials) = credentials {
+            setters.push(Box::new(Username::new(
+                ATTR_USERNAME,
+                credentials.username.clone(),
+            )));
+        }
+        if let Some(software) = &relay_config.software {
+            setters.push(Box::new(Software::new(ATTR_SOFTWARE, software.clone())));
+        }
+        // MESSAGE-INTEGRITY covers the attributes before it, only FINGERPRINT may follow
+        if let Some(credentials) = credentials {
+            setters.push(Box::new(MessageIntegrity::new_short_term_integrity(
+                credentials.password.clone(),
+            )));
+        }
+        // FINGERPRINT covers everything before it, so it has to come last
+        if relay_config.use_fingerprint {
+            setters.push(Box::new(FINGERPRINT));
+        }
+        request.build(&setters)?;
+    }
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record(
+        "transaction_id",
+        tracing::field::debug(&request.transaction_id),
+    );
+    #[cfg(feature = "relay")]
+    let (datagram, targets) = match relay_config.mode {
+        RelayMode::Relayed => {
+            let frame = encode_relay_frame(relay_config, local_addr, server_addr, &request.raw)?;
+            (frame, relay_config.next_relays_for(local_addr))
+        }
+        RelayMode::Direct => (request.raw.clone(), vec![server_addr]),
     };
-    // info!("STUN request send info: {:?}", send_info);
-    let mut send_info_raw = serialize_send_info(send_info).unwrap();
+    #[cfg(not(feature = "relay"))]
+    let (datagram, targets) = (request.raw.clone(), vec![server_addr]);
 
-    let mut request = Message::new();
-    request.build(&[Box::new(BINDING_REQUEST), Box::new(TransactionId::new())])?;
-    send_info_raw.append(&mut request.raw);
-    
-    conn.send_to(&send_info_raw, relayed_addr).await?;
-    
-    let mut bs = vec![0_u8; MAX_MESSAGE_SIZE];
-    let (n, _) = if deadline > Duration::from_secs(0) {
-        // TODO: Increase the timeout duration since we have the ICE indirection
-        match tokio::time::timeout(deadline.add(Duration::from_millis(200)), conn.recv_from(&mut bs)).await {
-            Ok(result) => match result {
-                Ok((n, addr)) => (n, addr),
-                Err(err) => return Err(Error::Other(err.to_string())),
-            },
-            Err(err) => return Err(Error::Other(err.to_string())),
+    // Leave room for the relay framing in front of a full sized STUN message
+    let mut bs = PooledBuffer::new(
+        relay_config.mtu + RECV_FRAMING_LEN,
+        relay_config.buffer_pool.as_deref(),
+    );
+    record(|metrics| &metrics.requests_sent);
+    // A relay that cannot be reached or does not answer is skipped for the next one,
+    // each relay gets the full deadline
+    'relays: for (i, &target) in targets.iter().enumerate() {
+        let has_fallback = i + 1 < targets.len();
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("relay_addr", tracing::field::display(target));
+
+        // Retransmit with a doubling RTO (RFC 5389 7.2.1). The transaction ID stays
+        // the same so a late response to an earlier attempt still matches
+        let timeout_at = if deadline > Duration::from_secs(0) {
+            Some(Instant::now() + relay_config.response_timeout(deadline))
+        } else {
+            None
+        };
+        let mut rto = relay_config.initial_rto();
+        let mut retransmits = 0;
+        loop {
+            #[cfg(feature = "relay")]
+            trace_relay_frame("outgoing", &datagram);
+            let send = traced!(
+                transport.send_frame(&datagram, target),
+                tracing::trace_span!("relay_send", %target)
+            );
+            if let Err(err) = send.await {
+                let err = Error::from(err);
+                if is_retriable(&err) {
+                    // Handled like a lost datagram, the retransmission sends it again
+                    debug!("Sending to {} failed, retrying: {}", target, err);
+                } else if has_fallback {
+                    warn!("Relay {} unreachable, trying the next one: {}", target, err);
+                    continue 'relays;
+                } else {
+                    return Err(err);
+                }
+            }
+            let sent_at = Instant::now();
+
+            let wait = match timeout_at {
+                Some(timeout_at) => rto.min(timeout_at.saturating_duration_since(Instant::now())),
+                None => rto,
+            };
+            let retransmit_at = Instant::now() + wait;
+            loop {
+                // Transports with a read timeout spare us a timer per receive
+                let remaining = retransmit_at.saturating_duration_since(Instant::now());
+                let recv_frame = traced!(
+                    transport.recv_frame(&mut bs),
+                    tracing::trace_span!("relay_recv")
+                );
+                let recv = async {
+                    if transport.set_read_timeout(Some(remaining)) {
+                        match recv_frame.await {
+                            Err(err) if is_read_timeout(&err) => None,
+                            received => Some(received),
+                        }
+                    } else {
+                        tokio::time::timeout_at(retransmit_at, recv_frame)
+                            .await
+                            .ok()
+                    }
+                };
+                let received = match cancel {
+                    Some(cancel) => tokio::select! {
+                        _ = cancel.cancelled() => return Err(Error::ErrCanceled),
+                        received = recv => received,
+                    },
+                    None => recv.await,
+                };
+                match received {
+                    // Anyone can send to our socket, only the relay (or the server when
+                    // talking directly) may answer
+                    Some(Ok((_, src))) if src != target => {
+                        debug!("Discarding datagram from {}, expected {}", src, target);
+                    }
+                    // An empty datagram, e.g. while the relay tears down, must not be
+                    // classified by whatever the buffer held before. It cannot be our
+                    // response either, keep waiting for that
+                    Some(Ok((n, src))) if n < MESSAGE_HEADER_SIZE => {
+                        classify(|metrics| &metrics.too_short);
+                        debug!(
+                            "Discarding {} byte datagram from {}: {:02x?}",
+                            n,
+                            src,
+                            &bs[..n]
+                        );
+                    }
+                    Some(Ok((n, src))) => {
+                        #[cfg(feature = "relay")]
+                        let n = match strip_session_ids(&mut bs[..n], relay_config.session_id) {
+                            Ok(n) => n,
+                            Err(err) => {
+                                record(|metrics| &metrics.parse_errors);
+                                return Err(err);
+                            }
+                        };
+                        // The relay may coalesce several frames into one datagram, look
+                        // through all of them for the one answering our transaction
+                        #[cfg(feature = "relay")]
+                        let responses = RelayFrameReader::new(&bs[..n]).map(|frame| {
+                            match &frame {
+                                Ok(RelayFrame::RecvInfo { .. }) => {
+                                    classify(|metrics| &metrics.relayed)
+                                }
+                                Ok(RelayFrame::RawStun(_)) => classify(|metrics| &metrics.raw_stun),
+                                Err(Error::ErrUnknownRelayPacketType) => {
+                                    classify(|metrics| &metrics.unknown_type)
+                                }
+                                _ => {}
+                            }
+                            frame.map_err(stun_decode_error).and_then(|frame| {
+                                // Only the relay we sent to gets this far in relayed
+                                // mode, and only the server when talking directly
+                                decode_stun_response(
+                                    frame,
+                                    request.transaction_id,
+                                    src,
+                                    server_addr,
+                                    target,
+                                )
+                            })
+                        });
+                        #[cfg(not(feature = "relay"))]
+                        if is_message(&bs[..n]) {
+                            classify(|metrics| &metrics.raw_stun);
+                        } else {
+                            classify(|metrics| &metrics.unknown_type);
+                        }
+                        #[cfg(not(feature = "relay"))]
+                        let responses = std::iter::once(decode_direct_stun_response(
+                            &bs[..n],
+                            request.transaction_id,
+                            src,
+                            server_addr,
+                        ));
+                        for response in responses {
+                            match response.and_then(|mut response| {
+                                check_error_response(&response.message)?;
+                                check_binding_success(&response.message)?;
+                                // Authenticated servers sign their responses, reject anything else
+                                if let Some(credentials) = credentials {
+                                    assert_inbound_message_integrity(
+                                        &mut response.message,
+                                        credentials.key(),
+                                    )?;
+                                }
+                                // Tells STUN apart from data the relay carries on the same port
+                                if response.message.contains(ATTR_FINGERPRINT) {
+                                    FINGERPRINT.check(&response.message)?;
+                                }
+                                Ok(response)
+                            }) {
+                                // Stale or foreign response, keep waiting for ours
+                                Err(Error::ErrTransactionMismatch) => {
+                                    debug!(
+                                        "Discarding STUN response with unexpected transaction ID"
+                                    );
+                                }
+                                Err(err @ Error::ErrStunErrorResponse { .. }) => {
+                                    return Err(err);
+                                }
+                                Err(err) => {
+                                    record(|metrics| &metrics.parse_errors);
+                                    return Err(err);
+                                }
+                                Ok(response) => {
+                                    record(|metrics| &metrics.responses_ok);
+                                    return Ok(StunResponse {
+                                        message: response.message,
+                                        local_addr: response.local_addr,
+                                        from: response.from,
+                                        rtt: sent_at.elapsed(),
+                                        was_relayed: response.was_relayed,
+                                        transaction_id,
+                                    });
+                                }
+                            }
+                        }
+                    }
+                    Some(Err(err)) => {
+                        let err = Error::ErrStunRecv(err);
+                        if !is_retriable(&err) {
+                            return Err(err);
+                        }
+                        debug!("Receiving from {} failed, retrying: {}", target, err);
+                        break;
+                    }
+                    None => break,
+                }
+            }
+
+            let expired = timeout_at.is_some_and(|timeout_at| Instant::now() >= timeout_at);
+            if expired || retransmits >= relay_config.max_retransmits {
+                if has_fallback {
+                    warn!("Relay {} did not answer, trying the next one", target);
+                    continue 'relays;
+                }
+                record(|metrics| &metrics.timeouts);
+                return Err(Error::ErrStunTimeout);
+            }
+            debug!(
+                "No STUN response from {} within {:?}, retransmitting",
+                server_addr, rto
+            );
+            retransmits += 1;
+            rto = relay_config.next_rto(rto);
         }
-    } else {
-        conn.recv_from(&mut bs).await?
+    }
+    // `targets` is never empty
+    Err(Error::ErrStunTimeout)
+}
+
+/// Registers the socket of `conn` with the relay and waits for the relay to acknowledge
+/// it, retransmitting like `stun_request`. Frames other than the acknowledgement are
+/// dropped.
+#[cfg(feature = "relay")]
+pub async fn open_relay_socket(
+    conn: &Arc<dyn Conn + Send + Sync>,
+    relay_config: &RelayConfig,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    let local_addr = conn.local_addr().map_err(|_| Error::ErrNoLocalAddr)?;
+    let relayed_addr = relay_config.relay_addr_for(local_addr);
+    let mut frame = encode_open_socket(RelayPacketType::OpenSocket, local_addr)?;
+    insert_session_id(&mut frame, relay_config.session_id);
+
+    let mut bs = vec![0_u8; relay_config.mtu + MAX_RELAY_HEADER_LEN];
+    let mut rto = relay_config.initial_rto();
+    for _ in 0..=relay_config.max_retransmits {
+        trace_relay_frame("outgoing", &frame);
+        conn.send_to(&frame, relayed_addr).await?;
+
+        let retransmit_at = Instant::now() + rto;
+        loop {
+            let recv = tokio::time::timeout_at(retransmit_at, conn.recv_from(&mut bs));
+            let received = match cancel {
+                Some(cancel) => tokio::select! {
+                    _ = cancel.cancelled() => return Err(Error::ErrCanceled),
+                    received = recv => received,
+                },
+                None => recv.await,
+            };
+            match received {
+                Ok(Ok((n, _))) => {
+                    let frame = match strip_session_ids(&mut bs[..n], relay_config.session_id) {
+                        Ok(n) => decode_relay_frame(&bs[..n]),
+                        Err(err) => Err(err),
+                    };
+                    match frame {
+                        Ok(RelayFrame::OpenSocketAck(addr)) if addr == local_addr => {
+                            debug!("Relay {} opened socket {}", relayed_addr, local_addr);
+                            return Ok(());
+                        }
+                        _ => debug!("Discarding relay frame while waiting for OpenSocketAck"),
+                    }
+                }
+                Ok(Err(err)) => return Err(Error::ErrStunRecv(err)),
+                Err(_) => break,
+            }
+        }
+        rto = relay_config.next_rto(rto);
+    }
+
+    Err(Error::ErrRelayHandshakeFailed)
+}
+
+/// Registers a socket bound on `net` with the relay through `open_relay_socket`, so
+/// the relay knows where to deliver the frames for it. Only sockets of the real
+/// network are registered, and only if `RelayConfig::open_socket` is set and the
+/// traffic goes through the relay at all.
+#[cfg(feature = "relay")]
+pub async fn register_relay_socket(
+    net: &Net,
+    conn: &Arc<dyn Conn + Send + Sync>,
+    relay_config: &RelayConfig,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    if net.is_virtual() || !relay_config.open_socket || relay_config.mode != RelayMode::Relayed {
+        return Ok(());
+    }
+    open_relay_socket(conn, relay_config, cancel).await
+}
+
+/// Makes sure the relay is listening before STUN requests are sent through it, see
+/// `RelayConfig::relay_check_timeout`. Passes right away if the check is disabled or
+/// the requests go directly to the servers.
+#[cfg(feature = "relay")]
+pub async fn check_relay_available(
+    conn: &Arc<dyn Conn + Send + Sync>,
+    relay_config: &RelayConfig,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    let Some(timeout) = relay_config.relay_check_timeout else {
+        return Ok(());
+    };
+    if relay_config.mode != RelayMode::Relayed {
+        return Ok(());
+    }
+    // A single attempt, the relay is on the same host and answers at once if it runs
+    let check_config = RelayConfig {
+        rto: timeout,
+        min_rto: Duration::ZERO,
+        max_retransmits: 0,
+        ..relay_config.clone()
     };
+    match open_relay_socket(conn, &check_config, cancel).await {
+        Err(Error::ErrCanceled) => Err(Error::ErrCanceled),
+        Err(err) => {
+            warn!(
+                "Relay {} did not acknowledge within {:?}: {}",
+                relay_config.relay_addr, timeout, err
+            );
+            Err(Error::ErrRelayUnavailable)
+        }
+        Ok(()) => Ok(()),
+    }
+}
 
-    // Check if we received a relayed packet or not
-    let mut res = Message::new();
-    let p_type = bs[0];
-    let mut local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
-    match p_type {
-        0xCC => {
-            let len = bs[1];
-            let recv_info = parse_send_info(&bs[2..], len as usize).unwrap();
-            // TODO: Check if we need to do something with the from information or not
-            info!("Received relayed STUN response from {}->{}", recv_info.from, recv_info.to);
-            local_addr = recv_info.to;
-            res.raw = bs[(2 + len as usize)..n].to_vec();
-            res.decode()?;
-        },
-        _ => {
-            res.raw = bs[..n].to_vec();
-            res.decode()?;
+/// The most the relay adds in front of a received STUN message.
+#[cfg(feature = "relay")]
+const RECV_FRAMING_LEN: usize = MAX_RELAY_HEADER_LEN;
+#[cfg(not(feature = "relay"))]
+const RECV_FRAMING_LEN: usize = 0;
+
+/// Whether a STUN request failing with `err` may succeed when retransmitted. Timeouts
+/// and interrupted or would-block I/O are retried; a closed socket or a refused
+/// connection, e.g. an ICMP port unreachable from a relay that is gone, is final.
+pub fn is_retriable(err: &Error) -> bool {
+    let io_err = match err {
+        Error::ErrStunTimeout => return true,
+        Error::Io(err) => &err.0,
+        Error::Util(util::Error::Io(err)) | Error::ErrStunRecv(util::Error::Io(err)) => &err.0,
+        _ => return false,
+    };
+    matches!(
+        io_err.kind(),
+        std::io::ErrorKind::TimedOut
+            | std::io::ErrorKind::WouldBlock
+            | std::io::ErrorKind::Interrupted
+    )
+}
+
+/// Surfaces the ERROR-CODE of an error response, e.g. a 401 from a server that
+/// requires authentication, as `ErrStunErrorResponse`.
+fn check_error_response(message: &Message) -> Result<()> {
+    if message.typ.class != CLASS_ERROR_RESPONSE {
+        return Ok(());
+    }
+    let mut error_code = ErrorCodeAttribute::default();
+    error_code.get_from(message).map_err(stun_decode_error)?;
+    Err(Error::ErrStunErrorResponse {
+        code: error_code.code.0,
+        reason: String::from_utf8_lossy(&error_code.reason).into_owned(),
+    })
+}
+
+/// Rejects anything but a binding success, e.g. an indication or a request that arrived
+/// on the relay port carrying our transaction ID.
+fn check_binding_success(message: &Message) -> Result<()> {
+    if message.typ != BINDING_SUCCESS {
+        debug!("Expected a binding success, got {}", message.typ);
+        return Err(Error::ErrUnexpectedStunMessage);
+    }
+    Ok(())
+}
+
+fn stun_decode_error(err: impl Into<Error>) -> Error {
+    Error::ErrStunDecode(Box::new(err.into()))
+}
+
+/// Decodes a STUN response frame, either relayed or received directly from the server,
+/// and checks it belongs to the request with `transaction_id` sent to `server_addr`.
+/// An unframed response is only accepted from `direct_from`: the relay the request was
+/// sent through in relayed mode, as it may pass the server's answer on as is, or the
+/// server itself. Returns the message, the local address it was relayed to, the
+/// address that sent it, which is `src` unless the relay reports otherwise, and
+/// whether it came framed.
+#[cfg(feature = "relay")]
+fn decode_stun_response(
+    frame: RelayFrame<'_>,
+    transaction_id: TransactionId,
+    src: SocketAddr,
+    server_addr: SocketAddr,
+    direct_from: SocketAddr,
+) -> Result<DecodedStunResponse> {
+    match frame {
+        RelayFrame::RecvInfo { recv_info, payload } => {
+            let recv_info = recv_info.unmapped();
+            info!("Received relayed STUN response {}", recv_info.redacted());
+            let message = decode_stun_message(payload, transaction_id, recv_info.from, server_addr)
+                .map_err(|err| match err {
+                    Error::ErrStunServerMismatch(server_addr, from) => {
+                        Error::ErrRelayMisroute(server_addr, from)
+                    }
+                    err => err,
+                })?;
+            Ok(DecodedStunResponse {
+                message,
+                local_addr: recv_info.to,
+                from: recv_info.from,
+                was_relayed: true,
+            })
+        }
+        // Answered without the relay's framing
+        RelayFrame::RawStun(raw) => {
+            decode_direct_stun_response(raw, transaction_id, src, direct_from)
         }
+        // Only we send SendInfo frames, a misconfigured relay reflected one of ours
+        RelayFrame::SendInfo { send_info, .. } => {
+            warn!(
+                "Relay reflected our SendInfo frame {}",
+                send_info.redacted()
+            );
+            Err(Error::ErrRelayLoopback)
+        }
+        _ => Err(stun_decode_error(Error::ErrUnknownRelayPacketType)),
+    }
+}
+
+/// Decodes a STUN response `src` sent straight to us, which has to be `direct_from`,
+/// the server or the relay passing its answer on unframed. There is no relayed local
+/// address to report, so it is left unspecified.
+fn decode_direct_stun_response(
+    raw: &[u8],
+    transaction_id: TransactionId,
+    src: SocketAddr,
+    direct_from: SocketAddr,
+) -> Result<DecodedStunResponse> {
+    let message = decode_stun_message(raw, transaction_id, src, direct_from)?;
+    Ok(DecodedStunResponse {
+        message,
+        local_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
+        from: src,
+        was_relayed: false,
+    })
+}
+
+/// Decodes `raw` and checks it answers the request with `transaction_id` and was sent
+/// by `server_addr`.
+fn decode_stun_message(
+    raw: &[u8],
+    transaction_id: TransactionId,
+    from: SocketAddr,
+    server_addr: SocketAddr,
+) -> Result<Message> {
+    let mut res = Message::new();
+    res.raw = raw.to_vec();
+    res.decode().map_err(stun_decode_error)?;
+    if res.transaction_id != transaction_id {
+        return Err(Error::ErrTransactionMismatch);
+    }
+    // Our transaction answered by another server means the response was misdelivered
+    if from != server_addr {
+        warn!("STUN response for {} came from {}", server_addr, from);
+        return Err(Error::ErrStunServerMismatch(server_addr, from));
     }
-    Ok((res, local_addr))
+    Ok(res)
 }
 
+/// Lists the addresses of the local interfaces that pass the filters and belong to the
+/// address families of `network_types`. Loopback addresses are always skipped,
+/// link-local ones unless `include_link_local` is set. An empty `network_types` stands
+/// for `supported_network_types()`, i.e. both IPv4 and IPv6, rather than for none.
 pub async fn local_interfaces(
     vnet: &Arc<Net>,
     interface_filter: &Option<InterfaceFilterFn>,
     ip_filter: &Option<IpFilterFn>,
     network_types: &[NetworkType],
+    include_link_local: bool,
 ) -> HashSet<IpAddr> {
-    let mut ips = HashSet::new();
+    let addrs = local_interfaces_detailed(
+        vnet,
+        interface_filter,
+        ip_filter,
+        network_types,
+        include_link_local,
+    )
+    .await;
+    let mut ips = HashSet::with_capacity(addrs.len());
+    ips.extend(addrs.into_iter().map(|(_, ip)| ip));
+    ips
+}
+
+/// Like `local_interfaces`, but lists the addresses under each of the `network_types`
+/// they can be used with, e.g. an IPv4 address under both `Udp4` and `Tcp4`.
+pub async fn local_interfaces_by_network_type(
+    vnet: &Arc<Net>,
+    interface_filter: &Option<InterfaceFilterFn>,
+    ip_filter: &Option<IpFilterFn>,
+    network_types: &[NetworkType],
+    include_link_local: bool,
+) -> HashMap<NetworkType, Vec<IpAddr>> {
+    let network_types = or_supported_network_types(network_types);
+    let addrs = local_interfaces(
+        vnet,
+        interface_filter,
+        ip_filter,
+        &network_types,
+        include_link_local,
+    )
+    .await;
+    group_by_network_type(addrs, &network_types)
+}
+
+/// Returns `network_types`, or the supported ones if it is empty.
+fn or_supported_network_types(network_types: &[NetworkType]) -> Cow<'_, [NetworkType]> {
+    if network_types.is_empty() {
+        Cow::Owned(supported_network_types())
+    } else {
+        Cow::Borrowed(network_types)
+    }
+}
+
+/// Files each address under the `network_types` of its family.
+pub(crate) fn group_by_network_type(
+    addrs: impl IntoIterator<Item = IpAddr>,
+    network_types: &[NetworkType],
+) -> HashMap<NetworkType, Vec<IpAddr>> {
+    let (ipv4_types, ipv6_types): (Vec<NetworkType>, Vec<NetworkType>) = network_types
+        .iter()
+        .copied()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .partition(|typ| typ.is_ipv4());
+    let mut grouped: HashMap<NetworkType, Vec<IpAddr>> = HashMap::with_capacity(4);
+    for addr in addrs {
+        let types = if addr.is_ipv4() {
+            &ipv4_types
+        } else {
+            &ipv6_types
+        };
+        for typ in types {
+            grouped.entry(*typ).or_default().push(addr);
+        }
+    }
+    grouped
+}
+
+/// Like `local_interfaces`, but keeps the name of the interface each address belongs to.
+pub async fn local_interfaces_detailed(
+    vnet: &Arc<Net>,
+    interface_filter: &Option<InterfaceFilterFn>,
+    ip_filter: &Option<IpFilterFn>,
+    network_types: &[NetworkType],
+    include_link_local: bool,
+) -> Vec<(String, IpAddr)> {
+    filter_interface_addrs(
+        &vnet.get_interfaces().await,
+        interface_filter,
+        ip_filter,
+        network_types,
+        include_link_local,
+    )
+}
+
+/// Returns the addresses of the interfaces the default route goes through, e.g. to keep
+/// the candidate list small on hosts with many interfaces. Intersect the result with
+/// `local_interfaces` to apply its filters as well. Platforms that do not report their
+/// routes mark no interface, all addresses are returned then.
+pub async fn default_route_interfaces(vnet: &Arc<Net>) -> HashSet<IpAddr> {
     let interfaces = vnet.get_interfaces().await;
+    let has_route_info = interfaces.iter().any(|iface| iface.is_default_route());
+    interfaces
+        .iter()
+        .filter(|iface| !has_route_info || iface.is_default_route())
+        .flat_map(|iface| iface.addrs().iter().map(|ipnet| ipnet.addr()))
+        .collect()
+}
 
+pub(crate) fn filter_interface_addrs(
+    interfaces: &[Interface],
+    interface_filter: &Option<InterfaceFilterFn>,
+    ip_filter: &Option<IpFilterFn>,
+    network_types: &[NetworkType],
+    include_link_local: bool,
+) -> Vec<(String, IpAddr)> {
     let (mut ipv4requested, mut ipv6requested) = (false, false);
-    for typ in network_types {
+    for typ in or_supported_network_types(network_types).iter() {
         if typ.is_ipv4() {
             ipv4requested = true;
         }
@@ -157,6 +1534,11 @@ pub async fn local_interfaces(
             ipv6requested = true;
         }
     }
+    if !ipv4requested && !ipv6requested {
+        return vec![];
+    }
+
+    let mut addrs = Vec::with_capacity(interfaces.iter().map(|iface| iface.addrs().len()).sum());
 
     for iface in interfaces {
         if let Some(filter) = interface_filter {
@@ -169,18 +1551,142 @@ pub async fn local_interfaces(
             let ipaddr = ipnet.addr();
 
             if !ipaddr.is_loopback()
+                && (include_link_local || !is_link_local(&ipaddr))
                 && ((ipv4requested && ipaddr.is_ipv4()) || (ipv6requested && ipaddr.is_ipv6()))
                 && ip_filter
                     .as_ref()
                     .map(|filter| filter(ipaddr))
                     .unwrap_or(true)
             {
-                ips.insert(ipaddr);
+                addrs.push((iface.name().to_owned(), ipaddr));
             }
         }
     }
 
-    ips
+    addrs
+}
+
+/// Reports whether `ip` is link-local, i.e. in `169.254.0.0/16` or `fe80::/10`.
+pub fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        // Ipv6Addr::is_unicast_link_local is unstable
+        IpAddr::V6(ip) => (ip.segments()[0] & 0xffc0) == 0xfe80,
+    }
+}
+
+/// Reports whether `ip` is private, i.e. in one of the RFC 1918 ranges, the shared
+/// address space `100.64.0.0/10` or `fc00::/7`.
+pub fn is_private(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private() || (ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64)
+        }
+        // Ipv6Addr::is_unique_local is unstable
+        IpAddr::V6(ip) => (ip.segments()[0] & 0xfe00) == 0xfc00,
+    }
+}
+
+/// Keeps at most `max` of `addrs` for host candidates, global addresses first, then
+/// private and then link-local ones. Ties go to the lower address, so the same
+/// addresses survive every gathering. Without a `max` all addresses are kept.
+pub fn cap_host_addrs(addrs: impl IntoIterator<Item = IpAddr>, max: Option<usize>) -> Vec<IpAddr> {
+    let mut addrs: Vec<IpAddr> = addrs.into_iter().collect();
+    let Some(max) = max else {
+        return addrs;
+    };
+    let rank = |ip: &IpAddr| {
+        if is_link_local(ip) {
+            2
+        } else if is_private(ip) {
+            1
+        } else {
+            0
+        }
+    };
+    addrs.sort_by_key(|ip| (rank(ip), *ip));
+    if addrs.len() > max {
+        let dropped = addrs.split_off(max);
+        debug!(
+            "Gathering host candidates for {} addresses, dropping {:?}",
+            max, dropped
+        );
+    }
+    addrs
+}
+
+/// How long `InterfaceCache` keeps the result of `local_interfaces` by default.
+pub const DEFAULT_INTERFACE_CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Memoizes `local_interfaces` per set of arguments so repeated gathering, e.g.
+/// during ICE restarts, doesn't enumerate the interfaces every time. A cache must
+/// only be used with one pair of filters, like the ones of a single agent.
+pub struct InterfaceCache {
+    ttl: Duration,
+    entries: tokio::sync::Mutex<HashMap<(Vec<NetworkType>, bool), CachedInterfaces>>,
+}
+
+type CachedInterfaces = (Instant, HashSet<IpAddr>);
+
+impl Default for InterfaceCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_INTERFACE_CACHE_TTL)
+    }
+}
+
+impl InterfaceCache {
+    pub fn new(ttl: Duration) -> Self {
+        InterfaceCache {
+            ttl,
+            entries: tokio::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cached addresses if they are younger than the TTL and runs
+    /// `local_interfaces` otherwise.
+    pub async fn local_interfaces(
+        &self,
+        vnet: &Arc<Net>,
+        interface_filter: &Option<InterfaceFilterFn>,
+        ip_filter: &Option<IpFilterFn>,
+        network_types: &[NetworkType],
+        include_link_local: bool,
+    ) -> HashSet<IpAddr> {
+        let key = (network_types.to_vec(), include_link_local);
+        let mut entries = self.entries.lock().await;
+        if let Some((fetched_at, ips)) = entries.get(&key) {
+            if fetched_at.elapsed() < self.ttl {
+                return ips.clone();
+            }
+        }
+
+        let ips = local_interfaces(
+            vnet,
+            interface_filter,
+            ip_filter,
+            network_types,
+            include_link_local,
+        )
+        .await;
+        entries.insert(key, (Instant::now(), ips.clone()));
+        ips
+    }
+
+    /// Drops all cached results, e.g. after a network change.
+    pub async fn invalidate(&self) {
+        self.entries.lock().await.clear();
+    }
+}
+
+/// Determines in which order `listen_udp_in_port_range_with_strategy` tries the
+/// ports of its range.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PortAllocationStrategy {
+    /// Start at a random port of the range.
+    #[default]
+    Random,
+    /// Start at the lowest port of the range and scan upwards.
+    Sequential,
 }
 
 pub async fn listen_udp_in_port_range(
@@ -188,10 +1694,225 @@ pub async fn listen_udp_in_port_range(
     port_max: u16,
     port_min: u16,
     laddr: SocketAddr,
-    relay_port: u16,
 ) -> Result<Arc<dyn Conn + Send + Sync>> {
+    listen_udp_in_port_range_with_strategy(
+        vnet,
+        port_max,
+        port_min,
+        laddr,
+        PortAllocationStrategy::Random,
+    )
+    .await
+}
+
+pub async fn listen_udp_in_port_range_with_strategy(
+    vnet: &Arc<Net>,
+    port_max: u16,
+    port_min: u16,
+    laddr: SocketAddr,
+    strategy: PortAllocationStrategy,
+) -> Result<Arc<dyn Conn + Send + Sync>> {
+    let options = ListenOptions {
+        strategy,
+        ..Default::default()
+    };
+    listen_udp_in_port_range_with_options(vnet, port_max, port_min, laddr, &options).await
+}
+
+/// How many times a port is retried after a transient bind failure by default.
+pub const DEFAULT_BIND_RETRIES: u32 = 2;
+
+/// The pause before retrying a port after a transient bind failure by default.
+pub const DEFAULT_BIND_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Controls how the `listen_*_in_port_range` helpers bind their socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenOptions {
+    /// The order in which the ports of the range are tried.
+    pub strategy: PortAllocationStrategy,
+    /// Allows the port to be shared. Together with `PortAllocationStrategy::Sequential`
+    /// repeated binds then take the same port, which keeps the NAT mapping the same for
+    /// every STUN server.
+    pub reuse_port: bool,
+    /// Pins the socket to this interface (SO_BINDTODEVICE) before it is bound, so its
+    /// traffic leaves through that NIC regardless of the routing table. Only Linux
+    /// supports this, other platforms and the virtual network ignore the device. The
+    /// socket reaches the relay over loopback, devices that cannot are refused with
+    /// `ErrBindDeviceWithRelay`.
+    pub bind_device: Option<String>,
+    /// How often a port is retried when binding it fails with an error that may go
+    /// away by itself, e.g. EADDRNOTAVAIL while its interface is flapping. Other
+    /// errors move on to the next port right away.
+    pub bind_retries: u32,
+    /// The pause before each of the `bind_retries`.
+    pub bind_retry_delay: Duration,
+    /// Ports of this pool that are handed out are skipped, the port the socket is
+    /// actually bound to is reserved until the returned conn is dropped. See
+    /// `PortPool::listen_udp`.
+    pub port_pool: Option<Arc<PortPool>>,
+    /// Logs at debug level when the returned conn is released, see `TrackedConn`.
+    pub track_release: bool,
+    /// Fails with `ErrEphemeralPortForbidden` instead of letting the OS pick a port
+    /// when neither the port range nor the local address give one, e.g. where a
+    /// firewall only lets a known range through.
+    pub forbid_ephemeral: bool,
+}
+
+impl Default for ListenOptions {
+    fn default() -> Self {
+        Self {
+            strategy: PortAllocationStrategy::default(),
+            reuse_port: false,
+            bind_device: None,
+            bind_retries: DEFAULT_BIND_RETRIES,
+            bind_retry_delay: DEFAULT_BIND_RETRY_DELAY,
+            port_pool: None,
+            track_release: false,
+            forbid_ephemeral: false,
+        }
+    }
+}
+
+/// Binds a UDP socket on a port of `[port_min, port_max]` the way `options` describe.
+pub async fn listen_udp_in_port_range_with_options(
+    vnet: &Arc<Net>,
+    port_max: u16,
+    port_min: u16,
+    laddr: SocketAddr,
+    options: &ListenOptions,
+) -> Result<Arc<dyn Conn + Send + Sync>> {
+    let bind_options = BindOptions {
+        reuse_port: options.reuse_port,
+        bind_device: options.bind_device.clone(),
+    };
+    // Borrowed by each attempt, the closure moves what it captures
+    let bind_options = &bind_options;
+    listen_in_port_range(port_max, port_min, laddr, options, |laddr| async move {
+        let conn = vnet.bind_with_options(laddr, bind_options).await?;
+        // Reserve the port the socket got, under `Net::Ifs` that is not the one asked
+        // for. A session that bound a port another one holds gives its socket up again
+        let bound = conn.local_addr()?;
+        let reservation = match &options.port_pool {
+            Some(pool) => match pool.reserve_bound(bound.port()) {
+                Ok(reservation) => Some(reservation),
+                Err(err) => {
+                    let _ = conn.close().await;
+                    return Err(err);
+                }
+            },
+            None => None,
+        };
+        let conn: Arc<dyn Conn + Send + Sync> = match reservation {
+            Some(reservation) => Arc::new(PooledConn::new(conn, reservation)),
+            None => conn,
+        };
+        let conn = if options.track_release {
+            Arc::new(TrackedConn::new(conn))
+        } else {
+            conn
+        };
+        Ok((conn, bound))
+    })
+    .await
+}
+
+/// Binds a TCP listener on a port of `[port_min, port_max]`, scanning the range the
+/// same way `listen_udp_in_port_range` does.
+pub async fn listen_tcp_in_port_range(
+    port_max: u16,
+    port_min: u16,
+    laddr: SocketAddr,
+    strategy: PortAllocationStrategy,
+) -> Result<TcpListener> {
+    let options = ListenOptions {
+        strategy,
+        ..Default::default()
+    };
+    listen_in_port_range(port_max, port_min, laddr, &options, |laddr| async move {
+        let listener = TcpListener::bind(laddr).await?;
+        let bound = listener.local_addr()?;
+        Ok((listener, bound))
+    })
+    .await
+}
+
+/// Returns the port of `[port_min, port_max]` a UDP socket could bind to, scanning the
+/// range the same way `listen_udp_in_port_range` does. The probing socket is closed
+/// before returning, so the port is free but not reserved.
+pub async fn probe_udp_port_in_range(
+    port_max: u16,
+    port_min: u16,
+    laddr: SocketAddr,
+    strategy: PortAllocationStrategy,
+) -> Result<u16> {
+    let options = ListenOptions {
+        strategy,
+        ..Default::default()
+    };
+    listen_in_port_range(port_max, port_min, laddr, &options, |laddr| async move {
+        let socket = tokio::net::UdpSocket::bind(laddr).await?;
+        let bound = socket.local_addr()?;
+        Ok((bound.port(), bound))
+    })
+    .await
+}
+
+/// Whether a failed bind may succeed when retried on the same port.
+fn is_transient_bind_error(err: &Error) -> bool {
+    let err = match err {
+        Error::Io(err) => &err.0,
+        Error::Util(util::Error::Io(err)) => &err.0,
+        _ => return false,
+    };
+    err.kind() == std::io::ErrorKind::AddrNotAvailable
+}
+
+/// Calls `listen` for `laddr`, retrying transient failures as `options` allow.
+async fn listen_with_retry<T, F, Fut>(
+    listen: &mut F,
+    laddr: SocketAddr,
+    options: &ListenOptions,
+) -> Result<T>
+where
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut retries = 0;
+    loop {
+        match listen(laddr).await {
+            Err(err) if retries < options.bind_retries && is_transient_bind_error(&err) => {
+                log::debug!("transient failure to listen {}, retrying: {}", laddr, err);
+                retries += 1;
+                tokio::time::sleep(options.bind_retry_delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Calls `listen` for the ports of `[port_min, port_max]` until one succeeds, starting
+/// at the port picked by the `options`' strategy and wrapping around at the end of the
+/// range. A zero bound stands for the lowest or highest port respectively. `listen`
+/// returns the listener along with the address it is actually bound to, which need not
+/// be the one asked for, e.g. under `Net::Ifs`.
+async fn listen_in_port_range<T, F, Fut>(
+    port_max: u16,
+    port_min: u16,
+    laddr: SocketAddr,
+    options: &ListenOptions,
+    mut listen: F,
+) -> Result<T>
+where
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<(T, SocketAddr)>>,
+{
     if laddr.port() != 0 || (port_min == 0 && port_max == 0) {
-        return Ok(vnet.bind(laddr, relay_port).await?);
+        if laddr.port() == 0 && options.forbid_ephemeral {
+            return Err(Error::ErrEphemeralPortForbidden);
+        }
+        let (listener, bound) = listen_with_retry(&mut listen, laddr, options).await?;
+        info!("Listening on {}", bound);
+        return Ok(listener);
     }
     let i = if port_min == 0 { 1 } else { port_min };
     let j = if port_max == 0 { 0xFFFF } else { port_max };
@@ -199,23 +1920,45 @@ pub async fn listen_udp_in_port_range(
         return Err(Error::ErrPort);
     }
 
-    let port_start = rand::random::<u16>() % (j - i + 1) + i;
+    let port_start = match options.strategy {
+        PortAllocationStrategy::Random => rand::random::<u16>() % (j - i + 1) + i,
+        PortAllocationStrategy::Sequential => i,
+    };
     let mut port_current = port_start;
     loop {
         let laddr = SocketAddr::new(laddr.ip(), port_current);
-        match vnet.bind(laddr, relay_port).await {
-            Ok(c) => return Ok(c),
+        let reserved = options
+            .port_pool
+            .as_ref()
+            .is_some_and(|pool| pool.is_reserved(port_current));
+        let result = if reserved {
+            Err(Error::ErrPortReserved(port_current))
+        } else {
+            listen_with_retry(&mut listen, laddr, options).await
+        };
+        match result {
+            Ok((listener, bound)) => {
+                info!("Listening on {} within port range [{}, {}]", bound, i, j);
+                return Ok(listener);
+            }
             Err(err) => log::debug!("failed to listen {}: {}", laddr, err),
         };
 
-        port_current += 1;
-        if port_current > j {
-            port_current = i;
-        }
+        // Wrap around explicitly, port 0 would bind an ephemeral port outside the range
+        port_current = match port_current.checked_add(1) {
+            Some(port) if port <= j => port,
+            _ => i,
+        };
         if port_current == port_start {
             break;
         }
     }
 
-    Err(Error::ErrPort)
+    Err(Error::ErrPortRangeExhausted(i, j))
+}
+
+/// Returns the address `conn` is bound to, e.g. to learn which port of a range
+/// `listen_udp_in_port_range` picked.
+pub fn bound_addr(conn: &Arc<dyn Conn + Send + Sync>) -> Result<SocketAddr> {
+    Ok(conn.local_addr()?)
 }
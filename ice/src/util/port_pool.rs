@@ -0,0 +1,174 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use util::vnet::net::Net;
+use util::Conn;
+
+use super::{listen_udp_in_port_range_with_options, ListenOptions};
+use crate::error::*;
+
+/// Owns a port range and hands its ports out to sessions, so agents running many
+/// sessions at once do not race each other for the same port. Ports stay reserved
+/// until the conn bound to them is dropped. Binding through a pool matters most with
+/// `reuse_port`, where the OS itself lets several sockets share a port.
+#[derive(Debug)]
+pub struct PortPool {
+    port_min: u16,
+    port_max: u16,
+    reserved: Mutex<HashSet<u16>>,
+}
+
+// Pools are shared by reference, two of them are only the same if they are one
+impl PartialEq for PortPool {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for PortPool {}
+
+impl PortPool {
+    /// Creates a pool owning `[port_min, port_max]`.
+    pub fn new(port_min: u16, port_max: u16) -> Result<Arc<Self>> {
+        if port_min == 0 || port_min > port_max {
+            return Err(Error::ErrPort);
+        }
+        Ok(Arc::new(PortPool {
+            port_min,
+            port_max,
+            reserved: Mutex::new(HashSet::new()),
+        }))
+    }
+
+    pub fn port_min(&self) -> u16 {
+        self.port_min
+    }
+
+    pub fn port_max(&self) -> u16 {
+        self.port_max
+    }
+
+    /// Returns whether `port` is currently handed out.
+    pub fn is_reserved(&self, port: u16) -> bool {
+        self.reserved.lock().unwrap().contains(&port)
+    }
+
+    /// Returns how many ports are currently handed out.
+    pub fn reserved_count(&self) -> usize {
+        self.reserved.lock().unwrap().len()
+    }
+
+    /// Reserves `port` until the returned reservation is dropped. Fails with
+    /// `ErrPortReserved` if it is already handed out and with `ErrPort` if it is
+    /// not part of the pool's range.
+    pub fn reserve(self: &Arc<Self>, port: u16) -> Result<PortReservation> {
+        if port < self.port_min || port > self.port_max {
+            return Err(Error::ErrPort);
+        }
+        self.reserve_bound(port)
+    }
+
+    /// Reserves `port`, which a socket was just bound to, until the returned reservation
+    /// is dropped. Unlike `reserve` the port may lie outside the pool's range: under
+    /// `Net::Ifs` the port bound is chosen for the relay mapping rather than taken from
+    /// the range, and the pool has to track the sockets that actually exist.
+    pub(crate) fn reserve_bound(self: &Arc<Self>, port: u16) -> Result<PortReservation> {
+        if !self.reserved.lock().unwrap().insert(port) {
+            return Err(Error::ErrPortReserved(port));
+        }
+        Ok(PortReservation {
+            pool: Arc::clone(self),
+            port,
+        })
+    }
+
+    /// Binds a UDP socket on a free port of the pool's range the way `options`
+    /// describe. The port returns to the pool once the conn is dropped.
+    pub async fn listen_udp(
+        self: &Arc<Self>,
+        vnet: &Arc<Net>,
+        laddr: SocketAddr,
+        options: &ListenOptions,
+    ) -> Result<Arc<dyn Conn + Send + Sync>> {
+        let options = ListenOptions {
+            port_pool: Some(Arc::clone(self)),
+            ..options.clone()
+        };
+        listen_udp_in_port_range_with_options(vnet, self.port_max, self.port_min, laddr, &options)
+            .await
+    }
+}
+
+/// A port taken from a `PortPool`, it is given back when this is dropped.
+#[derive(Debug)]
+pub struct PortReservation {
+    pool: Arc<PortPool>,
+    port: u16,
+}
+
+impl PortReservation {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for PortReservation {
+    fn drop(&mut self) {
+        self.pool.reserved.lock().unwrap().remove(&self.port);
+    }
+}
+
+/// A conn bound to a port of a `PortPool`, holding the port's reservation for as long
+/// as it lives.
+pub struct PooledConn {
+    conn: Arc<dyn Conn + Send + Sync>,
+    reservation: PortReservation,
+}
+
+impl PooledConn {
+    pub fn new(conn: Arc<dyn Conn + Send + Sync>, reservation: PortReservation) -> Self {
+        PooledConn { conn, reservation }
+    }
+
+    /// Returns the reserved port.
+    pub fn port(&self) -> u16 {
+        self.reservation.port()
+    }
+}
+
+#[async_trait]
+impl Conn for PooledConn {
+    async fn connect(&self, addr: SocketAddr) -> util::Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> util::Result<usize> {
+        self.conn.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> util::Result<(usize, SocketAddr)> {
+        self.conn.recv_from(buf).await
+    }
+
+    async fn send(&self, buf: &[u8]) -> util::Result<usize> {
+        self.conn.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> util::Result<usize> {
+        self.conn.send_to(buf, target).await
+    }
+
+    fn local_addr(&self) -> util::Result<SocketAddr> {
+        self.conn.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.conn.remote_addr()
+    }
+
+    async fn close(&self) -> util::Result<()> {
+        self.conn.close().await
+    }
+}
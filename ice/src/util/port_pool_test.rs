@@ -0,0 +1,94 @@
+use std::str::FromStr;
+
+use super::port_pool::*;
+use super::*;
+
+#[test]
+fn test_port_pool_reserve_and_release() -> Result<()> {
+    let pool = PortPool::new(5000, 5001)?;
+
+    let first = pool.reserve(5000)?;
+    assert_eq!(first.port(), 5000);
+    assert!(pool.is_reserved(5000));
+    assert_eq!(pool.reserve(5000).err(), Some(Error::ErrPortReserved(5000)));
+    assert_eq!(pool.reserve(5002).err(), Some(Error::ErrPort));
+
+    drop(first);
+    assert!(!pool.is_reserved(5000));
+    assert_eq!(pool.reserved_count(), 0);
+
+    assert_eq!(PortPool::new(0, 10).err(), Some(Error::ErrPort));
+    assert_eq!(PortPool::new(10, 9).err(), Some(Error::ErrPort));
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_port_pool_exhausts_and_releases() -> Result<()> {
+    use tokio::net::UdpSocket;
+
+    // With reuse_port the OS would hand out the same port twice, only the pool
+    // keeps the sessions apart
+    let port_min = UdpSocket::bind("127.0.0.1:0").await?.local_addr()?.port();
+    let port_max = port_min.saturating_add(1);
+    let pool = PortPool::new(port_min, port_max)?;
+    let vnet = Arc::new(Net::new(None));
+    let laddr = SocketAddr::from_str("127.0.0.1:0")?;
+    let options = ListenOptions {
+        strategy: PortAllocationStrategy::Sequential,
+        reuse_port: true,
+        ..Default::default()
+    };
+
+    let first = pool.listen_udp(&vnet, laddr, &options).await?;
+    let second = pool.listen_udp(&vnet, laddr, &options).await?;
+    assert_eq!(first.local_addr()?.port(), port_min);
+    assert_eq!(second.local_addr()?.port(), port_max);
+    assert_eq!(pool.reserved_count(), 2);
+
+    let result = pool.listen_udp(&vnet, laddr, &options).await;
+    assert_eq!(
+        result.err(),
+        Some(Error::ErrPortRangeExhausted(port_min, port_max))
+    );
+
+    drop(first);
+    assert!(!pool.is_reserved(port_min));
+    let third = pool.listen_udp(&vnet, laddr, &options).await?;
+    assert_eq!(third.local_addr()?.port(), port_min);
+    drop(second);
+    drop(third);
+    assert_eq!(pool.reserved_count(), 0);
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_port_pool_reserves_bound_port() -> Result<()> {
+    use tokio::net::UdpSocket;
+
+    // Without reuse_port the socket is bound to the port of its relay mapping rather
+    // than the one tried from the range, the pool holds the former
+    let port_min = UdpSocket::bind("127.0.0.1:0").await?.local_addr()?.port();
+    let pool = PortPool::new(port_min, port_min)?;
+    let vnet = Arc::new(Net::new(None));
+    let laddr = SocketAddr::from_str("127.0.0.1:0")?;
+
+    let conn = pool
+        .listen_udp(&vnet, laddr, &ListenOptions::default())
+        .await?;
+    let bound = conn.local_addr()?.port();
+    assert!(pool.is_reserved(bound));
+    assert_eq!(pool.reserved_count(), 1);
+    if bound != port_min {
+        assert!(!pool.is_reserved(port_min));
+    }
+
+    drop(conn);
+    assert!(!pool.is_reserved(bound));
+    assert_eq!(pool.reserved_count(), 0);
+
+    Ok(())
+}
@@ -0,0 +1,219 @@
+use log::{log_enabled, trace, Level};
+use stun::message::*;
+
+use std::fmt::Write;
+use std::net::SocketAddr;
+
+use crate::agent::agent_config::RelayConfig;
+use crate::agent::agent_external::{
+    decode_open_socket, insert_session_id, relay_header_lens, split_relay_header, split_send_info,
+    RelayPacketType, SendInfo, NO_SESSION_ID, SESSION_ID_LEN,
+};
+use crate::error::*;
+
+/// A datagram exchanged with the relay, borrowing its payload from the buffer it
+/// was decoded from.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RelayFrame<'a> {
+    /// A request handed to the relay for forwarding.
+    SendInfo {
+        send_info: SendInfo,
+        payload: &'a [u8],
+    },
+    /// A packet the relay received on behalf of one of our sockets.
+    RecvInfo {
+        recv_info: SendInfo,
+        payload: &'a [u8],
+    },
+    /// Registers a socket with the relay.
+    OpenSocket(SocketAddr),
+    /// Confirms the registration of a socket.
+    OpenSocketAck(SocketAddr),
+    /// A STUN message that did not go through the relay.
+    RawStun(&'a [u8]),
+}
+
+/// Frames the STUN message `msg` for the relay to forward as described by `send_info`.
+pub fn encode_stun_request(send_info: SendInfo, msg: &Message) -> Result<Vec<u8>> {
+    let mut frame = send_info.encode()?;
+    frame.extend_from_slice(&msg.raw);
+    Ok(frame)
+}
+
+/// Frames `payload` for the relay to forward from the socket bound to `local_addr` to
+/// `to` the way `relay_config` describes: in its frame version, tagged with its session
+/// id and announcing the advertised address in place of `local_addr`. Every datagram
+/// sent through the relay goes through here; payloads above the MTU of the relay are
+/// refused with `ErrRelayPayloadTooLarge`.
+pub fn encode_relay_frame(
+    relay_config: &RelayConfig,
+    local_addr: SocketAddr,
+    to: SocketAddr,
+    payload: &[u8],
+) -> Result<Vec<u8>> {
+    if payload.len() > relay_config.mtu {
+        return Err(Error::ErrRelayPayloadTooLarge);
+    }
+    let send_info = SendInfo {
+        from: relay_config.advertised_addr_for(local_addr),
+        to,
+    };
+    let mut frame = send_info.encode_versioned(relay_config.frame_version)?;
+    insert_session_id(&mut frame, relay_config.session_id);
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// Splits a datagram received from the relay, or directly from a STUN server, into
+/// its header and payload.
+pub fn decode_relay_frame(buf: &[u8]) -> Result<RelayFrame<'_>> {
+    trace_relay_frame("incoming", buf);
+    match buf
+        .first()
+        .map(|&packet_type| RelayPacketType::try_from(packet_type))
+    {
+        Some(Ok(RelayPacketType::SendInfo)) => {
+            let (send_info, payload) = split_send_info(buf)?;
+            Ok(RelayFrame::SendInfo { send_info, payload })
+        }
+        Some(Ok(RelayPacketType::RecvInfo)) => {
+            let (recv_info, payload) = split_send_info(buf)?;
+            Ok(RelayFrame::RecvInfo { recv_info, payload })
+        }
+        Some(Ok(RelayPacketType::OpenSocket)) => {
+            Ok(RelayFrame::OpenSocket(decode_open_socket(buf)?))
+        }
+        Some(Ok(RelayPacketType::OpenSocketAck)) => {
+            Ok(RelayFrame::OpenSocketAck(decode_open_socket(buf)?))
+        }
+        // STUN messages start with two zero bits, so they never look like a relay header
+        _ if is_message(buf) => Ok(RelayFrame::RawStun(buf)),
+        _ => Err(Error::ErrUnknownRelayPacketType),
+    }
+}
+
+/// Removes the session id from the relay frame at the start of `buf`, moving the rest
+/// of `buf` forward, and returns the length that is left. Fails with
+/// `ErrRelaySessionMismatch` if the frame was tagged for another session. Anything
+/// but a relay frame, and anything with `NO_SESSION_ID`, is left as it is.
+pub fn strip_session_id(buf: &mut [u8], session_id: u16) -> Result<usize> {
+    let is_relay_frame = buf
+        .first()
+        .is_some_and(|&packet_type| RelayPacketType::try_from(packet_type).is_ok());
+    if session_id == NO_SESSION_ID || !is_relay_frame {
+        return Ok(buf.len());
+    }
+    let tagged = buf
+        .get(1..1 + SESSION_ID_LEN)
+        .ok_or(Error::ErrTruncatedRelayFrame)?;
+    let tagged = u16::from_be_bytes([tagged[0], tagged[1]]);
+    if tagged != session_id {
+        return Err(Error::ErrRelaySessionMismatch(session_id, tagged));
+    }
+    buf.copy_within(1 + SESSION_ID_LEN.., 1);
+    Ok(buf.len() - SESSION_ID_LEN)
+}
+
+/// Removes the session id from each relay frame of the datagram `buf` like
+/// `strip_session_id`, and returns the length that is left. Raw STUN messages carry
+/// no session id, and with `NO_SESSION_ID` nothing is removed.
+pub fn strip_session_ids(buf: &mut [u8], session_id: u16) -> Result<usize> {
+    if session_id == NO_SESSION_ID {
+        return Ok(buf.len());
+    }
+    let (mut pos, mut end) = (0, buf.len());
+    while pos < end {
+        end = pos + strip_session_id(&mut buf[pos..end], session_id)?;
+        pos += RelayFrameReader::new(&buf[pos..end]).next_frame_len()?;
+    }
+    Ok(end)
+}
+
+/// Iterates the relay frames of a datagram the relay coalesced from several
+/// packets. A frame ends after the header announced by its length byte plus the
+/// STUN message that follows it, whose length is taken from the STUN header.
+/// Iteration stops at the first frame that cannot be decoded.
+#[derive(Debug)]
+pub struct RelayFrameReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> RelayFrameReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        RelayFrameReader { buf }
+    }
+
+    /// Returns the length of the first frame in `self.buf`.
+    fn next_frame_len(&self) -> Result<usize> {
+        let buf = self.buf;
+        match buf
+            .first()
+            .map(|&packet_type| RelayPacketType::try_from(packet_type))
+        {
+            Some(Ok(RelayPacketType::SendInfo | RelayPacketType::RecvInfo)) => {
+                let (_, payload) = split_relay_header(buf)?;
+                Ok(buf.len() - payload.len() + stun_message_len(payload))
+            }
+            Some(Ok(RelayPacketType::OpenSocket | RelayPacketType::OpenSocketAck)) => {
+                let (_, header_len, len) = relay_header_lens(buf)?;
+                Ok((header_len + len).min(buf.len()))
+            }
+            _ => Ok(stun_message_len(buf)),
+        }
+    }
+}
+
+impl<'a> Iterator for RelayFrameReader<'a> {
+    type Item = Result<RelayFrame<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let frame = self
+            .next_frame_len()
+            .and_then(|len| {
+                let (frame, rest) = self.buf.split_at(len);
+                self.buf = rest;
+                decode_relay_frame(frame)
+            })
+            .inspect_err(|_| self.buf = &[]);
+        Some(frame)
+    }
+}
+
+/// Returns the length of the STUN message at the start of `buf`. Anything that is
+/// not a STUN message, such as relayed application data, is taken to span the rest
+/// of `buf`, as it did before frames could be coalesced.
+fn stun_message_len(buf: &[u8]) -> usize {
+    if !is_message(buf) {
+        return buf.len();
+    }
+    let body_len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    (MESSAGE_HEADER_SIZE + body_len).min(buf.len())
+}
+
+/// Logs `buf` as hex at trace level, along with the packet type it starts with.
+/// Nothing is formatted unless trace logging is enabled.
+pub(crate) fn trace_relay_frame(direction: &str, buf: &[u8]) {
+    if log_enabled!(Level::Trace) {
+        trace!("{}", describe_relay_frame(direction, buf));
+    }
+}
+
+/// Formats the line logged by `trace_relay_frame`.
+pub(crate) fn describe_relay_frame(direction: &str, buf: &[u8]) -> String {
+    let packet_type = match buf.first() {
+        Some(&byte) => match RelayPacketType::try_from(byte) {
+            Ok(packet_type) => format!("{packet_type:?} (0x{byte:02x})"),
+            Err(_) if is_message(buf) => "raw STUN".to_owned(),
+            Err(_) => format!("unknown (0x{byte:02x})"),
+        },
+        None => "empty".to_owned(),
+    };
+    let mut line = format!("{direction} {packet_type} frame, {} bytes:", buf.len());
+    for byte in buf {
+        let _ = write!(line, " {byte:02x}");
+    }
+    line
+}
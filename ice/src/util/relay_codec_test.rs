@@ -0,0 +1,347 @@
+use std::str::FromStr;
+
+use super::relay_codec::*;
+use super::test_support::*;
+use super::*;
+use crate::agent::agent_external::{
+    encode_open_socket, insert_session_id, relay_payload_offset, RelayFrameVersion,
+    RelayPacketType, SendInfo, NO_SESSION_ID, SESSION_ID_LEN,
+};
+
+fn binding_request() -> Result<Message> {
+    let mut msg = Message::new();
+    msg.build(&[Box::new(BINDING_REQUEST), Box::new(TransactionId::new())])?;
+    Ok(msg)
+}
+
+#[test]
+fn test_send_info_frame_roundtrip() -> Result<()> {
+    let msg = binding_request()?;
+    for (from, to) in [
+        ("10.0.0.1:5000", "1.2.3.4:3478"),
+        ("10.0.0.1:5000", "[2001:db8::1]:3478"),
+        ("[fe80::1]:5000", "1.2.3.4:3478"),
+        ("[fe80::1]:5000", "[2001:db8::1]:3478"),
+    ] {
+        let send_info = SendInfo {
+            from: SocketAddr::from_str(from)?,
+            to: SocketAddr::from_str(to)?,
+        };
+
+        let frame = encode_stun_request(send_info, &msg)?;
+        assert_eq!(frame[0], RelayPacketType::SendInfo.as_u8());
+        assert_eq!(
+            decode_relay_frame(&frame)?,
+            RelayFrame::SendInfo {
+                send_info,
+                payload: &msg.raw,
+            }
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_encode_relay_frame_respects_mtu() -> Result<()> {
+    let relay_config = RelayConfig {
+        mtu: 4,
+        ..Default::default()
+    };
+    let from = SocketAddr::from_str("10.0.0.1:5000")?;
+    let to = SocketAddr::from_str("1.2.3.4:3478")?;
+
+    let frame = encode_relay_frame(&relay_config, from, to, b"ping")?;
+    assert_eq!(
+        decode_relay_frame(&frame)?,
+        RelayFrame::SendInfo {
+            send_info: SendInfo { from, to },
+            payload: b"ping",
+        }
+    );
+    assert_eq!(
+        encode_relay_frame(&relay_config, from, to, b"pings"),
+        Err(Error::ErrRelayPayloadTooLarge)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_recv_info_frame_roundtrip() -> Result<()> {
+    let msg = binding_request()?;
+    let recv_info = SendInfo {
+        from: SocketAddr::from_str("1.2.3.4:3478")?,
+        to: SocketAddr::from_str("10.0.0.1:5000")?,
+    };
+
+    let frame = recv_info_frame(recv_info, &msg.raw);
+    assert_eq!(
+        decode_relay_frame(&frame)?,
+        RelayFrame::RecvInfo {
+            recv_info,
+            payload: &msg.raw,
+        }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_raw_stun_frame() -> Result<()> {
+    let msg = binding_request()?;
+
+    assert_eq!(decode_relay_frame(&msg.raw)?, RelayFrame::RawStun(&msg.raw));
+
+    Ok(())
+}
+
+#[test]
+fn test_open_socket_frame_roundtrip() -> Result<()> {
+    for addr in ["10.0.0.1:5000", "[fe80::1]:5000"] {
+        let addr = SocketAddr::from_str(addr)?;
+
+        let open = encode_open_socket(RelayPacketType::OpenSocket, addr)?;
+        assert_eq!(decode_relay_frame(&open)?, RelayFrame::OpenSocket(addr));
+
+        let ack = encode_open_socket(RelayPacketType::OpenSocketAck, addr)?;
+        assert_eq!(decode_relay_frame(&ack)?, RelayFrame::OpenSocketAck(addr));
+        assert_eq!(
+            decode_relay_frame(&ack[..ack.len() - 1]),
+            Err(Error::ErrTruncatedRelayFrame)
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_decode_relay_frame_invalid() -> Result<()> {
+    assert_eq!(
+        decode_relay_frame(&[]),
+        Err(Error::ErrUnknownRelayPacketType)
+    );
+    assert_eq!(
+        decode_relay_frame(&[0xFF; 24]),
+        Err(Error::ErrUnknownRelayPacketType)
+    );
+
+    let send_info = SendInfo {
+        from: SocketAddr::from_str("10.0.0.1:5000")?,
+        to: SocketAddr::from_str("1.2.3.4:3478")?,
+    };
+    let frame = encode_stun_request(send_info, &binding_request()?)?;
+    assert_eq!(
+        decode_relay_frame(&frame[..10]),
+        Err(Error::ErrTruncatedRelayFrame)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_relay_frame_reader_coalesced_recv_info() -> Result<()> {
+    let first = binding_request()?;
+    let second = binding_request()?;
+    let recv_info = SendInfo {
+        from: SocketAddr::from_str("1.2.3.4:3478")?,
+        to: SocketAddr::from_str("10.0.0.1:5000")?,
+    };
+    let other_info = SendInfo {
+        from: SocketAddr::from_str("[2001:db8::1]:3478")?,
+        to: SocketAddr::from_str("[fe80::1]:5000")?,
+    };
+
+    let mut buf = recv_info_frame(recv_info, &first.raw);
+    buf.extend(recv_info_frame(other_info, &second.raw));
+
+    let frames = RelayFrameReader::new(&buf).collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        frames,
+        vec![
+            RelayFrame::RecvInfo {
+                recv_info,
+                payload: &first.raw,
+            },
+            RelayFrame::RecvInfo {
+                recv_info: other_info,
+                payload: &second.raw,
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_strip_session_ids_roundtrip() -> Result<()> {
+    let msg = binding_request()?;
+    let recv_info = SendInfo {
+        from: SocketAddr::from_str("1.2.3.4:3478")?,
+        to: SocketAddr::from_str("10.0.0.1:5000")?,
+    };
+    let plain = recv_info_frame(recv_info, &msg.raw);
+    let ack = encode_open_socket(RelayPacketType::OpenSocketAck, recv_info.to)?;
+
+    // Coalesced frames each carry the session id, raw STUN never does
+    let mut buf = vec![];
+    for frame in [&plain, &ack, &plain] {
+        let mut frame = frame.clone();
+        insert_session_id(&mut frame, 7);
+        buf.extend(frame);
+    }
+    buf.extend_from_slice(&msg.raw);
+    let expected = [&plain[..], &ack, &plain, &msg.raw].concat();
+
+    let mut untouched = buf.clone();
+    assert_eq!(strip_session_ids(&mut untouched, NO_SESSION_ID)?, buf.len());
+    assert_eq!(untouched, buf);
+
+    let n = strip_session_ids(&mut buf, 7)?;
+    assert_eq!(buf[..n], expected);
+    assert_eq!(RelayFrameReader::new(&buf[..n]).count(), 4);
+
+    Ok(())
+}
+
+#[test]
+fn test_strip_session_ids_rejects_other_sessions() -> Result<()> {
+    let mut frame = encode_open_socket(
+        RelayPacketType::OpenSocketAck,
+        SocketAddr::from_str("10.0.0.1:5000")?,
+    )?;
+    insert_session_id(&mut frame, 8);
+
+    assert_eq!(
+        strip_session_ids(&mut frame.clone(), 7),
+        Err(Error::ErrRelaySessionMismatch(7, 8))
+    );
+    // An untagged frame from a relay expecting sessions reads as a foreign one
+    let mut untagged = frame.clone();
+    untagged.drain(1..1 + SESSION_ID_LEN);
+    assert!(strip_session_ids(&mut untagged, 7).is_err());
+    assert_eq!(
+        strip_session_ids(&mut frame[..2], 8),
+        Err(Error::ErrTruncatedRelayFrame)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_relay_frame_reader_mixed_frames() -> Result<()> {
+    let msg = binding_request()?;
+    let addr = SocketAddr::from_str("10.0.0.1:5000")?;
+
+    let mut buf = encode_open_socket(RelayPacketType::OpenSocketAck, addr)?;
+    buf.extend_from_slice(&msg.raw);
+    buf.extend_from_slice(&msg.raw);
+
+    let frames = RelayFrameReader::new(&buf).collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        frames,
+        vec![
+            RelayFrame::OpenSocketAck(addr),
+            RelayFrame::RawStun(&msg.raw),
+            RelayFrame::RawStun(&msg.raw),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_relay_frame_reader_stops_at_invalid_frame() -> Result<()> {
+    let msg = binding_request()?;
+    let mut buf = msg.raw.clone();
+    buf.extend_from_slice(&[0xFF; 24]);
+    buf.extend_from_slice(&msg.raw);
+
+    let mut reader = RelayFrameReader::new(&buf);
+    assert_eq!(reader.next(), Some(Ok(RelayFrame::RawStun(&msg.raw))));
+    assert_eq!(reader.next(), Some(Err(Error::ErrUnknownRelayPacketType)));
+    assert_eq!(reader.next(), None);
+
+    Ok(())
+}
+
+#[test]
+fn test_relay_frame_versions_roundtrip() -> Result<()> {
+    let msg = binding_request()?;
+    let recv_info = SendInfo {
+        from: SocketAddr::from_str("1.2.3.4:3478")?,
+        to: SocketAddr::from_str("10.0.0.1:5000")?,
+    };
+
+    // The relay may coalesce frames of both versions into one datagram
+    let mut buf = vec![];
+    for version in [RelayFrameVersion::V1, RelayFrameVersion::V2] {
+        let mut frame = recv_info.encode_as(RelayPacketType::RecvInfo, version)?;
+        frame.extend_from_slice(&msg.raw);
+        assert_eq!(
+            decode_relay_frame(&frame)?,
+            RelayFrame::RecvInfo {
+                recv_info,
+                payload: &msg.raw,
+            },
+            "{version:?}"
+        );
+        buf.extend(frame);
+    }
+
+    let frames = RelayFrameReader::new(&buf).collect::<Result<Vec<_>>>()?;
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0], frames[1]);
+
+    Ok(())
+}
+
+#[test]
+fn test_relay_frame_length_exceeds_datagram() -> Result<()> {
+    let send_info = SendInfo {
+        from: SocketAddr::from_str("1.2.3.4:3478")?,
+        to: SocketAddr::from_str("10.0.0.1:5000")?,
+    };
+    let mut frame = send_info.encode_as(RelayPacketType::RecvInfo, RelayFrameVersion::V1)?;
+    assert_eq!(relay_payload_offset(frame[1]), frame.len());
+
+    // The header claims more bytes than the datagram carries
+    frame[1] += 1;
+    assert_eq!(
+        decode_relay_frame(&frame),
+        Err(Error::ErrTruncatedRelayFrame)
+    );
+    assert_eq!(
+        RelayFrameReader::new(&frame).next(),
+        Some(Err(Error::ErrTruncatedRelayFrame))
+    );
+    assert_eq!(
+        decode_relay_frame(&frame[..1]),
+        Err(Error::ErrTruncatedRelayFrame)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_describe_relay_frame() -> Result<()> {
+    let recv_info = SendInfo {
+        from: SocketAddr::from_str("1.2.3.4:3478")?,
+        to: SocketAddr::from_str("10.0.0.1:5000")?,
+    };
+    let frame = recv_info.encode_as(RelayPacketType::RecvInfo, RelayFrameVersion::V1)?;
+    assert_eq!(
+        describe_relay_frame("incoming", &frame),
+        "incoming RecvInfo (0xcc) frame, 16 bytes: \
+         cc 0e 04 01 02 03 04 0d 96 04 0a 00 00 01 13 88"
+    );
+
+    assert_eq!(
+        describe_relay_frame("incoming", &[0x42, 0x00]),
+        "incoming unknown (0x42) frame, 2 bytes: 42 00"
+    );
+    let msg = binding_request()?;
+    assert!(describe_relay_frame("outgoing", &msg.raw).starts_with("outgoing raw STUN frame"));
+
+    Ok(())
+}
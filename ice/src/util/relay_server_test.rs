@@ -0,0 +1,243 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+use super::relay_codec::*;
+use super::test_support::*;
+use super::*;
+use crate::agent::agent_external::{encode_open_socket, RelayPacketType, SendInfo};
+
+/// Answers the STUN request the relay forwards along the given route, or drops it
+/// when returning `None`.
+pub(crate) type StunResponder = Box<dyn Fn(&Message, SendInfo) -> Option<Message> + Send + Sync>;
+
+/// Stands in for quicheperf on loopback: acknowledges `OpenSocket` frames, hands the
+/// STUN request of every `SendInfo` frame to a responder instead of the network, and
+/// wraps the answer in a `RecvInfo` frame, or passes it on as is when started with
+/// `start_unframed`. Stops when dropped.
+pub(crate) struct TestRelay {
+    addr: SocketAddr,
+    forwarded: Arc<Mutex<Vec<SendInfo>>>,
+    handle: JoinHandle<()>,
+}
+
+impl TestRelay {
+    pub(crate) async fn start(responder: StunResponder) -> Result<Self> {
+        Self::spawn(responder, true).await
+    }
+
+    /// Like `start`, but passes the answers on without the `RecvInfo` framing.
+    pub(crate) async fn start_unframed(responder: StunResponder) -> Result<Self> {
+        Self::spawn(responder, false).await
+    }
+
+    async fn spawn(responder: StunResponder, framed: bool) -> Result<Self> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = socket.local_addr()?;
+        let forwarded = Arc::new(Mutex::new(vec![]));
+        let handle = tokio::spawn(serve(socket, responder, framed, Arc::clone(&forwarded)));
+        Ok(TestRelay {
+            addr,
+            forwarded,
+            handle,
+        })
+    }
+
+    /// Returns a config sending STUN requests through this relay.
+    pub(crate) fn relay_config(&self) -> RelayConfig {
+        RelayConfig {
+            relay_addr: self.addr,
+            ..Default::default()
+        }
+    }
+
+    /// The routes of the requests forwarded so far.
+    pub(crate) fn forwarded(&self) -> Vec<SendInfo> {
+        self.forwarded.lock().unwrap().clone()
+    }
+}
+
+impl Drop for TestRelay {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn serve(
+    socket: UdpSocket,
+    responder: StunResponder,
+    framed: bool,
+    forwarded: Arc<Mutex<Vec<SendInfo>>>,
+) {
+    let mut bs = [0u8; 1500];
+    while let Ok((n, src)) = socket.recv_from(&mut bs).await {
+        let reply = match decode_relay_frame(&bs[..n]) {
+            Ok(RelayFrame::OpenSocket(addr)) => {
+                encode_open_socket(RelayPacketType::OpenSocketAck, addr).ok()
+            }
+            Ok(RelayFrame::SendInfo { send_info, payload }) => {
+                forwarded.lock().unwrap().push(send_info);
+                let mut request = Message::new();
+                request.raw = payload.to_vec();
+                request
+                    .decode()
+                    .ok()
+                    .and_then(|()| responder(&request, send_info))
+                    .map(|response| {
+                        if framed {
+                            let recv_info = SendInfo {
+                                from: send_info.to,
+                                to: send_info.from,
+                            };
+                            recv_info_frame(recv_info, &response.raw)
+                        } else {
+                            response.raw
+                        }
+                    })
+            }
+            _ => None,
+        };
+        if let Some(reply) = reply {
+            let _ = socket.send_to(&reply, src).await;
+        }
+    }
+}
+
+/// Answers every binding request with a success reporting `mapped`.
+pub(crate) fn binding_responder(mapped: SocketAddr) -> StunResponder {
+    Box::new(move |request, _| Some(binding_success_to(request, mapped)))
+}
+
+#[tokio::test]
+async fn test_relay_binding_round_trip() -> Result<()> {
+    let mapped = SocketAddr::from_str("203.0.113.7:40000")?;
+    let server_addr = SocketAddr::from_str("192.0.2.1:3478")?;
+    let relay = TestRelay::start(binding_responder(mapped)).await?;
+    let relay_config = relay.relay_config();
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let local_addr = conn.local_addr()?;
+
+    open_relay_socket(&conn, &relay_config, None).await?;
+    let result = get_binding_result(
+        &conn,
+        server_addr,
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+        None,
+    )
+    .await?;
+
+    assert_eq!(result.mapped, mapped);
+    assert_eq!(result.local_addr, local_addr);
+    assert_eq!(
+        relay.forwarded(),
+        vec![SendInfo {
+            from: local_addr,
+            to: server_addr,
+        }]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_unframed_binding_round_trip() -> Result<()> {
+    let mapped = SocketAddr::from_str("203.0.113.7:40000")?;
+    let server_addr = SocketAddr::from_str("192.0.2.1:3478")?;
+    let relay = TestRelay::start_unframed(binding_responder(mapped)).await?;
+    let relay_config = relay.relay_config();
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+
+    // The answer arrives from the relay rather than the server, without a RecvInfo
+    // telling which server it is from
+    let response = stun_request(
+        &conn,
+        server_addr,
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+        None,
+    )
+    .await?;
+
+    assert_eq!(response.from, relay_config.relay_addr);
+    let mut xor_addr = XorMappedAddress::default();
+    xor_addr.get_from(&response.message)?;
+    assert_eq!(SocketAddr::new(xor_addr.ip, xor_addr.port), mapped);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_was_relayed() -> Result<()> {
+    let mapped = SocketAddr::from_str("203.0.113.7:40000")?;
+    let server_addr = SocketAddr::from_str("192.0.2.1:3478")?;
+    for (relay, was_relayed) in [
+        (TestRelay::start(binding_responder(mapped)).await?, true),
+        (
+            TestRelay::start_unframed(binding_responder(mapped)).await?,
+            false,
+        ),
+    ] {
+        let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let response = stun_request(
+            &conn,
+            server_addr,
+            Duration::from_secs(1),
+            &relay.relay_config(),
+            None,
+            None,
+            None,
+        )
+        .await?;
+        assert_eq!(response.was_relayed, was_relayed);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_records_observed_mappings() -> Result<()> {
+    let mapped = SocketAddr::from_str("203.0.113.7:40000")?;
+    let server_addr = SocketAddr::from_str("192.0.2.1:3478")?;
+    for (relay, recorded) in [
+        (TestRelay::start(binding_responder(mapped)).await?, true),
+        // Without a RecvInfo there is no telling which server saw the mapping
+        (
+            TestRelay::start_unframed(binding_responder(mapped)).await?,
+            false,
+        ),
+    ] {
+        let observed_mappings = Arc::new(ObservedMappings::default());
+        let relay_config = RelayConfig {
+            observed_mappings: Some(Arc::clone(&observed_mappings)),
+            ..relay.relay_config()
+        };
+        let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        get_binding_result(
+            &conn,
+            server_addr,
+            Duration::from_secs(1),
+            &relay_config,
+            None,
+            None,
+            None,
+        )
+        .await?;
+
+        let expected = if recorded {
+            HashMap::from([(server_addr, mapped)])
+        } else {
+            HashMap::new()
+        };
+        assert_eq!(observed_mappings.snapshot(), expected);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use util::Conn;
+
+/// The datagram path `stun_request` talks to the relay, or the STUN server directly,
+/// over. The loopback socket implements it through `Conn`, tests substitute a mock.
+#[async_trait]
+pub trait RelayTransport: Send + Sync {
+    /// The address the transport's socket is bound to, announced to the relay as the
+    /// source of relayed requests.
+    fn local_addr(&self) -> util::Result<SocketAddr>;
+
+    /// Sends one frame to `target`, the relay or the STUN server.
+    async fn send_frame(&self, frame: &[u8], target: SocketAddr) -> util::Result<usize>;
+
+    /// Receives the next datagram, which may hold several coalesced relay frames.
+    async fn recv_frame(&self, buf: &mut [u8]) -> util::Result<(usize, SocketAddr)>;
+
+    /// Bounds how long each following `recv_frame` waits, `None` waits forever. A receive
+    /// that runs out of time fails with a `TimedOut` or `WouldBlock` I/O error, like
+    /// `std::net::UdpSocket::set_read_timeout`. Returns whether the transport supports
+    /// this, otherwise `stun_request` wraps every receive in a timer of its own.
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> bool {
+        false
+    }
+}
+
+/// Whether `err` is a receive running out of the time set by `set_read_timeout`.
+pub fn is_read_timeout(err: &util::Error) -> bool {
+    match err {
+        util::Error::Io(err) => matches!(
+            err.0.kind(),
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+        ),
+        _ => false,
+    }
+}
+
+#[async_trait]
+impl RelayTransport for Arc<dyn Conn + Send + Sync> {
+    fn local_addr(&self) -> util::Result<SocketAddr> {
+        Conn::local_addr(self.as_ref())
+    }
+
+    async fn send_frame(&self, frame: &[u8], target: SocketAddr) -> util::Result<usize> {
+        self.send_to(frame, target).await
+    }
+
+    async fn recv_frame(&self, buf: &mut [u8]) -> util::Result<(usize, SocketAddr)> {
+        self.recv_from(buf).await
+    }
+}
@@ -0,0 +1,303 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, Mutex};
+
+use super::relay_transport::*;
+use super::test_support::*;
+use super::*;
+use crate::agent::agent_config::RelayMode;
+
+type Responder = Box<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+/// Answers every frame sent to it with whatever `responder` makes of it, as if the
+/// answer came back from the frame's target.
+struct MockTransport {
+    local_addr: SocketAddr,
+    responder: Responder,
+    sent: Mutex<Vec<SocketAddr>>,
+    resp_tx: mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>,
+    resp_rx: Mutex<mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>>,
+}
+
+impl MockTransport {
+    fn new(local_addr: SocketAddr, responder: Responder) -> Self {
+        let (resp_tx, resp_rx) = mpsc::unbounded_channel();
+        MockTransport {
+            local_addr,
+            responder,
+            sent: Mutex::new(vec![]),
+            resp_tx,
+            resp_rx: Mutex::new(resp_rx),
+        }
+    }
+}
+
+#[async_trait]
+impl RelayTransport for MockTransport {
+    fn local_addr(&self) -> util::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    async fn send_frame(&self, frame: &[u8], target: SocketAddr) -> util::Result<usize> {
+        self.sent.lock().await.push(target);
+        let _ = self.resp_tx.send(((self.responder)(frame), target));
+        Ok(frame.len())
+    }
+
+    async fn recv_frame(&self, buf: &mut [u8]) -> util::Result<(usize, SocketAddr)> {
+        let (resp, from) = self
+            .resp_rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(util::Error::ErrClosedListener)?;
+        buf[..resp.len()].copy_from_slice(&resp);
+        Ok((resp.len(), from))
+    }
+}
+
+#[tokio::test]
+async fn test_stun_request_through_mock_transport() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let server_addr = SocketAddr::from_str("1.2.3.4:3478")?;
+    let transport = MockTransport::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| binding_success(req, mapped).raw),
+    );
+    let relay_config = RelayConfig {
+        mode: RelayMode::Direct,
+        ..Default::default()
+    };
+
+    let resp = stun_request(
+        &transport,
+        server_addr,
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(resp.from, server_addr);
+    assert!(!resp.was_relayed);
+    let mut addr = XorMappedAddress::default();
+    addr.get_from(&resp.message)?;
+    assert_eq!(SocketAddr::new(addr.ip, addr.port), mapped);
+    assert_eq!(*transport.sent.lock().await, vec![server_addr]);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_relayed_stun_request_through_mock_transport() -> Result<()> {
+    let local_addr = SocketAddr::from_str("192.168.0.2:5000")?;
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let server_addr = SocketAddr::from_str("1.2.3.4:3478")?;
+    // Plays the relay, which strips the SendInfo header and frames the answer
+    let transport = MockTransport::new(
+        local_addr,
+        Box::new(move |req| relayed_binding_success(req, mapped)),
+    );
+    let relay_config = RelayConfig::default();
+
+    let resp = stun_request(
+        &transport,
+        server_addr,
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(resp.from, server_addr);
+    assert_eq!(resp.local_addr, local_addr);
+    assert!(resp.was_relayed);
+    assert_eq!(
+        *transport.sent.lock().await,
+        vec![relay_config.relay_addr_for(local_addr)]
+    );
+
+    Ok(())
+}
+
+/// Never answers, but honors read timeouts the way a socket with SO_RCVTIMEO would.
+struct TimeoutTransport {
+    timeouts: std::sync::Mutex<Vec<Option<Duration>>>,
+}
+
+#[async_trait]
+impl RelayTransport for TimeoutTransport {
+    fn local_addr(&self) -> util::Result<SocketAddr> {
+        Ok(SocketAddr::from_str("192.168.0.2:5000").unwrap())
+    }
+
+    async fn send_frame(&self, frame: &[u8], _target: SocketAddr) -> util::Result<usize> {
+        Ok(frame.len())
+    }
+
+    async fn recv_frame(&self, _buf: &mut [u8]) -> util::Result<(usize, SocketAddr)> {
+        let timeout = *self.timeouts.lock().unwrap().last().unwrap();
+        tokio::time::sleep(timeout.unwrap()).await;
+        Err(std::io::Error::from(std::io::ErrorKind::WouldBlock).into())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> bool {
+        self.timeouts.lock().unwrap().push(timeout);
+        true
+    }
+}
+
+#[tokio::test]
+async fn test_stun_request_uses_transport_read_timeout() -> Result<()> {
+    let transport = TimeoutTransport {
+        timeouts: std::sync::Mutex::new(vec![]),
+    };
+    let relay_config = RelayConfig {
+        mode: RelayMode::Direct,
+        rto: Duration::from_millis(10),
+        min_rto: Duration::ZERO,
+        max_retransmits: 2,
+        ..Default::default()
+    };
+
+    let result = stun_request(
+        &transport,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+        None,
+    )
+    .await;
+    // The timed out receives end each attempt instead of failing the request
+    assert_eq!(result.err(), Some(Error::ErrStunTimeout));
+    let timeouts = transport.timeouts.lock().unwrap();
+    assert_eq!(timeouts.len(), 3);
+    for (timeout, rto) in timeouts.iter().zip([10, 20, 40]) {
+        let timeout = timeout.expect("every receive is bounded");
+        assert!(timeout <= Duration::from_millis(rto), "{timeout:?}");
+    }
+
+    Ok(())
+}
+
+/// Fails every receive with an I/O error of `kind`.
+struct FailingTransport {
+    kind: std::io::ErrorKind,
+    sends: std::sync::atomic::AtomicUsize,
+}
+
+#[async_trait]
+impl RelayTransport for FailingTransport {
+    fn local_addr(&self) -> util::Result<SocketAddr> {
+        Ok(SocketAddr::from_str("192.168.0.2:5000").unwrap())
+    }
+
+    async fn send_frame(&self, frame: &[u8], _target: SocketAddr) -> util::Result<usize> {
+        self.sends
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Ok(frame.len())
+    }
+
+    async fn recv_frame(&self, _buf: &mut [u8]) -> util::Result<(usize, SocketAddr)> {
+        Err(std::io::Error::from(self.kind).into())
+    }
+}
+
+#[tokio::test]
+async fn test_stun_request_fails_fast_on_permanent_errors() -> Result<()> {
+    let relay_config = RelayConfig {
+        mode: RelayMode::Direct,
+        rto: Duration::from_millis(10),
+        min_rto: Duration::ZERO,
+        max_retransmits: 2,
+        ..Default::default()
+    };
+
+    for (kind, sends, expected) in [
+        (
+            std::io::ErrorKind::ConnectionRefused,
+            1,
+            Error::ErrStunRecv(std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into()),
+        ),
+        // Retried until the retransmissions are used up
+        (std::io::ErrorKind::Interrupted, 3, Error::ErrStunTimeout),
+    ] {
+        let transport = FailingTransport {
+            kind,
+            sends: Default::default(),
+        };
+        let result = stun_request(
+            &transport,
+            SocketAddr::from_str("1.2.3.4:3478")?,
+            Duration::from_secs(1),
+            &relay_config,
+            None,
+            None,
+            None,
+        )
+        .await;
+        assert_eq!(result.err(), Some(expected), "{kind:?}");
+        assert_eq!(transport.sends.into_inner(), sends, "{kind:?}");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stun_request_with_transaction_id() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let requests = Arc::new(std::sync::Mutex::new(vec![]));
+    let seen = Arc::clone(&requests);
+    let transport = MockTransport::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| {
+            seen.lock().unwrap().push(req.to_vec());
+            binding_success(req, mapped).raw
+        }),
+    );
+    let relay_config = RelayConfig {
+        mode: RelayMode::Direct,
+        ..Default::default()
+    };
+    let transaction_id = TransactionId([0x42; TRANSACTION_ID_SIZE]);
+
+    let resp = stun_request_with_transaction_id(
+        &transport,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+        None,
+        Some(transaction_id),
+    )
+    .await?;
+    assert_eq!(resp.transaction_id, transaction_id);
+    // The ID follows the type, length and magic cookie of the STUN header
+    assert_eq!(
+        requests.lock().unwrap()[0][8..MESSAGE_HEADER_SIZE],
+        transaction_id.0
+    );
+
+    // Without one every request gets a fresh ID
+    let resp = stun_request(
+        &transport,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert_ne!(resp.transaction_id, transaction_id);
+
+    Ok(())
+}
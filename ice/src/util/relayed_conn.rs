@@ -0,0 +1,159 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use log::debug;
+use stun::message::is_message;
+use util::Conn;
+
+use super::relay_codec::{encode_relay_frame, strip_session_ids, RelayFrame, RelayFrameReader};
+use crate::agent::agent_config::RelayConfig;
+use crate::agent::agent_external::{unmap_ipv4, RelayPacketType, MAX_RELAY_HEADER_LEN};
+use crate::error::*;
+
+/// Wraps a conn whose traffic goes through the relay. Datagrams passed to `send_to`
+/// are framed by `encode_relay_frame` and sent to the relay, which forwards them to
+/// their target. Frames received from the relay are unwrapped again, so `recv_from`
+/// returns the payload together with the peer that sent it. Datagrams that are not
+/// relay frames, e.g. STUN messages that arrive directly, are passed through unchanged.
+pub struct RelayedConn {
+    conn: Arc<dyn Conn + Send + Sync>,
+    // Announced to the relay as the source of our frames
+    source_addr: SocketAddr,
+    relay_config: RelayConfig,
+    remote_addr: Mutex<Option<SocketAddr>>,
+    // Payloads of frames the relay coalesced into a datagram already read
+    pending: Mutex<VecDeque<(Vec<u8>, SocketAddr)>>,
+}
+
+impl RelayedConn {
+    /// Wraps `conn`, reaching the relay the way `relay_config` describes for the
+    /// address `conn` is bound to.
+    pub fn new(conn: Arc<dyn Conn + Send + Sync>, relay_config: &RelayConfig) -> Result<Self> {
+        let local_addr = conn.local_addr().map_err(|_| Error::ErrNoLocalAddr)?;
+        Ok(Self::with_source_addr(conn, relay_config, local_addr))
+    }
+
+    /// Like `new`, but the frames are sent as coming from `source_addr` rather than the
+    /// address `conn` is bound to, e.g. from the address of the candidate `conn` serves.
+    pub fn with_source_addr(
+        conn: Arc<dyn Conn + Send + Sync>,
+        relay_config: &RelayConfig,
+        source_addr: SocketAddr,
+    ) -> Self {
+        RelayedConn {
+            conn,
+            source_addr,
+            relay_config: relay_config.clone(),
+            remote_addr: Mutex::new(None),
+            pending: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Returns the relay endpoint the frames are sent to. With `relay_addrs` set they
+    /// take turns among those instead, see `RelayConfig::next_relay_for`.
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay_config.relay_addr_for(self.source_addr)
+    }
+
+    fn pop_pending(&self, buf: &mut [u8]) -> Option<util::Result<(usize, SocketAddr)>> {
+        let (payload, from) = self.pending.lock().unwrap().pop_front()?;
+        Some(copy_payload(&payload, buf).map(|n| (n, from)))
+    }
+}
+
+/// Copies a received payload into the caller's buffer, which has to fit all of it.
+fn copy_payload(payload: &[u8], buf: &mut [u8]) -> util::Result<usize> {
+    let dst = buf
+        .get_mut(..payload.len())
+        .ok_or(util::Error::ErrBufferShort)?;
+    dst.copy_from_slice(payload);
+    Ok(payload.len())
+}
+
+fn to_util_error(err: Error) -> util::Error {
+    util::Error::Other(err.to_string())
+}
+
+#[async_trait]
+impl Conn for RelayedConn {
+    async fn connect(&self, addr: SocketAddr) -> util::Result<()> {
+        *self.remote_addr.lock().unwrap() = Some(addr);
+        Ok(())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> util::Result<usize> {
+        let (n, _) = self.recv_from(buf).await?;
+        Ok(n)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> util::Result<(usize, SocketAddr)> {
+        if let Some(received) = self.pop_pending(buf) {
+            return received;
+        }
+
+        let mut bs = vec![0u8; buf.len() + MAX_RELAY_HEADER_LEN];
+        loop {
+            let (n, src) = self.conn.recv_from(&mut bs).await?;
+            let is_relay_frame = bs[..n]
+                .first()
+                .is_some_and(|&packet_type| RelayPacketType::try_from(packet_type).is_ok());
+            if !is_relay_frame && !is_message(&bs[..n]) {
+                // Nothing to unwrap, e.g. application data a peer sent directly
+                return copy_payload(&bs[..n], buf).map(|n| (n, src));
+            }
+            let n = match strip_session_ids(&mut bs[..n], self.relay_config.session_id) {
+                Ok(n) => n,
+                Err(err) => {
+                    debug!("Discarding relay datagram: {}", err);
+                    continue;
+                }
+            };
+            let mut payloads = VecDeque::new();
+            for frame in RelayFrameReader::new(&bs[..n]) {
+                match frame {
+                    Ok(RelayFrame::RecvInfo { recv_info, payload }) => {
+                        payloads.push_back((payload.to_vec(), unmap_ipv4(recv_info.from)));
+                    }
+                    Ok(RelayFrame::RawStun(raw)) => payloads.push_back((raw.to_vec(), src)),
+                    Ok(frame) => debug!("Discarding relay frame {:?}", frame),
+                    Err(err) => debug!("Discarding undecodable relay frame: {}", err),
+                }
+            }
+            self.pending.lock().unwrap().extend(payloads);
+            if let Some(received) = self.pop_pending(buf) {
+                return received;
+            }
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> util::Result<usize> {
+        let target = self
+            .remote_addr
+            .lock()
+            .unwrap()
+            .ok_or(util::Error::ErrNoRemAddr)?;
+        self.send_to(buf, target).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> util::Result<usize> {
+        let frame = encode_relay_frame(&self.relay_config, self.source_addr, target, buf)
+            .map_err(to_util_error)?;
+        let relay_addr = self.relay_config.next_relay_for(self.source_addr);
+        self.conn.send_to(&frame, relay_addr).await?;
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> util::Result<SocketAddr> {
+        self.conn.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        *self.remote_addr.lock().unwrap()
+    }
+
+    async fn close(&self) -> util::Result<()> {
+        self.conn.close().await
+    }
+}
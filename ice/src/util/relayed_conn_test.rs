@@ -0,0 +1,207 @@
+use std::str::FromStr;
+
+use tokio::net::UdpSocket;
+
+use super::relay_codec::*;
+use super::relayed_conn::*;
+use super::test_support::*;
+use super::*;
+use crate::agent::agent_config::RelayFrameVersion;
+use crate::agent::agent_external::SendInfo;
+
+/// A `RelayedConn` on a loopback socket, together with the socket playing the relay.
+async fn relayed_pair() -> Result<(RelayedConn, UdpSocket)> {
+    let relay = UdpSocket::bind("127.0.0.1:0").await?;
+    let inner: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_config = RelayConfig {
+        relay_addr: relay.local_addr()?,
+        ..Default::default()
+    };
+    Ok((RelayedConn::new(inner, &relay_config)?, relay))
+}
+
+#[tokio::test]
+async fn test_relayed_conn_round_trip() -> Result<()> {
+    let (conn, relay) = relayed_pair().await?;
+    let local_addr = conn.local_addr()?;
+    let peer = SocketAddr::from_str("1.2.3.4:3478")?;
+    assert_eq!(conn.relay_addr(), relay.local_addr()?);
+
+    // Outgoing datagrams reach the relay with a SendInfo header in front
+    assert_eq!(conn.send_to(b"hello", peer).await?, 5);
+    let mut bs = [0u8; 1500];
+    let (n, src) = relay.recv_from(&mut bs).await?;
+    assert_eq!(src, local_addr);
+    assert_eq!(
+        decode_relay_frame(&bs[..n])?,
+        RelayFrame::SendInfo {
+            send_info: SendInfo {
+                from: local_addr,
+                to: peer,
+            },
+            payload: b"hello",
+        }
+    );
+
+    // The relay's answer comes back without its RecvInfo header
+    relay
+        .send_to(
+            &recv_info_frame(SendInfo::new(peer, local_addr)?, b"world"),
+            local_addr,
+        )
+        .await?;
+    let mut buf = [0u8; 64];
+    let (n, from) = conn.recv_from(&mut buf).await?;
+    assert_eq!(&buf[..n], b"world");
+    assert_eq!(from, peer);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relayed_conn_send_uses_connected_peer() -> Result<()> {
+    let (conn, relay) = relayed_pair().await?;
+    let peer = SocketAddr::from_str("[2001:db8::1]:3478")?;
+    assert!(conn.send(b"hello").await.is_err());
+
+    conn.connect(peer).await?;
+    assert_eq!(conn.remote_addr(), Some(peer));
+    conn.send(b"hello").await?;
+    let mut bs = [0u8; 1500];
+    let (n, _) = relay.recv_from(&mut bs).await?;
+    assert_eq!(SendInfo::decode(&bs[..n])?.to, peer);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relayed_conn_splits_coalesced_frames() -> Result<()> {
+    let (conn, relay) = relayed_pair().await?;
+    let local_addr = conn.local_addr()?;
+    let (peer1, peer2) = (
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        SocketAddr::from_str("5.6.7.8:3478")?,
+    );
+    let mut msg1 = Message::new();
+    msg1.build(&[Box::new(BINDING_SUCCESS), Box::new(TransactionId::new())])?;
+    let mut msg2 = Message::new();
+    msg2.build(&[Box::new(BINDING_SUCCESS), Box::new(TransactionId::new())])?;
+
+    // An acknowledgement is not data and skipped, the two responses are returned in order
+    let mut datagram = encode_open_socket(RelayPacketType::OpenSocketAck, local_addr)?;
+    datagram.extend(recv_info_frame(
+        SendInfo::new(peer1, local_addr)?,
+        &msg1.raw,
+    ));
+    datagram.extend(recv_info_frame(
+        SendInfo::new(peer2, local_addr)?,
+        &msg2.raw,
+    ));
+    relay.send_to(&datagram, local_addr).await?;
+
+    let mut buf = [0u8; 1500];
+    let (n, from) = conn.recv_from(&mut buf).await?;
+    assert_eq!((&buf[..n], from), (msg1.raw.as_slice(), peer1));
+    let (n, from) = conn.recv_from(&mut buf).await?;
+    assert_eq!((&buf[..n], from), (msg2.raw.as_slice(), peer2));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relayed_conn_passes_direct_stun_through() -> Result<()> {
+    let (conn, relay) = relayed_pair().await?;
+    let local_addr = conn.local_addr()?;
+    let mut msg = Message::new();
+    msg.build(&[Box::new(BINDING_SUCCESS), Box::new(TransactionId::new())])?;
+
+    relay.send_to(&msg.raw, local_addr).await?;
+    let mut buf = [0u8; 1500];
+    let (n, from) = conn.recv_from(&mut buf).await?;
+    assert_eq!(&buf[..n], msg.raw.as_slice());
+    assert_eq!(from, relay.local_addr()?);
+
+    // A payload larger than the caller's buffer is an error rather than truncated
+    relay
+        .send_to(
+            &recv_info_frame(SendInfo::new(from, local_addr)?, &msg.raw),
+            local_addr,
+        )
+        .await?;
+    let mut short = [0u8; 4];
+    assert_eq!(
+        conn.recv_from(&mut short).await,
+        Err(util::Error::ErrBufferShort)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relayed_conn_frames_as_configured() -> Result<()> {
+    let relay = UdpSocket::bind("127.0.0.1:0").await?;
+    let inner: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let local_addr = inner.local_addr()?;
+    let relay_config = RelayConfig {
+        relay_addr: relay.local_addr()?,
+        mtu: 16,
+        frame_version: RelayFrameVersion::V2,
+        ..Default::default()
+    };
+    let conn = RelayedConn::new(inner, &relay_config)?;
+    let peer = SocketAddr::from_str("1.2.3.4:3478")?;
+
+    conn.send_to(b"hello", peer).await?;
+    let mut bs = [0u8; 1500];
+    let (n, _) = relay.recv_from(&mut bs).await?;
+    assert_eq!(
+        &bs[..n],
+        encode_relay_frame(&relay_config, local_addr, peer, b"hello")?.as_slice()
+    );
+
+    // Payloads above the MTU of the relay are refused rather than sent
+    assert!(conn.send_to(&[0u8; 17], peer).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relayed_conn_carries_application_data() -> Result<()> {
+    let relay = UdpSocket::bind("127.0.0.1:0").await?;
+    let inner: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let local_addr = inner.local_addr()?;
+    let relay_config = RelayConfig {
+        relay_addr: relay.local_addr()?,
+        ..Default::default()
+    };
+    // Frames announce the address of the candidate rather than the loopback socket
+    let candidate_addr = SocketAddr::from_str("192.168.1.10:5000")?;
+    let conn = RelayedConn::with_source_addr(inner, &relay_config, candidate_addr);
+    let peer = SocketAddr::from_str("1.2.3.4:3478")?;
+
+    conn.send_to(b"data", peer).await?;
+    let mut bs = [0u8; 1500];
+    let (n, _) = relay.recv_from(&mut bs).await?;
+    assert_eq!(SendInfo::decode(&bs[..n])?.from, candidate_addr);
+    assert_eq!(conn.local_addr()?, local_addr);
+
+    // A relayed payload that is not STUN is returned whole, its bytes are not taken
+    // for a STUN length
+    let data = [0x17, 0xfe, 0xfd, 0x00, 0x01, 0x02, 0x03, 0x04];
+    relay
+        .send_to(
+            &recv_info_frame(SendInfo::new(peer, local_addr)?, &data),
+            local_addr,
+        )
+        .await?;
+    let mut buf = [0u8; 64];
+    let (n, from) = conn.recv_from(&mut buf).await?;
+    assert_eq!((&buf[..n], from), (&data[..], peer));
+
+    // Datagrams that are no relay frames at all are passed through as they are
+    relay.send_to(&data, local_addr).await?;
+    let (n, from) = conn.recv_from(&mut buf).await?;
+    assert_eq!((&buf[..n], from), (&data[..], relay.local_addr()?));
+
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use tokio::net::UdpSocket;
+
+use super::test_support::*;
+use super::*;
+use crate::agent::agent_config::RelayMode;
+
+/// Answers one binding request on `server` like a STUN server without any relay.
+fn spawn_stun_server(server: UdpSocket) -> tokio::task::JoinHandle<Result<()>> {
+    tokio::spawn(async move {
+        let mut bs = [0u8; 1500];
+        let (n, src) = server.recv_from(&mut bs).await?;
+        assert!(is_message(&bs[..n]), "expected a plain STUN request");
+        let resp = binding_success(&bs[..n], src);
+        server.send_to(&resp.raw, src).await?;
+        Ok(())
+    })
+}
+
+async fn request(relay_config: &RelayConfig, server_addr: SocketAddr) -> Result<StunResponse> {
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    stun_request(
+        &conn,
+        server_addr,
+        Duration::from_secs(1),
+        relay_config,
+        None,
+        None,
+        None,
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_stun_request_direct_mode() -> Result<()> {
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?;
+    let handle = spawn_stun_server(server);
+    let relay_config = RelayConfig {
+        mode: RelayMode::Direct,
+        ..Default::default()
+    };
+
+    let resp = request(&relay_config, server_addr).await?;
+    assert_eq!(resp.from, server_addr);
+    assert!(!resp.was_relayed);
+    handle.await.unwrap()?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "relay"))]
+#[tokio::test]
+async fn test_stun_request_goes_direct_without_relay_feature() -> Result<()> {
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?;
+    let handle = spawn_stun_server(server);
+
+    // The relay mode is ignored, there is no framing to put the request in
+    let resp = request(&RelayConfig::default(), server_addr).await?;
+    assert_eq!(resp.from, server_addr);
+    assert!(!resp.was_relayed);
+    handle.await.unwrap()?;
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_goes_through_relay() -> Result<()> {
+    use super::relay_codec::*;
+
+    let relay = UdpSocket::bind("127.0.0.1:0").await?;
+    let relay_config = RelayConfig {
+        relay_addr: relay.local_addr()?,
+        ..Default::default()
+    };
+    let server_addr = SocketAddr::new(IpAddr::from([192, 0, 2, 1]), 3478);
+    let handle = tokio::spawn(async move {
+        let mut bs = [0u8; 1500];
+        let (n, src) = relay.recv_from(&mut bs).await?;
+        let RelayFrame::SendInfo { send_info, payload } = decode_relay_frame(&bs[..n])? else {
+            panic!("expected a SendInfo frame");
+        };
+        assert_eq!(send_info.to, server_addr);
+        let resp = binding_success(payload, src);
+        let frame = relayed_response(&bs[..n], &resp);
+        relay.send_to(&frame, src).await?;
+        Ok::<_, Error>(())
+    });
+
+    let resp = request(&relay_config, server_addr).await?;
+    assert_eq!(resp.from, server_addr);
+    assert!(resp.was_relayed);
+    handle.await.unwrap()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_is_retriable() {
+    let io = |kind: std::io::ErrorKind| std::io::Error::from(kind);
+    let util_io = |kind| util::Error::from(io(kind));
+
+    for err in [
+        Error::ErrStunTimeout,
+        Error::from(io(std::io::ErrorKind::TimedOut)),
+        Error::Util(util_io(std::io::ErrorKind::Interrupted)),
+        Error::ErrStunRecv(util_io(std::io::ErrorKind::WouldBlock)),
+    ] {
+        assert!(is_retriable(&err), "{err} should be retried");
+    }
+    for err in [
+        Error::ErrStunRecv(util::Error::ErrClosedListener),
+        Error::ErrStunRecv(util_io(std::io::ErrorKind::ConnectionRefused)),
+        Error::Util(util_io(std::io::ErrorKind::ConnectionReset)),
+        Error::from(io(std::io::ErrorKind::NotConnected)),
+        Error::ErrRelayLoopback,
+        Error::ErrShortStunResponse,
+    ] {
+        assert!(!is_retriable(&err), "{err} should fail fast");
+    }
+}
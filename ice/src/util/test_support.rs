@@ -0,0 +1,80 @@
+//! Fixtures shared by the tests that play STUN server or relay.
+
+use std::net::SocketAddr;
+
+use stun::message::*;
+use stun::xoraddr::XorMappedAddress;
+
+#[cfg(feature = "relay")]
+use crate::agent::agent_config::RelayFrameVersion;
+#[cfg(feature = "relay")]
+use crate::agent::agent_external::{split_relay_header, RelayPacketType, SendInfo};
+
+/// Builds the binding success answering `request`, reporting `mapped` as the
+/// XOR-MAPPED-ADDRESS.
+pub(crate) fn binding_success_to(request: &Message, mapped: SocketAddr) -> Message {
+    let mut response = Message::new();
+    response
+        .build(&[
+            Box::new(BINDING_SUCCESS),
+            Box::new(request.transaction_id),
+            Box::new(XorMappedAddress {
+                ip: mapped.ip(),
+                port: mapped.port(),
+            }),
+        ])
+        .expect("a binding success");
+    response
+}
+
+/// Like `binding_success_to`, answering the raw STUN request `raw`.
+pub(crate) fn binding_success(raw: &[u8], mapped: SocketAddr) -> Message {
+    binding_success_to(&stun_message(raw), mapped)
+}
+
+/// Decodes the raw STUN message `raw`.
+pub(crate) fn stun_message(raw: &[u8]) -> Message {
+    let mut msg = Message::new();
+    msg.raw = raw.to_vec();
+    msg.decode().expect("a STUN message");
+    msg
+}
+
+/// Returns the payload of the relay frame `frame`, in either frame version.
+#[cfg(feature = "relay")]
+pub(crate) fn relay_payload(frame: &[u8]) -> &[u8] {
+    split_relay_header(frame).expect("a relay frame").1
+}
+
+/// Returns the header of the relay frame `frame`, everything in front of its payload.
+#[cfg(feature = "relay")]
+pub(crate) fn relay_header(frame: &[u8]) -> &[u8] {
+    &frame[..frame.len() - relay_payload(frame).len()]
+}
+
+/// Frames `payload` as the relay delivers it from `recv_info.from` to `recv_info.to`.
+#[cfg(feature = "relay")]
+pub(crate) fn recv_info_frame(recv_info: SendInfo, payload: &[u8]) -> Vec<u8> {
+    let mut frame = recv_info
+        .encode_as(RelayPacketType::RecvInfo, RelayFrameVersion::V1)
+        .expect("a RecvInfo frame");
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Frames `response` as the relay delivers the answer to the relayed request `request`.
+#[cfg(feature = "relay")]
+pub(crate) fn relayed_response(request: &[u8], response: &Message) -> Vec<u8> {
+    let send_info = SendInfo::decode(request).expect("a SendInfo frame");
+    let recv_info = SendInfo {
+        from: send_info.to,
+        to: send_info.from,
+    };
+    recv_info_frame(recv_info, &response.raw)
+}
+
+/// Answers the relayed binding request `request` with a success reporting `mapped`.
+#[cfg(feature = "relay")]
+pub(crate) fn relayed_binding_success(request: &[u8], mapped: SocketAddr) -> Vec<u8> {
+    relayed_response(request, &binding_success(relay_payload(request), mapped))
+}
@@ -0,0 +1,89 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::debug;
+use util::Conn;
+
+/// Called with the local address of a `TrackedConn` once it is released, `None` if
+/// the conn could not tell its address.
+pub type ReleaseHook = Box<dyn FnOnce(Option<SocketAddr>) + Send + Sync>;
+
+/// A conn that logs when it is released, i.e. when the last reference to it is
+/// dropped, together with the port that became free. Helps to tell from the logs
+/// who holds on to the ports of an exhausted range. See `ListenOptions::track_release`.
+pub struct TrackedConn {
+    conn: Arc<dyn Conn + Send + Sync>,
+    // Taken up front, a closed conn may no longer report it
+    local_addr: Option<SocketAddr>,
+    on_release: Option<ReleaseHook>,
+}
+
+impl TrackedConn {
+    pub fn new(conn: Arc<dyn Conn + Send + Sync>) -> Self {
+        let local_addr = conn.local_addr().ok();
+        debug!("Bound UDP socket {:?}", local_addr);
+        TrackedConn {
+            conn,
+            local_addr,
+            on_release: None,
+        }
+    }
+
+    /// Calls `on_release` after logging the release, e.g. to count the ports in use.
+    pub fn with_release_hook(mut self, on_release: ReleaseHook) -> Self {
+        self.on_release = Some(on_release);
+        self
+    }
+}
+
+impl Drop for TrackedConn {
+    fn drop(&mut self) {
+        match self.local_addr {
+            Some(local_addr) => debug!(
+                "Released UDP port {} of socket {}",
+                local_addr.port(),
+                local_addr
+            ),
+            None => debug!("Released UDP socket of unknown port"),
+        }
+        if let Some(on_release) = self.on_release.take() {
+            on_release(self.local_addr);
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for TrackedConn {
+    async fn connect(&self, addr: SocketAddr) -> util::Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> util::Result<usize> {
+        self.conn.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> util::Result<(usize, SocketAddr)> {
+        self.conn.recv_from(buf).await
+    }
+
+    async fn send(&self, buf: &[u8]) -> util::Result<usize> {
+        self.conn.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> util::Result<usize> {
+        self.conn.send_to(buf, target).await
+    }
+
+    fn local_addr(&self) -> util::Result<SocketAddr> {
+        self.conn.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.conn.remote_addr()
+    }
+
+    async fn close(&self) -> util::Result<()> {
+        self.conn.close().await
+    }
+}
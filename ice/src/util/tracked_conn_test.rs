@@ -0,0 +1,30 @@
+use std::sync::Mutex;
+
+use tokio::net::UdpSocket;
+
+use super::tracked_conn::*;
+use super::*;
+
+#[tokio::test]
+async fn test_tracked_conn_release_hook() -> Result<()> {
+    let socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let local_addr = socket.local_addr()?;
+    let released = Arc::new(Mutex::new(vec![]));
+    let hook_released = Arc::clone(&released);
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(TrackedConn::new(socket).with_release_hook(
+        Box::new(move |addr| hook_released.lock().unwrap().push(addr)),
+    ));
+
+    let other = Arc::clone(&conn);
+    drop(conn);
+    assert!(
+        released.lock().unwrap().is_empty(),
+        "released while still referenced"
+    );
+    assert_eq!(other.local_addr()?, local_addr);
+
+    drop(other);
+    assert_eq!(*released.lock().unwrap(), [Some(local_addr)]);
+
+    Ok(())
+}
@@ -1,10 +1,3101 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, Mutex};
+
+use super::test_support::*;
 use super::*;
+use crate::agent::agent_config::RelayMode;
+#[cfg(feature = "relay")]
+use crate::agent::agent_config::{DEFAULT_RELAY_ADDR, DEFAULT_RELAY_TIMEOUT_PADDING};
+#[cfg(feature = "relay")]
+use crate::agent::agent_external::{
+    insert_session_id, RelayPacketType, SendInfo, ADDR_FAMILY_IPV4,
+};
+#[cfg(feature = "relay")]
+use crate::candidate::{Candidate, CandidateRelatedAddress, CandidateType};
+
+type Responder = Box<dyn Fn(&[u8]) -> Vec<Vec<u8>> + Send + Sync>;
+
+/// A `Conn` that records every outgoing datagram and answers with the datagrams
+/// the responder returns for it.
+struct MockConn {
+    /// `None` makes `local_addr` fail, as it does for a closed socket.
+    local_addr: Option<SocketAddr>,
+    sent: Mutex<Vec<(Vec<u8>, SocketAddr)>>,
+    responder: Responder,
+    /// Datagrams sent to these addresses are recorded but never answered.
+    silent_targets: Vec<SocketAddr>,
+    /// Answers every datagram ahead of the responder, from an address of its own.
+    spoofer: Option<(SocketAddr, Responder)>,
+    resp_tx: mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>,
+    resp_rx: Mutex<mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>>,
+}
+
+impl MockConn {
+    fn new(local_addr: SocketAddr, responder: Responder) -> Self {
+        let (resp_tx, resp_rx) = mpsc::unbounded_channel();
+        MockConn {
+            local_addr: Some(local_addr),
+            sent: Mutex::new(vec![]),
+            responder,
+            silent_targets: vec![],
+            spoofer: None,
+            resp_tx,
+            resp_rx: Mutex::new(resp_rx),
+        }
+    }
+
+    fn with_silent_targets(mut self, silent_targets: Vec<SocketAddr>) -> Self {
+        self.silent_targets = silent_targets;
+        self
+    }
+
+    fn with_spoofer(mut self, from: SocketAddr, responder: Responder) -> Self {
+        self.spoofer = Some((from, responder));
+        self
+    }
+}
+
+#[async_trait]
+impl Conn for MockConn {
+    async fn connect(&self, _addr: SocketAddr) -> std::result::Result<(), util::Error> {
+        Err(util::Error::Other("not applicable".to_owned()))
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> std::result::Result<usize, util::Error> {
+        let (n, _) = self.recv_from(buf).await?;
+        Ok(n)
+    }
+
+    async fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> std::result::Result<(usize, SocketAddr), util::Error> {
+        let mut resp_rx = self.resp_rx.lock().await;
+        match resp_rx.recv().await {
+            Some((data, from)) => {
+                let n = std::cmp::min(data.len(), buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                Ok((n, from))
+            }
+            None => Err(util::Error::ErrClosedListener),
+        }
+    }
+
+    async fn send(&self, _buf: &[u8]) -> std::result::Result<usize, util::Error> {
+        Err(util::Error::Other("not applicable".to_owned()))
+    }
+
+    async fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> std::result::Result<usize, util::Error> {
+        self.sent.lock().await.push((buf.to_vec(), target));
+        if self.silent_targets.contains(&target) {
+            return Ok(buf.len());
+        }
+        if let Some((from, spoofer)) = &self.spoofer {
+            for resp in spoofer(buf) {
+                let _ = self.resp_tx.send((resp, *from));
+            }
+        }
+        for resp in (self.responder)(buf) {
+            let _ = self.resp_tx.send((resp, target));
+        }
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> std::result::Result<SocketAddr, util::Error> {
+        self.local_addr.ok_or(util::Error::ErrClosedListener)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    async fn close(&self) -> std::result::Result<(), util::Error> {
+        // Pending and future receives fail once the queued responses are drained
+        self.resp_rx.lock().await.close();
+        Ok(())
+    }
+}
 
 #[tokio::test]
 async fn test_local_interfaces() -> Result<()> {
     let vnet = Arc::new(Net::new(None));
     let interfaces = vnet.get_interfaces().await;
-    let ips = local_interfaces(&vnet, &None, &None, &[NetworkType::Udp4, NetworkType::Udp6]).await;
+    let ips = local_interfaces(
+        &vnet,
+        &None,
+        &None,
+        &[NetworkType::Udp4, NetworkType::Udp6],
+        false,
+    )
+    .await;
     log::info!("interfaces: {:?}, ips: {:?}", interfaces, ips);
     Ok(())
 }
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_listen_udp_in_port_range_bind_device() -> Result<()> {
+    let vnet = Arc::new(Net::new(None));
+    let laddr = SocketAddr::from_str("127.0.0.1:0")?;
+    let on_device = |device: &str| ListenOptions {
+        bind_device: Some(device.to_owned()),
+        ..Default::default()
+    };
+    let conn = listen_udp_in_port_range_with_options(&vnet, 0, 0, laddr, &on_device("lo")).await?;
+    assert!(conn.local_addr()?.ip().is_loopback());
+
+    let result =
+        listen_udp_in_port_range_with_options(&vnet, 0, 0, laddr, &on_device("nosuchdev0")).await;
+    assert!(result.is_err(), "an unknown device must not be ignored");
+
+    // A physical device could not reach the relay on loopback, it is refused before
+    // anything is bound
+    let physical = Arc::new(Net::Ifs(vec![Interface::new(
+        "eth0".to_owned(),
+        vec![ipnet::IpNet::from_str("192.168.0.2/24").unwrap()],
+    )]));
+    let result =
+        listen_udp_in_port_range_with_options(&physical, 0, 0, laddr, &on_device("eth0")).await;
+    assert_eq!(
+        result.err(),
+        Some(Error::Util(util::Error::ErrBindDeviceWithRelay(
+            "eth0".to_owned()
+        )))
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_uses_configured_relay_addr() -> Result<()> {
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(|_| vec![]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let relay_config = RelayConfig {
+        relay_addr: SocketAddr::from_str("127.0.0.1:23456")?,
+        ..Default::default()
+    };
+
+    let result = stun_request(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_millis(10),
+        &relay_config,
+        None,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(
+        result.err(),
+        Some(Error::ErrStunTimeout),
+        "no response was sent, request should time out"
+    );
+
+    let sent = mock.sent.lock().await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].1, relay_config.relay_addr);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_rotates_relays() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let relays = vec![
+        SocketAddr::from_str("10.0.0.1:12345")?,
+        SocketAddr::from_str("10.0.0.2:12345")?,
+        SocketAddr::from_str("10.0.0.3:12345")?,
+    ];
+    // The second relay is down
+    let mock = Arc::new(
+        MockConn::new(
+            SocketAddr::from_str("192.168.0.2:5000")?,
+            Box::new(move |req| vec![relayed_binding_success(req, mapped)]),
+        )
+        .with_silent_targets(vec![relays[1]]),
+    );
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let relay_config = RelayConfig {
+        relay_addrs: relays.clone(),
+        rto: Duration::from_millis(10),
+        min_rto: Duration::ZERO,
+        max_retransmits: 1,
+        relay_timeout_padding: Duration::from_millis(0),
+        ..Default::default()
+    };
+    // Clones share the rotation, like the agent's config and its gathering tasks
+    let cloned = relay_config.clone();
+
+    for config in [&relay_config, &cloned, &relay_config, &cloned] {
+        let (addr, _, _) = get_xormapped_addr(
+            &conn,
+            SocketAddr::from_str("1.2.3.4:3478")?,
+            Duration::from_secs(1),
+            config,
+            None,
+            None,
+            None,
+        )
+        .await?;
+        assert_eq!(addr.port, mapped.port());
+    }
+
+    let targets: Vec<SocketAddr> = mock.sent.lock().await.iter().map(|(_, to)| *to).collect();
+    assert_eq!(
+        targets,
+        vec![
+            relays[0], // Retransmitted once, then the third relay takes over
+            relays[1], relays[1], relays[2], relays[2], relays[0],
+        ]
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_fails_when_all_relays_are_down() -> Result<()> {
+    let relays = vec![
+        SocketAddr::from_str("10.0.0.1:12345")?,
+        SocketAddr::from_str("10.0.0.2:12345")?,
+    ];
+    let mock = Arc::new(
+        MockConn::new(
+            SocketAddr::from_str("192.168.0.2:5000")?,
+            Box::new(|_| vec![]),
+        )
+        .with_silent_targets(relays.clone()),
+    );
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let relay_config = RelayConfig {
+        relay_addrs: relays.clone(),
+        rto: Duration::from_millis(10),
+        min_rto: Duration::ZERO,
+        max_retransmits: 0,
+        ..Default::default()
+    };
+    let metrics = StunMetrics::default();
+
+    let result = stun_request(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &relay_config,
+        Some(&metrics),
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(result.err(), Some(Error::ErrStunTimeout));
+    let targets: Vec<SocketAddr> = mock.sent.lock().await.iter().map(|(_, to)| *to).collect();
+    assert_eq!(targets, relays);
+    assert_eq!(metrics.snapshot().timeouts, 1);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_probe_nat_mapping() -> Result<()> {
+    let server_a = SocketAddr::from_str("1.2.3.4:3478")?;
+    let server_b = SocketAddr::from_str("5.6.7.8:3478")?;
+    let relay_config = RelayConfig::default();
+
+    for (port_b, expected) in [
+        (40000, NatMappingBehavior::EndpointIndependent),
+        (40001, NatMappingBehavior::AddressDependent),
+    ] {
+        // Server B sees the mapping on `port_b`, server A always on 40000
+        let conn: Arc<dyn Conn + Send + Sync> = Arc::new(MockConn::new(
+            SocketAddr::from_str("192.168.0.2:5000")?,
+            Box::new(move |req| {
+                let port = if SendInfo::decode(req).unwrap().to == server_b {
+                    port_b
+                } else {
+                    40000
+                };
+                let mapped = SocketAddr::new(IpAddr::from([9, 9, 9, 9]), port);
+                vec![relayed_binding_success(req, mapped)]
+            }),
+        ));
+
+        let behavior = probe_nat_mapping(
+            &conn,
+            server_a,
+            server_b,
+            Duration::from_secs(1),
+            &relay_config,
+        )
+        .await?;
+        assert_eq!(behavior, expected);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_get_xormapped_addr_error_response() -> Result<()> {
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(|req| {
+            let req_msg = stun_message(relay_payload(req));
+
+            let mut resp = Message::new();
+            resp.build(&[
+                Box::new(BINDING_ERROR),
+                Box::new(req_msg.transaction_id),
+                Box::new(ErrorCodeAttribute {
+                    code: CODE_UNAUTHORIZED,
+                    reason: b"Unauthorized".to_vec(),
+                }),
+            ])
+            .unwrap();
+            vec![relayed_response(req, &resp)]
+        }),
+    ));
+    let metrics = StunMetrics::default();
+
+    let result = get_xormapped_addr(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        Some(&metrics),
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(
+        result.err(),
+        Some(Error::ErrStunErrorResponse {
+            code: 401,
+            reason: "Unauthorized".to_owned(),
+        })
+    );
+    // The server answered, the response just was not a success
+    assert_eq!(metrics.snapshot().parse_errors, 0);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+/// Answers the relayed request `req` with a message of type `typ` that carries the
+/// request's transaction ID.
+fn relayed_answer_of_type(req: &[u8], typ: MessageType) -> Vec<u8> {
+    let req_msg = stun_message(relay_payload(req));
+
+    let mut resp = Message::new();
+    resp.build(&[Box::new(typ), Box::new(req_msg.transaction_id)])
+        .unwrap();
+    relayed_response(req, &resp)
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_rejects_non_success_messages() -> Result<()> {
+    let binding_indication = MessageType::new(METHOD_BINDING, CLASS_INDICATION);
+    for typ in [binding_indication, BINDING_REQUEST] {
+        let conn: Arc<dyn Conn + Send + Sync> = Arc::new(MockConn::new(
+            SocketAddr::from_str("192.168.0.2:5000")?,
+            Box::new(move |req| vec![relayed_answer_of_type(req, typ)]),
+        ));
+        let metrics = StunMetrics::default();
+
+        let result = get_xormapped_addr(
+            &conn,
+            SocketAddr::from_str("1.2.3.4:3478")?,
+            Duration::from_secs(1),
+            &RelayConfig::default(),
+            Some(&metrics),
+            None,
+            None,
+        )
+        .await;
+        assert_eq!(result.err(), Some(Error::ErrUnexpectedStunMessage), "{typ}");
+        assert_eq!(metrics.snapshot().responses_ok, 0);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_get_binding_result_nat_detection_attributes() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let server_addr = SocketAddr::from_str("1.2.3.4:3478")?;
+    let other_address = SocketAddr::from_str("1.2.3.5:3479")?;
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| {
+            let mut resp = binding_success(relay_payload(req), mapped);
+            MappedAddress {
+                ip: server_addr.ip(),
+                port: server_addr.port(),
+            }
+            .add_to_as(&mut resp, ATTR_RESPONSE_ORIGIN)
+            .unwrap();
+            MappedAddress {
+                ip: other_address.ip(),
+                port: other_address.port(),
+            }
+            .add_to_as(&mut resp, ATTR_OTHER_ADDRESS)
+            .unwrap();
+            vec![relayed_response(req, &resp)]
+        }),
+    ));
+
+    let res = get_binding_result(
+        &conn,
+        server_addr,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(res.mapped, mapped);
+    assert_eq!(res.response_origin, Some(server_addr));
+    assert_eq!(res.other_address, Some(other_address));
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_get_binding_result_without_nat_detection_attributes() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| vec![relayed_binding_success(req, mapped)]),
+    ));
+
+    let res = get_binding_result(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(res.mapped, mapped);
+    assert_eq!(res.response_origin, None);
+    assert_eq!(res.other_address, None);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_short_response() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    for len in [0, MESSAGE_HEADER_SIZE - 1] {
+        let conn: Arc<dyn Conn + Send + Sync> = Arc::new(MockConn::new(
+            SocketAddr::from_str("192.168.0.2:5000")?,
+            // A relay header byte that would be taken for a relayed response, followed
+            // by the actual response
+            Box::new(move |req| {
+                vec![
+                    vec![RelayPacketType::RecvInfo.as_u8(); len],
+                    relayed_binding_success(req, mapped),
+                ]
+            }),
+        ));
+        let metrics = StunMetrics::default();
+
+        let response = stun_request(
+            &conn,
+            SocketAddr::from_str("1.2.3.4:3478")?,
+            Duration::from_secs(1),
+            &RelayConfig::default(),
+            Some(&metrics),
+            None,
+            None,
+        )
+        .await?;
+        let mut xor_addr = XorMappedAddress::default();
+        xor_addr.get_from(&response.message)?;
+        assert_eq!(SocketAddr::new(xor_addr.ip, xor_addr.port), mapped);
+        assert_eq!(metrics.snapshot().parse_errors, 0);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_retransmits() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let attempts = AtomicUsize::new(0);
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| {
+            // Drop the first two requests
+            if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                vec![]
+            } else {
+                vec![relayed_binding_success(req, mapped)]
+            }
+        }),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let relay_config = RelayConfig {
+        rto: Duration::from_millis(10),
+        min_rto: Duration::ZERO,
+        ..Default::default()
+    };
+
+    let (addr, _, _) = get_xormapped_addr(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(addr.ip, mapped.ip());
+    assert_eq!(addr.port, mapped.port());
+
+    let sent = mock.sent.lock().await;
+    assert_eq!(sent.len(), 3);
+    assert!(
+        sent.iter().all(|(buf, _)| *buf == sent[0].0),
+        "retransmissions must reuse the transaction ID"
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_gives_up_after_max_retransmits() -> Result<()> {
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(|_| vec![]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let relay_config = RelayConfig {
+        rto: Duration::from_millis(1),
+        min_rto: Duration::ZERO,
+        max_retransmits: 3,
+        ..Default::default()
+    };
+
+    let result = stun_request(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(result.err(), Some(Error::ErrStunTimeout));
+    assert_eq!(mock.sent.lock().await.len(), 4);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_first_retransmit_after_initial_rto() -> Result<()> {
+    // Returns the time between the first transmission and the retransmission
+    async fn first_retransmit_after(relay_config: RelayConfig) -> Result<Duration> {
+        let sent_at = Arc::new(std::sync::Mutex::new(vec![]));
+        let record = Arc::clone(&sent_at);
+        let conn: Arc<dyn Conn + Send + Sync> = Arc::new(MockConn::new(
+            SocketAddr::from_str("192.168.0.2:5000")?,
+            Box::new(move |_| {
+                record.lock().unwrap().push(Instant::now());
+                vec![]
+            }),
+        ));
+        let relay_config = RelayConfig {
+            max_retransmits: 1,
+            ..relay_config
+        };
+
+        let result = stun_request(
+            &conn,
+            SocketAddr::from_str("1.2.3.4:3478")?,
+            Duration::from_secs(2),
+            &relay_config,
+            None,
+            None,
+            None,
+        )
+        .await;
+        assert_eq!(result.err(), Some(Error::ErrStunTimeout));
+        let sent_at = sent_at.lock().unwrap();
+        assert_eq!(sent_at.len(), 2);
+        Ok(sent_at[1] - sent_at[0])
+    }
+
+    let after = first_retransmit_after(RelayConfig {
+        rto: Duration::from_millis(150),
+        ..Default::default()
+    })
+    .await?;
+    assert!(
+        after >= Duration::from_millis(150) && after < Duration::from_millis(400),
+        "retransmitted after {after:?}"
+    );
+
+    // Too low a timeout is raised to the floor
+    let after = first_retransmit_after(RelayConfig {
+        rto: Duration::from_millis(5),
+        min_rto: Duration::from_millis(100),
+        ..Default::default()
+    })
+    .await?;
+    assert!(
+        after >= Duration::from_millis(100) && after < Duration::from_millis(350),
+        "retransmitted after {after:?}"
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_rejects_unknown_packet_type() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| {
+            let mut frame = relayed_binding_success(req, mapped);
+            frame[0] = 0x42;
+            vec![frame]
+        }),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+
+    let result = stun_request(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(
+        result.err(),
+        Some(Error::ErrStunDecode(Box::new(
+            Error::ErrUnknownRelayPacketType
+        )))
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_decode_error() -> Result<()> {
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| {
+            // A RecvInfo header followed by a STUN header cut short
+            let mut frame = relayed_response(req, &Message::new());
+            frame.extend_from_slice(&[0x01, 0x01, 0x00]);
+            vec![frame]
+        }),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+
+    let result = stun_request(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await;
+    assert!(
+        matches!(result.err(), Some(Error::ErrStunDecode(err)) if matches!(*err, Error::Stun(_)))
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_recv_error() -> Result<()> {
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(|_| vec![]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    conn.close().await?;
+
+    let result = stun_request(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(
+        result.err(),
+        Some(Error::ErrStunRecv(util::Error::ErrClosedListener))
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[test]
+fn test_decode_stun_response_transaction_mismatch() -> Result<()> {
+    let send_info = SendInfo {
+        from: SocketAddr::from_str("192.168.0.2:5000")?,
+        to: SocketAddr::from_str("1.2.3.4:3478")?,
+    };
+    let mut request = Message::new();
+    request.build(&[Box::new(BINDING_REQUEST), Box::new(TransactionId::new())])?;
+    let mut frame = send_info.encode()?;
+    frame.extend_from_slice(&request.raw);
+
+    let relay = SocketAddr::from_str("127.0.0.1:12345")?;
+    let response = relayed_binding_success(&frame, SocketAddr::from_str("5.6.7.8:40000")?);
+    let decoded = decode_stun_response(
+        decode_relay_frame(&response)?,
+        request.transaction_id,
+        relay,
+        send_info.to,
+        relay,
+    )?;
+    assert_eq!(decoded.local_addr, send_info.from);
+    assert_eq!(decoded.from, send_info.to);
+    assert_eq!(
+        decode_stun_response(
+            decode_relay_frame(&response)?,
+            TransactionId::new(),
+            relay,
+            send_info.to,
+            relay
+        )
+        .err(),
+        Some(Error::ErrTransactionMismatch)
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[test]
+fn test_decode_stun_response_was_relayed() -> Result<()> {
+    let server = SocketAddr::from_str("1.2.3.4:3478")?;
+    let mut request = Message::new();
+    request.build(&[Box::new(BINDING_REQUEST), Box::new(TransactionId::new())])?;
+    let mut req = SendInfo {
+        from: SocketAddr::from_str("192.168.0.2:5000")?,
+        to: server,
+    }
+    .encode()?;
+    req.extend_from_slice(&request.raw);
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+
+    let relayed = relayed_binding_success(&req, mapped);
+    assert_eq!(relayed[0], RelayPacketType::RecvInfo.as_u8());
+    let relay = SocketAddr::from_str("127.0.0.1:12345")?;
+    let decoded = decode_stun_response(
+        decode_relay_frame(&relayed)?,
+        request.transaction_id,
+        relay,
+        server,
+        relay,
+    )?;
+    assert!(decoded.was_relayed);
+
+    // The same response without the relay's framing
+    let direct = binding_success(relay_payload(&req), mapped);
+    let decoded = decode_stun_response(
+        decode_relay_frame(&direct.raw)?,
+        request.transaction_id,
+        server,
+        server,
+        server,
+    )?;
+    assert!(!decoded.was_relayed);
+    assert_eq!(decoded.message.raw, direct.raw);
+
+    // Passed on unframed by the relay the request went through
+    let decoded = decode_stun_response(
+        decode_relay_frame(&direct.raw)?,
+        request.transaction_id,
+        relay,
+        server,
+        relay,
+    )?;
+    assert!(!decoded.was_relayed);
+    assert_eq!(decoded.from, relay);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_discards_transaction_mismatch() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let stray = SocketAddr::from_str("9.9.9.9:9999")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| {
+            // A response to some other request arrives ahead of ours
+            let mut other = relay_header(req).to_vec();
+            let mut msg = Message::new();
+            msg.build(&[Box::new(BINDING_REQUEST), Box::new(TransactionId::new())])
+                .unwrap();
+            other.extend_from_slice(&msg.raw);
+            vec![
+                relayed_binding_success(&other, stray),
+                relayed_binding_success(req, mapped),
+            ]
+        }),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+
+    let (addr, _, _) = get_xormapped_addr(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(addr.ip, mapped.ip());
+    assert_eq!(addr.port, mapped.port());
+    assert_eq!(mock.sent.lock().await.len(), 1);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_discards_datagrams_from_other_peers() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let spoofed = SocketAddr::from_str("6.6.6.6:6666")?;
+    let mock = Arc::new(
+        MockConn::new(
+            SocketAddr::from_str("192.168.0.2:5000")?,
+            Box::new(move |req| vec![relayed_binding_success(req, mapped)]),
+        )
+        // A well-formed answer to our transaction, but not sent by the relay
+        .with_spoofer(
+            SocketAddr::from_str("10.9.9.9:23456")?,
+            Box::new(move |req| vec![relayed_binding_success(req, spoofed)]),
+        ),
+    );
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+
+    let (addr, _, _) = get_xormapped_addr(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(addr.ip, mapped.ip());
+    assert_eq!(addr.port, mapped.port());
+    assert_eq!(mock.sent.lock().await.len(), 1);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_gather_srflx_on_existing_conn() -> Result<()> {
+    let local_addr = SocketAddr::from_str("192.168.0.2:5000")?;
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let mock = Arc::new(MockConn::new(
+        local_addr,
+        Box::new(move |req| vec![relayed_binding_success(req, mapped)]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+
+    let candidate = gather_srflx_on(
+        Arc::clone(&conn),
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+    )
+    .await?;
+    assert_eq!(candidate.candidate_type(), CandidateType::ServerReflexive);
+    assert_eq!(candidate.network_type(), NetworkType::Udp4);
+    assert_eq!(candidate.address(), mapped.ip().to_string());
+    assert_eq!(candidate.port(), mapped.port());
+    assert_eq!(
+        candidate.related_address(),
+        Some(CandidateRelatedAddress {
+            address: local_addr.ip().to_string(),
+            port: local_addr.port(),
+        })
+    );
+    // The request went out on the given conn, nothing new was bound
+    assert_eq!(mock.sent.lock().await.len(), 1);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_session_id() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    // Answers in the session the request came from, or in `answer_session` if set
+    let relay = |answer_session: Option<u16>| -> Responder {
+        Box::new(move |req| {
+            let mut req = req.to_vec();
+            assert_eq!(req[1..3], [0x01, 0x02], "request not tagged");
+            let n = strip_session_ids(&mut req, 0x0102).unwrap();
+            let mut resp = relayed_binding_success(&req[..n], mapped);
+            insert_session_id(&mut resp, answer_session.unwrap_or(0x0102));
+            vec![resp]
+        })
+    };
+    let relay_config = RelayConfig {
+        session_id: 0x0102,
+        ..Default::default()
+    };
+
+    for (answer_session, expected) in [
+        (None, Ok(mapped)),
+        (
+            Some(0x0103),
+            Err(Error::ErrRelaySessionMismatch(0x0102, 0x0103)),
+        ),
+    ] {
+        let conn: Arc<dyn Conn + Send + Sync> = Arc::new(MockConn::new(
+            SocketAddr::from_str("192.168.0.2:5000")?,
+            relay(answer_session),
+        ));
+        let result = get_binding_result(
+            &conn,
+            SocketAddr::from_str("1.2.3.4:3478")?,
+            Duration::from_secs(1),
+            &relay_config,
+            None,
+            None,
+            None,
+        )
+        .await;
+        assert_eq!(result.map(|res| res.mapped), expected);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_advertised_local_addr() -> Result<()> {
+    let advertised = SocketAddr::from_str("192.168.0.2:5000")?;
+    let server_addr = SocketAddr::from_str("1.2.3.4:3478")?;
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let conn = Arc::new(MockConn::new(
+        SocketAddr::from_str("0.0.0.0:5000")?,
+        Box::new(move |req| vec![relayed_binding_success(req, mapped)]),
+    ));
+    let relay_config = RelayConfig {
+        advertised_local_addr: Some(advertised),
+        ..Default::default()
+    };
+
+    let result = get_binding_result(
+        &(Arc::clone(&conn) as Arc<dyn Conn + Send + Sync>),
+        server_addr,
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(result.mapped, mapped);
+
+    // The relay is told the advertised address, not the wildcard the socket is bound to
+    let sent = conn.sent.lock().await;
+    assert_eq!(
+        SendInfo::decode(&sent[0].0)?,
+        SendInfo {
+            from: advertised,
+            to: server_addr,
+        }
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_relay_metrics() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let relay_metrics = Arc::new(RelayMetrics::default());
+    // Short datagrams are waited past, a single attempt counts each answer once
+    let relay_config = RelayConfig {
+        relay_metrics: Some(Arc::clone(&relay_metrics)),
+        max_retransmits: 0,
+        ..Default::default()
+    };
+
+    let answers: [Responder; 4] = [
+        Box::new(move |req| vec![relayed_binding_success(req, mapped)]),
+        Box::new(move |req| vec![binding_success(relay_payload(req), mapped).raw]),
+        Box::new(|_| vec![vec![0xff; MESSAGE_HEADER_SIZE]]),
+        Box::new(|_| vec![vec![RelayPacketType::RecvInfo.as_u8(), 2]]),
+    ];
+    for answer in answers {
+        let conn: Arc<dyn Conn + Send + Sync> = Arc::new(MockConn::new(
+            SocketAddr::from_str("192.168.0.2:5000")?,
+            answer,
+        ));
+        // Only the relayed response comes from the server, the rest are rejected
+        let _ = get_binding_result(
+            &conn,
+            SocketAddr::from_str("1.2.3.4:3478")?,
+            Duration::from_secs(1),
+            &relay_config,
+            None,
+            None,
+            None,
+        )
+        .await;
+    }
+
+    assert_eq!(
+        relay_metrics.snapshot(),
+        RelayMetricsSnapshot {
+            relayed: 1,
+            raw_stun: 1,
+            unknown_type: 1,
+            too_short: 1,
+        }
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_coalesced_frames() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let stray = SocketAddr::from_str("9.9.9.9:9999")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| {
+            // The relay packs a response to some other request and ours into one datagram
+            let mut other = relay_header(req).to_vec();
+            let mut msg = Message::new();
+            msg.build(&[Box::new(BINDING_REQUEST), Box::new(TransactionId::new())])
+                .unwrap();
+            other.extend_from_slice(&msg.raw);
+            let mut datagram = relayed_binding_success(&other, stray);
+            datagram.extend(relayed_binding_success(req, mapped));
+            vec![datagram]
+        }),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+
+    let (addr, _, _) = get_xormapped_addr(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(addr.ip, mapped.ip());
+    assert_eq!(addr.port, mapped.port());
+    assert_eq!(mock.sent.lock().await.len(), 1);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_software_attribute() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| vec![relayed_binding_success(req, mapped)]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let server = SocketAddr::from_str("1.2.3.4:3478")?;
+
+    for software in [None, Some("webrtc-rs ice".to_owned())] {
+        let relay_config = RelayConfig {
+            software: software.clone(),
+            ..Default::default()
+        };
+        stun_request(
+            &conn,
+            server,
+            Duration::from_secs(1),
+            &relay_config,
+            None,
+            None,
+            None,
+        )
+        .await?;
+
+        let (req, _) = mock.sent.lock().await.pop().unwrap();
+        let msg = stun_message(relay_payload(&req));
+        match software {
+            Some(software) => {
+                assert_eq!(Software::get_from_as(&msg, ATTR_SOFTWARE)?.text, software);
+                assert!(req
+                    .windows(software.len())
+                    .any(|window| window == software.as_bytes()));
+            }
+            None => assert!(!msg.contains(ATTR_SOFTWARE)),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+/// Like `binding_success`, with a FINGERPRINT that is corrupted if `valid` is false.
+fn fingerprinted_binding_success(req: &[u8], mapped: SocketAddr, valid: bool) -> Message {
+    let mut resp = binding_success(relay_payload(req), mapped);
+    FINGERPRINT.add_to(&mut resp).unwrap();
+    if !valid {
+        let last = resp.raw.len() - 1;
+        resp.raw[last] ^= 0xFF;
+    }
+    resp
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_fingerprint_in_request() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| vec![relayed_binding_success(req, mapped)]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let server = SocketAddr::from_str("1.2.3.4:3478")?;
+
+    for use_fingerprint in [false, true] {
+        let relay_config = RelayConfig {
+            software: Some("webrtc-rs ice".to_owned()),
+            use_fingerprint,
+            ..Default::default()
+        };
+        stun_request(
+            &conn,
+            server,
+            Duration::from_secs(1),
+            &relay_config,
+            None,
+            None,
+            None,
+        )
+        .await?;
+
+        let (req, _) = mock.sent.lock().await.pop().unwrap();
+        let msg = stun_message(relay_payload(&req));
+        assert_eq!(msg.contains(ATTR_FINGERPRINT), use_fingerprint);
+        if use_fingerprint {
+            FINGERPRINT.check(&msg)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_verifies_response_fingerprint() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let server = SocketAddr::from_str("1.2.3.4:3478")?;
+
+    for valid in [true, false] {
+        let mock = Arc::new(MockConn::new(
+            SocketAddr::from_str("192.168.0.2:5000")?,
+            Box::new(move |req| {
+                vec![relayed_response(
+                    req,
+                    &fingerprinted_binding_success(req, mapped, valid),
+                )]
+            }),
+        ));
+        let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+
+        let result = stun_request(
+            &conn,
+            server,
+            Duration::from_secs(1),
+            &RelayConfig::default(),
+            None,
+            None,
+            None,
+        )
+        .await;
+        if valid {
+            assert!(result?.message.contains(ATTR_FINGERPRINT));
+        } else {
+            assert_eq!(
+                result.err(),
+                Some(Error::Stun(stun::Error::ErrFingerprintMismatch))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_full_mtu_response() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| {
+            let mut resp = binding_success(relay_payload(req), mapped);
+            // Pad the response to exactly 1280 bytes
+            let padding = 1280 - resp.raw.len() - ATTRIBUTE_HEADER_SIZE;
+            resp.add(AttrType(0x8050), &vec![0; padding]);
+            assert_eq!(resp.raw.len(), 1280);
+            vec![relayed_response(req, &resp)]
+        }),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+
+    let resp = stun_request(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(resp.message.raw.len(), 1280);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[test]
+fn test_decode_stun_response_short_frame() -> Result<()> {
+    // Announces a 14 byte header but carries only part of it
+    let frame = [
+        RelayPacketType::RecvInfo.as_u8(),
+        14,
+        ADDR_FAMILY_IPV4,
+        10,
+        0,
+    ];
+    let src = SocketAddr::from_str("127.0.0.1:12345")?;
+    assert_eq!(
+        decode_relay_frame(&frame)
+            .and_then(|frame| decode_stun_response(frame, TransactionId::new(), src, src, src))
+            .err(),
+        Some(Error::ErrTruncatedRelayFrame)
+    );
+    assert_eq!(
+        decode_relay_frame(&[])
+            .and_then(|frame| decode_stun_response(frame, TransactionId::new(), src, src, src))
+            .err(),
+        Some(Error::ErrUnknownRelayPacketType)
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+/// Wraps a `MockConn`, delaying every response and counting the requests awaiting one.
+struct CountingConn {
+    inner: MockConn,
+    in_flight: Arc<AtomicUsize>,
+    max_in_flight: Arc<AtomicUsize>,
+}
+
+#[cfg(feature = "relay")]
+#[async_trait]
+impl Conn for CountingConn {
+    async fn connect(&self, addr: SocketAddr) -> std::result::Result<(), util::Error> {
+        self.inner.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> std::result::Result<usize, util::Error> {
+        self.inner.recv(buf).await
+    }
+
+    async fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> std::result::Result<(usize, SocketAddr), util::Error> {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let received = self.inner.recv_from(buf).await;
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        received
+    }
+
+    async fn send(&self, buf: &[u8]) -> std::result::Result<usize, util::Error> {
+        self.inner.send(buf).await
+    }
+
+    async fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> std::result::Result<usize, util::Error> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+        self.inner.send_to(buf, target).await
+    }
+
+    fn local_addr(&self) -> std::result::Result<SocketAddr, util::Error> {
+        self.inner.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    async fn close(&self) -> std::result::Result<(), util::Error> {
+        self.inner.close().await
+    }
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_gather_from_servers_concurrent_limit() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let max_in_flight = Arc::new(AtomicUsize::new(0));
+    let servers: Vec<SocketAddr> = (1..=10)
+        .map(|i| SocketAddr::from_str(&format!("1.2.3.{i}:3478")))
+        .collect::<std::result::Result<_, _>>()?;
+
+    let mut rx = gather_from_servers_concurrent(
+        servers.clone(),
+        Duration::from_secs(1),
+        RelayConfig::default(),
+        2,
+        None,
+        {
+            let (in_flight, max_in_flight) = (Arc::clone(&in_flight), Arc::clone(&max_in_flight));
+            move |_| {
+                let conn: Arc<dyn Conn + Send + Sync> = Arc::new(CountingConn {
+                    inner: MockConn::new(
+                        SocketAddr::from_str("192.168.0.2:5000").unwrap(),
+                        Box::new(move |req| vec![relayed_binding_success(req, mapped)]),
+                    ),
+                    in_flight: Arc::clone(&in_flight),
+                    max_in_flight: Arc::clone(&max_in_flight),
+                });
+                async move { Ok(conn) }
+            }
+        },
+    );
+
+    let mut answered = vec![];
+    while let Some((server, result)) = rx.recv().await {
+        let (addr, _, _) = result?;
+        assert_eq!(addr.ip, mapped.ip());
+        answered.push(server);
+    }
+    answered.sort();
+    assert_eq!(answered, servers);
+    assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_trickle_candidates_host_before_srflx() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let (up, down) = (
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        SocketAddr::from_str("1.2.3.5:3478")?,
+    );
+    let hosts = vec![
+        IpAddr::from_str("192.168.0.2")?,
+        IpAddr::from_str("10.0.0.2")?,
+    ];
+
+    // The STUN answer only comes in after a while, one server never answers
+    let srflx_results = gather_from_servers_concurrent(
+        vec![up, down],
+        Duration::from_millis(200),
+        RelayConfig::default(),
+        2,
+        None,
+        move |server| async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let conn: Arc<dyn Conn + Send + Sync> = Arc::new(MockConn::new(
+                SocketAddr::from_str("192.168.0.2:5000")?,
+                Box::new(move |req| {
+                    if server == up {
+                        vec![relayed_binding_success(req, mapped)]
+                    } else {
+                        vec![]
+                    }
+                }),
+            ));
+            Ok(conn)
+        },
+    );
+
+    let mut rx = trickle_candidates(hosts.clone(), srflx_results);
+    for ip in hosts {
+        assert_eq!(rx.recv().await, Some(TrickleCandidate::Host(ip)));
+    }
+    assert_eq!(
+        rx.recv().await,
+        Some(TrickleCandidate::ServerReflexive {
+            server: up,
+            mapped,
+            local_addr: SocketAddr::from_str("192.168.0.2:5000")?,
+        })
+    );
+    assert_eq!(rx.recv().await, None);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_gather_from_servers_fails_fast_without_relay() -> Result<()> {
+    // Datagrams to a relay that is not running vanish without an answer
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(|_| vec![]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let relay_config = RelayConfig {
+        relay_check_timeout: Some(Duration::from_millis(20)),
+        ..Default::default()
+    };
+    let servers = [
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        SocketAddr::from_str("1.2.3.5:3478")?,
+    ];
+
+    let started = Instant::now();
+    let result = gather_from_servers(
+        &conn,
+        &servers,
+        Duration::from_secs(5),
+        &relay_config,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(result.err(), Some(Error::ErrRelayUnavailable));
+    assert!(started.elapsed() < Duration::from_millis(500));
+    // Only the OpenSocket frame was sent, no server was queried
+    let sent = mock.sent.lock().await;
+    assert_eq!(sent.len(), 1);
+    assert!(matches!(
+        decode_relay_frame(&sent[0].0)?,
+        RelayFrame::OpenSocket(_)
+    ));
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_check_relay_available() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| match decode_relay_frame(req) {
+            Ok(RelayFrame::OpenSocket(addr)) => {
+                vec![encode_open_socket(RelayPacketType::OpenSocketAck, addr).unwrap()]
+            }
+            _ => vec![relayed_binding_success(req, mapped)],
+        }),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let relay_config = RelayConfig {
+        relay_check_timeout: Some(Duration::from_millis(20)),
+        ..Default::default()
+    };
+
+    check_relay_available(&conn, &relay_config, None).await?;
+    let (addr, _, _) = gather_from_servers(
+        &conn,
+        &[SocketAddr::from_str("1.2.3.4:3478")?],
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(addr.port, mapped.port());
+
+    // Direct requests do not depend on the relay
+    let direct = RelayConfig {
+        mode: RelayMode::Direct,
+        ..relay_config
+    };
+    let silent: Arc<dyn Conn + Send + Sync> = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(|_| vec![]),
+    ));
+    check_relay_available(&silent, &direct, None).await?;
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_gather_from_servers_fallback() -> Result<()> {
+    let down = SocketAddr::from_str("1.2.3.4:3478")?;
+    let up = SocketAddr::from_str("1.2.3.5:3478")?;
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| {
+            if SendInfo::decode(req).unwrap().to == down {
+                vec![]
+            } else {
+                vec![relayed_binding_success(req, mapped)]
+            }
+        }),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let relay_config = RelayConfig {
+        rto: Duration::from_millis(10),
+        min_rto: Duration::ZERO,
+        max_retransmits: 1,
+        ..Default::default()
+    };
+
+    let (addr, _, _) = gather_from_servers(
+        &conn,
+        &[down, up],
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(addr.ip, mapped.ip());
+    assert_eq!(addr.port, mapped.port());
+
+    let result = gather_from_servers(
+        &conn,
+        &[down],
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+    )
+    .await;
+    match result {
+        Err(Error::ErrStunServersFailed(errors)) => {
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].0, down);
+        }
+        Err(err) => panic!("expected ErrStunServersFailed, got {err}"),
+        Ok(_) => panic!("expected ErrStunServersFailed"),
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_gather_with_budget() -> Result<()> {
+    let slow: Vec<SocketAddr> = (1..=4)
+        .map(|i| SocketAddr::new(IpAddr::from([1, 2, 3, i]), 3478))
+        .collect();
+    let fast = SocketAddr::from_str("1.2.3.9:3478")?;
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| {
+            if SendInfo::decode(req).unwrap().to == fast {
+                vec![relayed_binding_success(req, mapped)]
+            } else {
+                vec![]
+            }
+        }),
+    ));
+    let relay_config = RelayConfig::default();
+
+    // Serially, each slow server alone would take longer than the whole budget
+    let budget = Duration::from_millis(300);
+    let servers = [&slow[..], &[fast]].concat();
+    let started = Instant::now();
+    let result = gather_with_budget(&conn, &servers, budget, &relay_config, None, None).await;
+    assert!(started.elapsed() < budget + Duration::from_millis(150));
+    match result {
+        Err(Error::ErrStunServersFailed(errors)) => {
+            assert!(errors.iter().all(|(server, _)| *server != fast));
+        }
+        Err(err) => panic!("expected ErrStunServersFailed, got {err}"),
+        Ok(_) => panic!("expected the budget to run out before {fast}"),
+    }
+
+    // Without the relay padding every server gets its share, leaving time for the last
+    let relay_config = RelayConfig {
+        relay_timeout_padding: Duration::ZERO,
+        ..relay_config
+    };
+    let (addr, _, _) = gather_with_budget(
+        &conn,
+        &servers,
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(addr.port, mapped.port());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_probe_udp_port_in_range() -> Result<()> {
+    let laddr = SocketAddr::from_str("127.0.0.1:0")?;
+
+    let port = probe_udp_port_in_range(20110, 20100, laddr, PortAllocationStrategy::Random).await?;
+    assert!((20100..=20110).contains(&port));
+    // Nothing holds on to the probed port
+    let socket = tokio::net::UdpSocket::bind(SocketAddr::new(laddr.ip(), port)).await?;
+
+    // A taken port is skipped like listen_udp_in_port_range would
+    assert_eq!(
+        probe_udp_port_in_range(port, port, laddr, PortAllocationStrategy::Sequential).await,
+        Err(Error::ErrPortRangeExhausted(port, port))
+    );
+    drop(socket);
+    assert_eq!(
+        probe_udp_port_in_range(port, port, laddr, PortAllocationStrategy::Sequential).await?,
+        port
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_listen_udp_sequential() -> Result<()> {
+    let nw = Arc::new(Net::new(Some(util::vnet::net::NetConfig::default())));
+    let laddr = SocketAddr::from_str("127.0.0.1:0")?;
+
+    let mut conns = vec![];
+    for expected in 20000..=20002 {
+        let conn = listen_udp_in_port_range_with_strategy(
+            &nw,
+            20002,
+            20000,
+            laddr,
+            PortAllocationStrategy::Sequential,
+        )
+        .await?;
+        assert_eq!(conn.local_addr()?.port(), expected);
+        conns.push(conn);
+    }
+
+    // The range is exhausted, the scan must wrap around and give up
+    let result = listen_udp_in_port_range_with_strategy(
+        &nw,
+        20002,
+        20000,
+        laddr,
+        PortAllocationStrategy::Sequential,
+    )
+    .await;
+    assert_eq!(
+        result.err(),
+        Some(Error::ErrPortRangeExhausted(20000, 20002))
+    );
+
+    // Freed ports are picked up again, lowest first
+    conns.remove(1).close().await?;
+    let conn = listen_udp_in_port_range_with_strategy(
+        &nw,
+        20002,
+        20000,
+        laddr,
+        PortAllocationStrategy::Sequential,
+    )
+    .await?;
+    assert_eq!(conn.local_addr()?.port(), 20001);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_listen_udp_exhausted_single_port_range() -> Result<()> {
+    let nw = Arc::new(Net::new(Some(util::vnet::net::NetConfig::default())));
+    let laddr = SocketAddr::from_str("127.0.0.1:0")?;
+
+    let conn = listen_udp_in_port_range(&nw, 20010, 20010, laddr).await?;
+    assert_eq!(bound_addr(&conn)?, SocketAddr::from_str("127.0.0.1:20010")?);
+
+    let err = listen_udp_in_port_range(&nw, 20010, 20010, laddr)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err, Error::ErrPortRangeExhausted(20010, 20010));
+    assert!(err.to_string().contains("[20010, 20010]"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_listen_in_port_range_top_of_range() -> Result<()> {
+    let laddr = SocketAddr::from_str("127.0.0.1:0")?;
+
+    // Every port of the full range is tried once, the scan never wraps through port 0
+    let mut tried = vec![];
+    let result: Result<()> =
+        listen_in_port_range(0xFFFF, 1, laddr, &ListenOptions::default(), |laddr| {
+            tried.push(laddr.port());
+            async { Err(Error::ErrPort) }
+        })
+        .await;
+    assert_eq!(result.err(), Some(Error::ErrPortRangeExhausted(1, 0xFFFF)));
+    assert_eq!(tried.len(), 0xFFFF);
+    assert!(!tried.contains(&0));
+    tried.sort_unstable();
+    tried.dedup();
+    assert_eq!(tried.len(), 0xFFFF);
+
+    let nw = Arc::new(Net::new(Some(util::vnet::net::NetConfig::default())));
+    let _taken = listen_udp_in_port_range(&nw, 65534, 65534, laddr).await?;
+    let conn = listen_udp_in_port_range_with_strategy(
+        &nw,
+        65535,
+        65534,
+        laddr,
+        PortAllocationStrategy::Sequential,
+    )
+    .await?;
+    assert_eq!(bound_addr(&conn)?.port(), 65535);
+    let err = listen_udp_in_port_range(&nw, 65535, 65534, laddr)
+        .await
+        .err();
+    assert_eq!(err, Some(Error::ErrPortRangeExhausted(65534, 65535)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_listen_udp_in_port_range_forbid_ephemeral() -> Result<()> {
+    let nw = Arc::new(Net::new(Some(util::vnet::net::NetConfig::default())));
+    let options = ListenOptions {
+        forbid_ephemeral: true,
+        ..Default::default()
+    };
+
+    let err = listen_udp_in_port_range_with_options(
+        &nw,
+        0,
+        0,
+        SocketAddr::from_str("127.0.0.1:0")?,
+        &options,
+    )
+    .await
+    .err();
+    assert_eq!(err, Some(Error::ErrEphemeralPortForbidden));
+
+    // A range, or a port of its own, keeps the OS from choosing
+    let conn = listen_udp_in_port_range_with_options(
+        &nw,
+        20041,
+        20040,
+        SocketAddr::from_str("127.0.0.1:0")?,
+        &options,
+    )
+    .await?;
+    assert!((20040..=20041).contains(&bound_addr(&conn)?.port()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_listen_in_port_range_retries_transient_failures() -> Result<()> {
+    let laddr = SocketAddr::from_str("127.0.0.1:0")?;
+    let options = ListenOptions {
+        strategy: PortAllocationStrategy::Sequential,
+        bind_retries: 1,
+        bind_retry_delay: Duration::from_millis(1),
+        ..Default::default()
+    };
+    let bind_error =
+        |kind: std::io::ErrorKind| Error::Util(util::Error::from(std::io::Error::from(kind)));
+
+    // The interface is back on the retry, the port is kept
+    let mut tried = vec![];
+    let port = listen_in_port_range(20031, 20030, laddr, &options, |laddr| {
+        tried.push(laddr.port());
+        let attempt = tried.len();
+        async move {
+            match attempt {
+                1 => Err(bind_error(std::io::ErrorKind::AddrNotAvailable)),
+                _ => Ok((laddr.port(), laddr)),
+            }
+        }
+    })
+    .await?;
+    assert_eq!(port, 20030);
+    assert_eq!(tried, [20030, 20030]);
+
+    // A port in use is not retried, and retries are bounded
+    let mut tried = vec![];
+    let result = listen_in_port_range(20031, 20030, laddr, &options, |laddr| {
+        tried.push(laddr.port());
+        let attempt = tried.len();
+        async move {
+            match attempt {
+                1 => Err(bind_error(std::io::ErrorKind::AddrInUse)),
+                2 | 3 => Err(bind_error(std::io::ErrorKind::AddrNotAvailable)),
+                _ => Ok((laddr.port(), laddr)),
+            }
+        }
+    })
+    .await;
+    assert_eq!(
+        result.err(),
+        Some(Error::ErrPortRangeExhausted(20030, 20031))
+    );
+    assert_eq!(tried, [20030, 20031, 20031]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_listen_tcp_in_port_range() -> Result<()> {
+    let laddr = SocketAddr::from_str("127.0.0.1:0")?;
+
+    let mut listeners = vec![];
+    for _ in 0..2 {
+        let listener =
+            listen_tcp_in_port_range(20021, 20020, laddr, PortAllocationStrategy::Random).await?;
+        let port = listener.local_addr()?.port();
+        assert!((20020..=20021).contains(&port), "port {port} out of range");
+        listeners.push(listener);
+    }
+    assert_ne!(
+        listeners[0].local_addr()?.port(),
+        listeners[1].local_addr()?.port()
+    );
+
+    let result =
+        listen_tcp_in_port_range(20021, 20020, laddr, PortAllocationStrategy::Sequential).await;
+    assert_eq!(
+        result.err(),
+        Some(Error::ErrPortRangeExhausted(20020, 20021))
+    );
+
+    let result =
+        listen_tcp_in_port_range(20020, 20021, laddr, PortAllocationStrategy::Random).await;
+    assert_eq!(result.err(), Some(Error::ErrPort));
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_ipv6_loopback_relay() -> Result<()> {
+    let mapped = SocketAddr::from_str("[2001:db8::8]:40000")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("[2001:db8::2]:5000")?,
+        Box::new(move |req| vec![relayed_binding_success(req, mapped)]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+
+    let (addr, local_addr, _) = get_xormapped_addr(
+        &conn,
+        SocketAddr::from_str("[2001:db8::4]:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(addr.ip, mapped.ip());
+    assert_eq!(addr.port, mapped.port());
+    assert_eq!(local_addr, SocketAddr::from_str("[2001:db8::2]:5000")?);
+
+    let sent = mock.sent.lock().await;
+    assert_eq!(sent[0].1, SocketAddr::from_str("[::1]:12345")?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_interface_cache() -> Result<()> {
+    let r = Arc::new(Mutex::new(util::vnet::router::Router::new(
+        util::vnet::router::RouterConfig {
+            cidr: "1.2.3.0/24".to_owned(),
+            ..Default::default()
+        },
+    )?));
+    let nw = Arc::new(Net::new(Some(util::vnet::net::NetConfig::default())));
+    crate::agent::agent_vnet_test::connect_net2router(&nw, &r).await?;
+
+    // The IP filter runs once per address, so it counts the enumerations
+    let enumerations = Arc::new(AtomicUsize::new(0));
+    let enumerations2 = Arc::clone(&enumerations);
+    let ip_filter: Option<IpFilterFn> = Some(Box::new(move |_| {
+        enumerations2.fetch_add(1, Ordering::SeqCst);
+        true
+    }));
+
+    let cache = InterfaceCache::new(Duration::from_millis(50));
+    let first = cache
+        .local_interfaces(&nw, &None, &ip_filter, &[NetworkType::Udp4], false)
+        .await;
+    let second = cache
+        .local_interfaces(&nw, &None, &ip_filter, &[NetworkType::Udp4], false)
+        .await;
+    assert!(!first.is_empty());
+    assert_eq!(first, second);
+    assert_eq!(enumerations.load(Ordering::SeqCst), 1);
+
+    // Different network types are cached separately
+    cache
+        .local_interfaces(&nw, &None, &ip_filter, &[NetworkType::Udp6], false)
+        .await;
+    let count = enumerations.load(Ordering::SeqCst);
+
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    cache
+        .local_interfaces(&nw, &None, &ip_filter, &[NetworkType::Udp4], false)
+        .await;
+    assert_eq!(enumerations.load(Ordering::SeqCst), count + 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_is_link_local() -> Result<()> {
+    for ip in ["169.254.10.1", "fe80::1", "febf::1"] {
+        assert!(is_link_local(&IpAddr::from_str(ip)?), "{ip}");
+    }
+    for ip in ["1.2.3.4", "192.168.0.1", "2001:db8::1", "fec0::1"] {
+        assert!(!is_link_local(&IpAddr::from_str(ip)?), "{ip}");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_local_interfaces_link_local() -> Result<()> {
+    let r = Arc::new(Mutex::new(util::vnet::router::Router::new(
+        util::vnet::router::RouterConfig {
+            cidr: "169.254.1.0/24".to_owned(),
+            ..Default::default()
+        },
+    )?));
+    let nw = Arc::new(Net::new(Some(util::vnet::net::NetConfig::default())));
+    crate::agent::agent_vnet_test::connect_net2router(&nw, &r).await?;
+
+    let ips = local_interfaces(&nw, &None, &None, &[NetworkType::Udp4], false).await;
+    assert!(
+        ips.is_empty(),
+        "link-local addresses must be skipped: {ips:?}"
+    );
+
+    let ips = local_interfaces(&nw, &None, &None, &[NetworkType::Udp4], true).await;
+    assert_eq!(ips.len(), 1);
+    assert!(ips.iter().all(is_link_local));
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_rtt() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| vec![relayed_binding_success(req, mapped)]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let deadline = Duration::from_secs(1);
+
+    let started = Instant::now();
+    let (_, _, rtt) = get_xormapped_addr(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        deadline,
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert!(rtt <= started.elapsed());
+    assert!(rtt < deadline);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_without_timeout_padding() -> Result<()> {
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(|_| vec![]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let deadline = Duration::from_millis(100);
+    let relay_config = RelayConfig {
+        relay_timeout_padding: Duration::from_secs(0),
+        ..Default::default()
+    };
+    assert_eq!(relay_config.response_timeout(deadline), deadline);
+
+    let started = Instant::now();
+    let result = stun_request(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        deadline,
+        &relay_config,
+        None,
+        None,
+        None,
+    )
+    .await;
+    let elapsed = started.elapsed();
+    assert_eq!(result.err(), Some(Error::ErrStunTimeout));
+    assert!(elapsed >= deadline);
+    assert!(
+        elapsed < deadline + DEFAULT_RELAY_TIMEOUT_PADDING,
+        "gave up after {elapsed:?}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_create_addr_keeps_network_type() -> Result<()> {
+    let ip = IpAddr::from_str("10.0.0.1")?;
+
+    let udp = create_addr(NetworkType::Udp4, ip, 5000);
+    assert_eq!(udp.addr, SocketAddr::from_str("10.0.0.1:5000")?);
+    assert_eq!(udp.network, NetworkType::Udp4);
+    assert!(!udp.is_tcp());
+
+    let tcp = create_addr(NetworkType::Tcp4, ip, 5000);
+    assert_eq!(SocketAddr::from(tcp), udp.addr);
+    assert_eq!(tcp.network, NetworkType::Tcp4);
+    assert!(tcp.is_tcp());
+    assert_ne!(tcp, udp);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_metrics() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let up = SocketAddr::from_str("1.2.3.4:3478")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| {
+            let send_info = SendInfo::decode(req).unwrap();
+            if send_info.to == up {
+                vec![relayed_binding_success(req, mapped)]
+            } else if send_info.to.port() == 1 {
+                vec![vec![0xff; MESSAGE_HEADER_SIZE]]
+            } else {
+                vec![]
+            }
+        }),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let relay_config = RelayConfig {
+        rto: Duration::from_millis(1),
+        min_rto: Duration::ZERO,
+        max_retransmits: 1,
+        ..Default::default()
+    };
+    let metrics = StunMetrics::default();
+
+    for server in ["1.2.3.4:3478", "1.2.3.4:3478", "9.9.9.9:3478", "9.9.9.9:1"] {
+        let _ = stun_request(
+            &conn,
+            SocketAddr::from_str(server)?,
+            Duration::from_secs(1),
+            &relay_config,
+            Some(&metrics),
+            None,
+            None,
+        )
+        .await;
+    }
+
+    assert_eq!(
+        metrics.snapshot(),
+        StunMetricsSnapshot {
+            requests_sent: 4,
+            responses_ok: 2,
+            timeouts: 1,
+            parse_errors: 1,
+        }
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_integrity() -> Result<()> {
+    let credentials = Credentials {
+        username: "user".to_owned(),
+        password: "server-password".to_owned(),
+    };
+    let key = credentials.key().to_vec();
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let signing_key = key.clone();
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| {
+            let mut resp = binding_success(relay_payload(req), mapped);
+            MessageIntegrity(signing_key.clone())
+                .add_to(&mut resp)
+                .unwrap();
+            vec![relayed_response(req, &resp)]
+        }),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let server = SocketAddr::from_str("1.2.3.4:3478")?;
+
+    let (addr, _, _) = get_xormapped_addr(
+        &conn,
+        server,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        Some(&credentials),
+        None,
+    )
+    .await?;
+    assert_eq!(addr.port, mapped.port());
+
+    let result = get_xormapped_addr(
+        &conn,
+        server,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        Some(&Credentials {
+            password: "wrong-password".to_owned(),
+            ..credentials.clone()
+        }),
+        None,
+    )
+    .await;
+    assert_eq!(
+        result.err(),
+        Some(Error::Stun(stun::Error::ErrIntegrityMismatch))
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_with_credentials() -> Result<()> {
+    let credentials = Credentials {
+        username: "user:peer".to_owned(),
+        password: "short-term-password".to_owned(),
+    };
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(|_| vec![]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let relay_config = RelayConfig {
+        use_fingerprint: true,
+        ..Default::default()
+    };
+
+    let result = stun_request(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_millis(10),
+        &relay_config,
+        None,
+        Some(&credentials),
+        None,
+    )
+    .await;
+    assert_eq!(result.err(), Some(Error::ErrStunTimeout));
+
+    // The relayed request carries both attributes, signed with the password
+    let sent = mock.sent.lock().await;
+    let (frame, _) = &sent[0];
+    let mut request = Message::new();
+    request.raw = relay_payload(frame).to_vec();
+    request.decode()?;
+    assert_inbound_username(&request, &credentials.username)?;
+    assert_inbound_message_integrity(&mut request, credentials.key())?;
+    FINGERPRINT.check(&request)?;
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_detects_misrouted_response() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let other_server = SocketAddr::from_str("9.9.9.9:3478")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| {
+            let send_info = SendInfo::decode(req).unwrap();
+            let recv_info = SendInfo {
+                from: other_server,
+                to: send_info.from,
+            };
+            let response = binding_success(relay_payload(req), mapped);
+            vec![recv_info_frame(recv_info, &response.raw)]
+        }),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let server = SocketAddr::from_str("1.2.3.4:3478")?;
+
+    let result = stun_request(
+        &conn,
+        server,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(
+        result.err(),
+        Some(Error::ErrRelayMisroute(server, other_server))
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_reuses_pooled_buffer() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| vec![relayed_binding_success(req, mapped)]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let pool = Arc::new(BufferPool::default());
+    let relay_config = RelayConfig {
+        buffer_pool: Some(Arc::clone(&pool)),
+        ..Default::default()
+    };
+
+    for _ in 0..2 {
+        stun_request(
+            &conn,
+            SocketAddr::from_str("1.2.3.4:3478")?,
+            Duration::from_secs(1),
+            &relay_config,
+            None,
+            None,
+            None,
+        )
+        .await?;
+        // The buffer taken by the first request is the one every later request gets
+        assert_eq!(pool.len(), 1);
+    }
+    let buf = pool.take(16);
+    let ptr = buf.as_ptr();
+    pool.give_back(buf);
+    stun_request(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+        None,
+    )
+    .await?;
+    let buf = pool.take(16);
+    assert_eq!(buf.as_ptr(), ptr);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[cfg(feature = "tracing")]
+#[tracing_test::traced_test]
+#[tokio::test]
+async fn test_stun_request_tracing_spans() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| vec![relayed_binding_success(req, mapped)]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+
+    get_xormapped_addr(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await?;
+
+    // The request span is nested in the gather span and carries all its fields
+    let relay_addr = RelayConfig::default().relay_addr;
+    assert!(logs_contain(
+        "get_xormapped_addr{server_addr=1.2.3.4:3478}:stun_request{server_addr=1.2.3.4:3478 \
+         transaction_id=TransactionId("
+    ));
+    assert!(logs_contain(&format!(
+        "relay_addr={relay_addr} outcome=\"ok\"}}"
+    )));
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_detects_reflected_send_info() -> Result<()> {
+    // The relay loops our request back instead of forwarding it
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(|req| vec![req.to_vec()]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let metrics = StunMetrics::default();
+
+    let result = stun_request(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        Some(&metrics),
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(result.err(), Some(Error::ErrRelayLoopback));
+    assert_eq!(metrics.snapshot().parse_errors, 1);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[test]
+fn test_decode_stun_response_server_mismatch() -> Result<()> {
+    let server = SocketAddr::from_str("1.2.3.4:3478")?;
+    let other_server = SocketAddr::from_str("9.9.9.9:3478")?;
+    let mut request = Message::new();
+    request.build(&[Box::new(BINDING_REQUEST), Box::new(TransactionId::new())])?;
+    let mut req = SendInfo {
+        from: SocketAddr::from_str("192.168.0.2:5000")?,
+        to: other_server,
+    }
+    .encode()?;
+    req.extend_from_slice(&request.raw);
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+
+    // Relayed on behalf of another server
+    let relayed = relayed_binding_success(&req, mapped);
+    assert_eq!(
+        decode_stun_response(
+            decode_relay_frame(&relayed)?,
+            request.transaction_id,
+            SocketAddr::from_str("127.0.0.1:12345")?,
+            server,
+            SocketAddr::from_str("127.0.0.1:12345")?
+        )
+        .err(),
+        Some(Error::ErrRelayMisroute(server, other_server))
+    );
+
+    // Sent directly by another server
+    let direct = binding_success(relay_payload(&req), mapped);
+    assert_eq!(
+        decode_stun_response(
+            decode_relay_frame(&direct.raw)?,
+            request.transaction_id,
+            other_server,
+            server,
+            server
+        )
+        .err(),
+        Some(Error::ErrStunServerMismatch(server, other_server))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_validate_stun_server() -> Result<()> {
+    for addr in [
+        "0.0.0.0:3478",
+        "[::]:3478",
+        "127.0.0.1:3478",
+        "[::1]:3478",
+        "1.2.3.4:0",
+    ] {
+        let addr = SocketAddr::from_str(addr)?;
+        assert_eq!(
+            validate_stun_server(addr),
+            Err(Error::ErrInvalidStunServer(addr)),
+            "{addr} should be rejected"
+        );
+    }
+    validate_stun_server(SocketAddr::from_str("1.2.3.4:3478")?)?;
+    validate_stun_server(SocketAddr::from_str("[2001:db8::1]:3478")?)?;
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_get_xormapped_addr_mapped_address_fallback() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| {
+            // A legacy RFC 3489 server only knows MAPPED-ADDRESS
+            let req_msg = stun_message(relay_payload(req));
+            let mut resp = Message::new();
+            resp.build(&[
+                Box::new(BINDING_SUCCESS),
+                Box::new(req_msg.transaction_id),
+                Box::new(MappedAddress {
+                    ip: mapped.ip(),
+                    port: mapped.port(),
+                }),
+            ])
+            .unwrap();
+            vec![relayed_response(req, &resp)]
+        }),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+
+    let (addr, _, _) = get_xormapped_addr(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(addr.ip, mapped.ip());
+    assert_eq!(addr.port, mapped.port());
+
+    Ok(())
+}
+
+#[test]
+fn test_mapped_addr_from_prefers_xor_mapped_address() -> Result<()> {
+    let xor_mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let mut msg = Message::new();
+    msg.build(&[
+        Box::new(BINDING_SUCCESS),
+        Box::new(TransactionId::new()),
+        Box::new(MappedAddress {
+            ip: IpAddr::from_str("10.0.0.1")?,
+            port: 1234,
+        }),
+        Box::new(XorMappedAddress {
+            ip: xor_mapped.ip(),
+            port: xor_mapped.port(),
+        }),
+    ])?;
+    assert_eq!(mapped_addr_from(&msg)?, xor_mapped);
+
+    let mut empty = Message::new();
+    empty.build(&[Box::new(BINDING_SUCCESS), Box::new(TransactionId::new())])?;
+    assert_eq!(
+        mapped_addr_from(&empty),
+        Err(Error::Stun(stun::Error::ErrAttributeNotFound))
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_get_xormapped_addr_family_mismatch() -> Result<()> {
+    let mapped = SocketAddr::from_str("[2001:db8::8]:40000")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| vec![relayed_binding_success(req, mapped)]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+
+    let result = get_xormapped_addr(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(result.err(), Some(Error::ErrAddressFamilyMismatch));
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_canceled() -> Result<()> {
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(|_| vec![]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let cancel = CancellationToken::new();
+
+    let canceler = cancel.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        canceler.cancel();
+    });
+
+    let started = Instant::now();
+    let result = stun_request(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(10),
+        &RelayConfig::default(),
+        None,
+        None,
+        Some(&cancel),
+    )
+    .await;
+    assert_eq!(result.err(), Some(Error::ErrCanceled));
+    assert!(
+        started.elapsed() < Duration::from_millis(500),
+        "canceled after {:?}",
+        started.elapsed()
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_open_relay_socket() -> Result<()> {
+    let local_addr = SocketAddr::from_str("192.168.0.2:5000")?;
+    let mock = Arc::new(MockConn::new(
+        local_addr,
+        Box::new(|req| match decode_relay_frame(req) {
+            Ok(RelayFrame::OpenSocket(addr)) => vec![
+                // A stale acknowledgement for another socket is ignored
+                encode_open_socket(
+                    RelayPacketType::OpenSocketAck,
+                    SocketAddr::from_str("192.168.0.2:5001").unwrap(),
+                )
+                .unwrap(),
+                encode_open_socket(RelayPacketType::OpenSocketAck, addr).unwrap(),
+            ],
+            _ => vec![],
+        }),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+
+    open_relay_socket(&conn, &RelayConfig::default(), None).await?;
+
+    let sent = mock.sent.lock().await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(
+        decode_relay_frame(&sent[0].0)?,
+        RelayFrame::OpenSocket(local_addr)
+    );
+    assert_eq!(sent[0].1, DEFAULT_RELAY_ADDR);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_open_relay_socket_without_ack() -> Result<()> {
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(|_| vec![]),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let relay_config = RelayConfig {
+        rto: Duration::from_millis(1),
+        min_rto: Duration::ZERO,
+        max_retransmits: 2,
+        ..Default::default()
+    };
+
+    let result = open_relay_socket(&conn, &relay_config, None).await;
+    assert_eq!(result.err(), Some(Error::ErrRelayHandshakeFailed));
+    assert_eq!(mock.sent.lock().await.len(), 3);
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_register_relay_socket() -> Result<()> {
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("127.0.0.1:5000")?,
+        Box::new(|req| match decode_relay_frame(req) {
+            Ok(RelayFrame::OpenSocket(addr)) => {
+                vec![encode_open_socket(RelayPacketType::OpenSocketAck, addr).unwrap()]
+            }
+            _ => vec![],
+        }),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let relay_addr = SocketAddr::from_str("127.0.0.1:23456")?;
+    let relay_config = RelayConfig {
+        relay_addr,
+        ..Default::default()
+    };
+
+    // The virtual network has no relay, its sockets are left alone
+    let vnet = Net::new(Some(util::vnet::net::NetConfig::default()));
+    register_relay_socket(&vnet, &conn, &relay_config, None).await?;
+    assert!(mock.sent.lock().await.is_empty());
+
+    // Sockets of the real network go to the configured relay
+    let ifs = Net::new(None);
+    register_relay_socket(&ifs, &conn, &relay_config, None).await?;
+    {
+        let sent = mock.sent.lock().await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, relay_addr);
+    }
+
+    let unregistered = RelayConfig {
+        open_socket: false,
+        ..relay_config.clone()
+    };
+    register_relay_socket(&ifs, &conn, &unregistered, None).await?;
+    let direct = RelayConfig {
+        mode: RelayMode::Direct,
+        ..relay_config
+    };
+    register_relay_socket(&ifs, &conn, &direct, None).await?;
+    assert_eq!(mock.sent.lock().await.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stun_request_direct_mode() -> Result<()> {
+    let mapped = SocketAddr::from_str("5.6.7.8:40000")?;
+    let mock = Arc::new(MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(move |req| {
+            // No relay framing, the request is a plain STUN message
+            let mut req_msg = Message::new();
+            req_msg.raw = req.to_vec();
+            req_msg.decode().unwrap();
+            let mut resp = Message::new();
+            resp.build(&[
+                Box::new(BINDING_SUCCESS),
+                Box::new(req_msg.transaction_id),
+                Box::new(XorMappedAddress {
+                    ip: mapped.ip(),
+                    port: mapped.port(),
+                }),
+            ])
+            .unwrap();
+            vec![resp.raw]
+        }),
+    ));
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+    let server = SocketAddr::from_str("1.2.3.4:3478")?;
+    let relay_config = RelayConfig {
+        mode: RelayMode::Direct,
+        ..Default::default()
+    };
+
+    let (addr, _, _) = get_xormapped_addr(
+        &conn,
+        server,
+        Duration::from_secs(1),
+        &relay_config,
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert_eq!(addr.ip, mapped.ip());
+    assert_eq!(addr.port, mapped.port());
+
+    let sent = mock.sent.lock().await;
+    assert_eq!(sent[0].1, server);
+    assert!(is_message(&sent[0].0));
+
+    Ok(())
+}
+
+/// Queries a public STUN server without the relay, run with `--ignored`.
+#[tokio::test]
+#[ignore]
+async fn test_stun_request_direct_mode_public_server() -> Result<()> {
+    let server = tokio::net::lookup_host("stun.l.google.com:19302")
+        .await?
+        .find(|addr| addr.is_ipv4())
+        .expect("no IPv4 address for the STUN server");
+    let conn: Arc<dyn Conn + Send + Sync> =
+        Arc::new(tokio::net::UdpSocket::bind("0.0.0.0:0").await?);
+    let relay_config = RelayConfig {
+        mode: RelayMode::Direct,
+        ..Default::default()
+    };
+
+    let (addr, _, rtt) = get_xormapped_addr(
+        &conn,
+        server,
+        Duration::from_secs(5),
+        &relay_config,
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert!(addr.ip.is_ipv4());
+    assert_ne!(addr.port, 0);
+    assert!(rtt < Duration::from_secs(5));
+
+    Ok(())
+}
+
+#[test]
+fn test_cap_host_addrs_prefers_global() -> Result<()> {
+    // 50 addresses as on a host full of containers and VPN tunnels
+    let global: Vec<IpAddr> = (1..=4)
+        .map(|i| IpAddr::from_str(&format!("203.0.113.{i}")))
+        .chain([IpAddr::from_str("2001:db8::1")])
+        .collect::<std::result::Result<_, _>>()?;
+    let private: Vec<IpAddr> = (0..20)
+        .map(|i| IpAddr::from_str(&format!("172.17.{i}.1")))
+        .chain((0..14).map(|i| IpAddr::from_str(&format!("10.8.{i}.1"))))
+        .chain((0..5).map(|i| IpAddr::from_str(&format!("100.64.0.{}", i + 1))))
+        .chain((0..5).map(|i| IpAddr::from_str(&format!("fd00::{}", i + 1))))
+        .collect::<std::result::Result<_, _>>()?;
+    let link_local = vec![IpAddr::from_str("169.254.0.1")?];
+    let addrs: Vec<IpAddr> = [link_local, private, global.clone()].concat();
+    assert_eq!(addrs.len(), 50);
+
+    let kept = cap_host_addrs(addrs.iter().rev().copied(), Some(8));
+    assert_eq!(kept.len(), 8);
+    // All global addresses survive, the lowest private ones fill up the rest
+    assert_eq!(&kept[..5], &global[..]);
+    assert_eq!(
+        &kept[5..],
+        &[
+            IpAddr::from_str("10.8.0.1")?,
+            IpAddr::from_str("10.8.1.1")?,
+            IpAddr::from_str("10.8.2.1")?,
+        ]
+    );
+
+    // Without a cap nothing is dropped
+    assert_eq!(cap_host_addrs(addrs.clone(), None), addrs);
+
+    Ok(())
+}
+
+#[test]
+fn test_is_private() -> Result<()> {
+    for ip in [
+        "10.0.0.1",
+        "172.16.0.1",
+        "192.168.1.1",
+        "100.64.0.1",
+        "fd00::1",
+    ] {
+        assert!(is_private(&IpAddr::from_str(ip)?), "{ip}");
+    }
+    for ip in [
+        "8.8.8.8",
+        "100.128.0.1",
+        "169.254.0.1",
+        "2001:db8::1",
+        "fe80::1",
+    ] {
+        assert!(!is_private(&IpAddr::from_str(ip)?), "{ip}");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_local_interfaces_empty_network_types() -> Result<()> {
+    let vnet = Arc::new(Net::Ifs(vec![Interface::new(
+        "eth0".to_owned(),
+        vec![
+            ipnet::IpNet::from_str("192.168.0.2/24").unwrap(),
+            ipnet::IpNet::from_str("2001:db8::2/64").unwrap(),
+        ],
+    )]));
+
+    let ips = local_interfaces(&vnet, &None, &None, &[], false).await;
+    assert_eq!(
+        ips,
+        HashSet::from([
+            IpAddr::from_str("192.168.0.2")?,
+            IpAddr::from_str("2001:db8::2")?
+        ])
+    );
+
+    let grouped = local_interfaces_by_network_type(&vnet, &None, &None, &[], false).await;
+    assert_eq!(
+        grouped[&NetworkType::Udp4],
+        vec![IpAddr::from_str("192.168.0.2")?]
+    );
+    assert_eq!(
+        grouped[&NetworkType::Udp6],
+        vec![IpAddr::from_str("2001:db8::2")?]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_default_route_interfaces() -> Result<()> {
+    let interface = |name: &str, addrs: &[&str], default_route: bool| {
+        let mut iface = Interface::new(
+            name.to_owned(),
+            addrs
+                .iter()
+                .map(|addr| ipnet::IpNet::from_str(addr).unwrap())
+                .collect(),
+        );
+        iface.set_default_route(default_route);
+        iface
+    };
+    let interfaces = vec![
+        interface("lo", &["127.0.0.1/8"], false),
+        interface("eth0", &["192.168.0.2/24", "2001:db8::2/64"], true),
+        interface("docker0", &["172.17.0.1/16"], false),
+    ];
+
+    let vnet = Arc::new(Net::Ifs(interfaces.clone()));
+    let ips = default_route_interfaces(&vnet).await;
+    assert_eq!(
+        ips,
+        HashSet::from([
+            IpAddr::from_str("192.168.0.2")?,
+            IpAddr::from_str("2001:db8::2")?
+        ])
+    );
+
+    // Combined with `local_interfaces` its filters apply as well
+    let local = local_interfaces(&vnet, &None, &None, &[NetworkType::Udp4], false).await;
+    let preferred: HashSet<IpAddr> = local.intersection(&ips).copied().collect();
+    assert_eq!(preferred, HashSet::from([IpAddr::from_str("192.168.0.2")?]));
+
+    // Without route info every interface is kept
+    let unrouted = interfaces
+        .into_iter()
+        .map(|mut iface| {
+            iface.set_default_route(false);
+            iface
+        })
+        .collect();
+    let vnet = Arc::new(Net::Ifs(unrouted));
+    assert_eq!(default_route_interfaces(&vnet).await.len(), 4);
+
+    Ok(())
+}
+
+#[test]
+fn test_local_interfaces_detailed_keeps_names() -> Result<()> {
+    let interfaces = vec![
+        Interface::new(
+            "lo0".to_owned(),
+            vec![ipnet::IpNet::from_str("127.0.0.1/8").unwrap()],
+        ),
+        Interface::new(
+            "eth0".to_owned(),
+            vec![
+                ipnet::IpNet::from_str("192.168.0.2/24").unwrap(),
+                ipnet::IpNet::from_str("2001:db8::2/64").unwrap(),
+            ],
+        ),
+        Interface::new(
+            "wwan0".to_owned(),
+            vec![ipnet::IpNet::from_str("10.20.30.40/8").unwrap()],
+        ),
+    ];
+
+    let addrs = filter_interface_addrs(&interfaces, &None, &None, &[NetworkType::Udp4], false);
+    assert_eq!(
+        addrs,
+        vec![
+            ("eth0".to_owned(), IpAddr::from_str("192.168.0.2")?),
+            ("wwan0".to_owned(), IpAddr::from_str("10.20.30.40")?),
+        ]
+    );
+
+    // The filters apply the same way as in `local_interfaces`
+    let interface_filter: Option<InterfaceFilterFn> = Some(Box::new(|name| name != "wwan0"));
+    let addrs = filter_interface_addrs(
+        &interfaces,
+        &interface_filter,
+        &None,
+        &[NetworkType::Udp4, NetworkType::Udp6],
+        false,
+    );
+    assert_eq!(
+        addrs,
+        vec![
+            ("eth0".to_owned(), IpAddr::from_str("192.168.0.2")?),
+            ("eth0".to_owned(), IpAddr::from_str("2001:db8::2")?),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_group_by_network_type_many_addrs() -> Result<()> {
+    // 10 interfaces with 5 IPv4 and 5 IPv6 addresses each
+    let interfaces: Vec<Interface> = (0..10)
+        .map(|i| {
+            let addrs = (0..5)
+                .flat_map(|j| {
+                    [
+                        format!("10.{i}.0.{}/24", j + 1),
+                        format!("2001:db8:{i}::{}/64", j + 1),
+                    ]
+                })
+                .map(|addr| ipnet::IpNet::from_str(&addr).unwrap())
+                .collect();
+            Interface::new(format!("veth{i}"), addrs)
+        })
+        .collect();
+    let network_types = [
+        NetworkType::Udp4,
+        NetworkType::Udp6,
+        NetworkType::Tcp4,
+        NetworkType::Udp4,
+    ];
+
+    let started = Instant::now();
+    for _ in 0..1000 {
+        let addrs = filter_interface_addrs(&interfaces, &None, &None, &network_types, false);
+        assert_eq!(addrs.len(), 100);
+        let grouped = group_by_network_type(addrs.into_iter().map(|(_, ip)| ip), &network_types);
+        assert_eq!(grouped.len(), 3);
+        assert!(grouped[&NetworkType::Udp4].iter().all(IpAddr::is_ipv4));
+        assert_eq!(grouped[&NetworkType::Udp4].len(), 50);
+        assert_eq!(grouped[&NetworkType::Tcp4], grouped[&NetworkType::Udp4]);
+        assert!(grouped[&NetworkType::Udp6].iter().all(IpAddr::is_ipv6));
+        assert_eq!(grouped[&NetworkType::Udp6].len(), 50);
+    }
+    log::debug!(
+        "1000 passes over 100 addresses took {:?}",
+        started.elapsed()
+    );
+
+    // No requested network type stands for both families
+    assert_eq!(
+        filter_interface_addrs(&interfaces, &None, &None, &[], false).len(),
+        100
+    );
+    assert!(group_by_network_type([IpAddr::from_str("10.0.0.1")?], &[]).is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_local_interfaces_detailed_vnet() -> Result<()> {
+    let r = Arc::new(Mutex::new(util::vnet::router::Router::new(
+        util::vnet::router::RouterConfig {
+            cidr: "1.2.3.0/24".to_owned(),
+            ..Default::default()
+        },
+    )?));
+    let nw = Arc::new(Net::new(Some(util::vnet::net::NetConfig::default())));
+    crate::agent::agent_vnet_test::connect_net2router(&nw, &r).await?;
+
+    let detailed = local_interfaces_detailed(&nw, &None, &None, &[NetworkType::Udp4], false).await;
+    assert_eq!(detailed.len(), 1);
+    assert_eq!(detailed[0].0, "eth0");
+
+    let ips = local_interfaces(&nw, &None, &None, &[NetworkType::Udp4], false).await;
+    assert_eq!(ips, HashSet::from([detailed[0].1]));
+
+    Ok(())
+}
+
+#[cfg(feature = "relay")]
+#[tokio::test]
+async fn test_stun_request_without_local_addr() -> Result<()> {
+    let mut mock = MockConn::new(
+        SocketAddr::from_str("192.168.0.2:5000")?,
+        Box::new(|_| vec![]),
+    );
+    mock.local_addr = None;
+    let mock = Arc::new(mock);
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&mock) as _;
+
+    let result = stun_request(
+        &conn,
+        SocketAddr::from_str("1.2.3.4:3478")?,
+        Duration::from_secs(1),
+        &RelayConfig::default(),
+        None,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(result.err(), Some(Error::ErrNoLocalAddr));
+    assert_eq!(
+        open_relay_socket(&conn, &RelayConfig::default(), None)
+            .await
+            .err(),
+        Some(Error::ErrNoLocalAddr)
+    );
+    assert!(mock.sent.lock().await.is_empty());
+
+    Ok(())
+}
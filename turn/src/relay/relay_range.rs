@@ -58,7 +58,7 @@ impl RelayAddressGenerator for RelayAddressGeneratorRanges {
                 .net
                 .resolve_addr(use_ipv4, &format!("{}:{}", self.address, requested_port))
                 .await?;
-            let conn = self.net.bind(addr, 0).await?;
+            let conn = self.net.bind(addr).await?;
             let mut relay_addr = conn.local_addr()?;
             relay_addr.set_ip(self.relay_address);
             return Ok((conn, relay_addr));
@@ -70,7 +70,7 @@ impl RelayAddressGenerator for RelayAddressGeneratorRanges {
                 .net
                 .resolve_addr(use_ipv4, &format!("{}:{}", self.address, port))
                 .await?;
-            let conn = match self.net.bind(addr, 0).await {
+            let conn = match self.net.bind(addr).await {
                 Ok(conn) => conn,
                 Err(_) => continue,
             };
@@ -101,6 +101,8 @@ pub enum Error {
     ErrStaticIpIsBeyondSubnet,
     #[error("address space exhausted")]
     ErrAddressSpaceExhausted,
+    #[error("device {0} cannot reach the relay on loopback")]
+    ErrBindDeviceWithRelay(String),
     #[error("no IP address is assigned for eth0")]
     ErrNoIpaddrEth0,
     #[error("Invalid mask")]
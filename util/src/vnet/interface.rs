@@ -8,11 +8,16 @@ use crate::error::*;
 pub struct Interface {
     pub(crate) name: String,
     pub(crate) addrs: Vec<IpNet>,
+    pub(crate) default_route: bool,
 }
 
 impl Interface {
     pub fn new(name: String, addrs: Vec<IpNet>) -> Self {
-        Interface { name, addrs }
+        Interface {
+            name,
+            addrs,
+            default_route: false,
+        }
     }
 
     pub fn add_addr(&mut self, addr: IpNet) {
@@ -26,6 +31,17 @@ impl Interface {
         &self.addrs
     }
 
+    /// Marks the interface as one a default route goes through.
+    pub fn set_default_route(&mut self, default_route: bool) {
+        self.default_route = default_route;
+    }
+
+    /// Reports whether a default route goes through the interface. This is only known
+    /// for the system's interfaces on Linux, elsewhere it is always false.
+    pub fn is_default_route(&self) -> bool {
+        self.default_route
+    }
+
     pub fn convert(addr: SocketAddr, mask: Option<SocketAddr>) -> Result<IpNet> {
         if let Some(mask) = mask {
             Ok(IpNet::with_netmask(addr.ip(), mask.ip()).map_err(|_| Error::ErrInvalidMask)?)
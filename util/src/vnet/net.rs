@@ -1,7 +1,7 @@
 #[cfg(test)]
 mod net_test;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
 use std::sync::atomic::{AtomicU64, Ordering};
@@ -10,7 +10,8 @@ use std::time::Duration;
 
 use async_trait::async_trait;
 use ipnet::IpNet;
-use log::{info, warn};
+use log::info;
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::net::UdpSocket;
 use tokio::sync::Mutex;
 use tokio::time::sleep;
@@ -26,8 +27,6 @@ use crate::{conn, ifaces, Conn};
 pub(crate) const LO0_STR: &str = "lo0";
 pub(crate) const UDP_STR: &str = "udp";
 pub(crate) const MAX_BINDING_ATTEMPTS : usize = 100;
-pub const BINDING_PACKET_TYPE : u8 = 0xBB;
-pub const CONFIRM_BINDING_PACKET_TYPE : u8 = 0xBC;
 
 lazy_static! {
     pub static ref MAC_ADDR_COUNTER: AtomicU64 = AtomicU64::new(0xBEEFED910200);
@@ -36,6 +35,88 @@ lazy_static! {
 
 pub(crate) type HardwareAddr = Vec<u8>;
 
+/// How `Net::bind_with_options` sets up a socket before binding it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BindOptions {
+    /// Binds with SO_REUSEADDR and, where supported, SO_REUSEPORT. A non zero port in
+    /// the bound address is then used as is rather than a fresh one, so binding the
+    /// same address again yields a socket on the same port.
+    pub reuse_port: bool,
+    /// Pins the socket to this interface via SO_BINDTODEVICE before it is bound. This
+    /// is Linux only, elsewhere the device is ignored. The socket talks to the relay
+    /// over loopback, so `Net::Ifs` refuses any device without a loopback address with
+    /// `ErrBindDeviceWithRelay` rather than binding a socket that cannot reach it.
+    pub bind_device: Option<String>,
+}
+
+/// Binds a UDP socket to `addr`. With `reuse_port` set, SO_REUSEADDR and, where the
+/// platform has it, SO_REUSEPORT are enabled before binding so other sockets with the
+/// same options can share the port. A `bind_device` pins the socket to that interface
+/// with SO_BINDTODEVICE on Linux and is ignored on other platforms.
+pub(crate) fn bind_udp_socket(
+    addr: SocketAddr,
+    reuse_port: bool,
+    bind_device: Option<&str>,
+) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if reuse_port {
+        socket.set_reuse_address(true)?;
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    if let Some(device) = bind_device {
+        socket.bind_device(Some(device.as_bytes()))?;
+    }
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    let _ = bind_device;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Returns the names of the interfaces a default route goes through, as listed by the
+/// kernel's IPv4 and IPv6 routing tables.
+#[cfg(target_os = "linux")]
+fn default_route_interfaces() -> HashSet<String> {
+    let ipv4_routes = std::fs::read_to_string("/proc/net/route").unwrap_or_default();
+    let ipv6_routes = std::fs::read_to_string("/proc/net/ipv6_route").unwrap_or_default();
+    parse_default_routes(&ipv4_routes, &ipv6_routes)
+}
+
+/// Other platforms do not expose their routes, no interface is marked.
+#[cfg(not(target_os = "linux"))]
+fn default_route_interfaces() -> HashSet<String> {
+    HashSet::new()
+}
+
+/// Picks the interfaces of the default routes out of tables in the format of
+/// `/proc/net/route` and `/proc/net/ipv6_route`.
+#[cfg(any(target_os = "linux", test))]
+pub(crate) fn parse_default_routes(ipv4_routes: &str, ipv6_routes: &str) -> HashSet<String> {
+    let mut names = HashSet::new();
+    // Iface, Destination, Gateway, Flags, RefCnt, Use, Metric, Mask, ... after a header
+    for line in ipv4_routes.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() > 7 && fields[1] == "00000000" && fields[7] == "00000000" {
+            names.insert(fields[0].to_owned());
+        }
+    }
+    // Destination, prefix length, source, source prefix length, next hop, metric,
+    // RefCnt, Use, Flags, Iface. The kernel's unreachable ::/0 route sits on lo
+    for line in ipv6_routes.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() == 10
+            && fields[1] == "00"
+            && fields[0].bytes().all(|b| b == b'0')
+            && fields[9] != "lo"
+        {
+            names.insert(fields[9].to_owned());
+        }
+    }
+    names
+}
+
 pub(crate) fn new_mac_address() -> HardwareAddr {
     let b = MAC_ADDR_COUNTER
         .fetch_add(1, Ordering::SeqCst)
@@ -480,9 +561,12 @@ impl Net {
                 }
             }
 
+            let default_routes = default_route_interfaces();
             let mut ifs = vec![];
             for (name, addrs) in m.into_iter() {
-                ifs.push(Interface::new(name, addrs));
+                let mut ifc = Interface::new(name, addrs);
+                ifc.set_default_route(default_routes.contains(ifc.name()));
+                ifs.push(ifc);
             }
 
             Net::Ifs(ifs)
@@ -536,58 +620,55 @@ impl Net {
         }
     }
 
-    async fn send_binding_for_socket(&self, socket: Arc<UdpSocket>, addr: SocketAddr, relay_port: u16) -> Result<()> {
-        // info!("Sending binding information to relay");
-        let mut payload : Vec<u8> = Vec::new();
-        payload.push(BINDING_PACKET_TYPE);
-        match addr.ip() {
-            IpAddr::V4(ip) => {
-                let mut ip_bytes = ip.octets().to_vec();
-                payload.append(&mut ip_bytes);
-            },
-            IpAddr::V6(ip) => {
-                let mut ip_bytes = ip.octets().to_vec();
-                payload.append(&mut ip_bytes);
-            },
-        }
-        let mut port_bytes = addr.port().to_be_bytes().to_vec();
-        payload.append(&mut port_bytes);
-        let buf = payload.as_slice();
-        // Hardcoded for now (works with the same port because two different hosts in mininet)
-        // let port = 12345;
-        let local_relay = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), relay_port);
-        socket.send_to(&buf, local_relay).await?;
-
-        // In order not to proceed too fast, wait for an answer until the socket has been bound, then proceed
-        let mut confirm_buf :[u8; 10] = [0; 10];
-        let confirm_size = socket.recv(&mut confirm_buf).await.unwrap();
-        if confirm_size != 10 || confirm_buf[0] != CONFIRM_BINDING_PACKET_TYPE {
-            warn!("Check the implementation, something is wrong with the confirm packet after the socket bind!");
-        }
-
-        Ok(())
+    /// Binds a UDP socket for `addr`. On `Net::Ifs` the socket is bound on loopback with
+    /// the port it is given, so the relay can reach it; registering it with the relay is
+    /// left to the caller.
+    pub async fn bind(&self, addr: SocketAddr) -> Result<Arc<dyn Conn + Send + Sync>> {
+        self.bind_with_options(addr, &BindOptions::default()).await
     }
 
-    pub async fn bind(&self, addr: SocketAddr, relay_port: u16) -> Result<Arc<dyn Conn + Send + Sync>> {
+    /// Like `bind`, but sets up the socket as `options` describe before it is bound.
+    /// The virtual network ignores the options.
+    pub async fn bind_with_options(
+        &self,
+        addr: SocketAddr,
+        options: &BindOptions,
+    ) -> Result<Arc<dyn Conn + Send + Sync>> {
         match self {
             Net::VNet(vnet) => {
                 info!("Using the VNet to bind socket");
                 let net = vnet.lock().await;
                 net.bind(addr).await
             }
-            Net::Ifs(_) => {
+            Net::Ifs(ifs) => {
                 info!("Binding socket in ICE requested for: {}", addr);
+                if let Some(device) = &options.bind_device {
+                    let reaches_loopback = ifs.iter().any(|ifc| {
+                        ifc.name() == device
+                            && ifc.addrs().iter().any(|inet| inet.addr().is_loopback())
+                    });
+                    if !reaches_loopback {
+                        return Err(Error::ErrBindDeviceWithRelay(device.clone()));
+                    }
+                }
                 // Creating the mapping for the socket to the relay
                 let mut counter = 0;
-                let mut mapping = next_local_port();
+                let fixed_port = options.reuse_port && addr.port() != 0;
+                let mut mapping = if fixed_port { addr.port() } else { next_local_port() };
                 let mut localhost = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), mapping);
                 if addr.is_ipv6() {
                     let loopback = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1));
                     localhost.set_ip(loopback);
                 }
                 loop {
-                    let sock = match UdpSocket::bind(localhost).await {
+                    let sock = match bind_udp_socket(
+                        localhost,
+                        options.reuse_port,
+                        options.bind_device.as_deref(),
+                    ) {
                         Ok(s) => s,
+                        // The caller asked for this very port, do not hand out another one
+                        Err(err) if fixed_port => return Err(err.into()),
                         Err(_) => {
                             // Should be because the binding failed
                             if counter > MAX_BINDING_ATTEMPTS {
@@ -600,16 +681,7 @@ impl Net {
                         }
                     };
 
-                    // Store the original local addr somewhere to share with the remote
-                    // app. To make this as little a change as possible store this deep
-                    // down in the implementation (aka. here)
-                    // OR: Connect and send the info directly, than nothing is needed
-                    let socket = Arc::new(sock);
-                    // We use the assigned port to specify STUN source addresses later
-                    let mut bind_addr = addr.clone();
-                    bind_addr.set_port(socket.local_addr().unwrap().port());
-                    self.send_binding_for_socket(socket.clone(), bind_addr, relay_port).await?;
-                    return Ok(socket.clone());
+                    return Ok(Arc::new(sock));
                 }
                 return Err(crate::Error::ErrAddressSpaceExhausted);
                 // Ok(Arc::new(UdpSocket::bind(addr).await?))
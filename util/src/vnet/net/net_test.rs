@@ -58,7 +58,7 @@ async fn test_net_native_bind() -> Result<()> {
     let nw = Net::new(None);
     assert!(!nw.is_virtual(), "should be false");
 
-    let conn = nw.bind(SocketAddr::from_str("127.0.0.1:0")?, 0).await?;
+    let conn = nw.bind(SocketAddr::from_str("127.0.0.1:0")?).await?;
     let laddr = conn.local_addr()?;
     assert_eq!(
         laddr.ip().to_string(),
@@ -93,7 +93,7 @@ async fn test_net_native_loopback() -> Result<()> {
     let nw = Net::new(None);
     assert!(!nw.is_virtual(), "should be false");
 
-    let conn = nw.bind(SocketAddr::from_str("127.0.0.1:0")?, 0).await?;
+    let conn = nw.bind(SocketAddr::from_str("127.0.0.1:0")?).await?;
     let laddr = conn.local_addr()?;
 
     let msg = "PING!";
@@ -408,7 +408,7 @@ async fn test_net_virtual_loopback1() -> Result<()> {
     let nw = Net::new(Some(NetConfig::default()));
     assert!(nw.is_virtual(), "should be true");
 
-    let conn = nw.bind(SocketAddr::from_str("127.0.0.1:0")?, 0).await?;
+    let conn = nw.bind(SocketAddr::from_str("127.0.0.1:0")?).await?;
     let laddr = conn.local_addr()?;
 
     let msg = "PING!";
@@ -429,7 +429,7 @@ async fn test_net_virtual_bind_specific_port() -> Result<()> {
     let nw = Net::new(Some(NetConfig::default()));
     assert!(nw.is_virtual(), "should be true");
 
-    let conn = nw.bind(SocketAddr::from_str("127.0.0.1:50916")?, 0).await?;
+    let conn = nw.bind(SocketAddr::from_str("127.0.0.1:50916")?).await?;
     let laddr = conn.local_addr()?;
     assert_eq!(
         laddr.ip().to_string().as_str(),
@@ -554,7 +554,7 @@ async fn test_net_virtual_resolver() -> Result<()> {
 async fn test_net_virtual_loopback2() -> Result<()> {
     let nw = Net::new(Some(NetConfig::default()));
 
-    let conn = nw.bind(SocketAddr::from_str("127.0.0.1:50916")?, 0).await?;
+    let conn = nw.bind(SocketAddr::from_str("127.0.0.1:50916")?).await?;
     let laddr = conn.local_addr()?;
     assert_eq!(
         laddr.to_string().as_str(),
@@ -681,8 +681,8 @@ async fn test_net_virtual_end2end() -> Result<()> {
         get_ipaddr(&nic).await?
     };
 
-    let conn1 = net1.bind(SocketAddr::new(ip1, 1234), 0).await?;
-    let conn2 = net2.bind(SocketAddr::new(ip2, 5678), 0).await?;
+    let conn1 = net1.bind(SocketAddr::new(ip1, 1234)).await?;
+    let conn2 = net2.bind(SocketAddr::new(ip2, 5678)).await?;
 
     {
         let mut w = wan.lock().await;
@@ -815,9 +815,9 @@ async fn test_net_virtual_two_ips_on_a_nic() -> Result<()> {
     }
 
     let (conn1, conn2) = (
-        net.bind(SocketAddr::new(Ipv4Addr::from_str(DEMO_IP)?.into(), 1234), 0)
+        net.bind(SocketAddr::new(Ipv4Addr::from_str(DEMO_IP)?.into(), 1234))
             .await?,
-        net.bind(SocketAddr::new(Ipv4Addr::from_str("1.2.3.5")?.into(), 1234), 0)
+        net.bind(SocketAddr::new(Ipv4Addr::from_str("1.2.3.5")?.into(), 1234))
             .await?,
     );
 
@@ -901,3 +901,58 @@ async fn test_net_virtual_two_ips_on_a_nic() -> Result<()> {
     log::debug!("main recv done_ch_rx");
     Ok(())
 }
+
+#[tokio::test]
+async fn test_bind_udp_socket_reuse_port() -> Result<()> {
+    let first = bind_udp_socket(SocketAddr::from_str("127.0.0.1:0")?, true, None)?;
+    let addr = first.local_addr()?;
+
+    // Both sockets have to opt in for the port to be shared
+    assert!(bind_udp_socket(addr, false, None).is_err());
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    {
+        let second = bind_udp_socket(addr, true, None)?;
+        assert_eq!(second.local_addr()?, addr);
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_bind_udp_socket_to_device() -> Result<()> {
+    let addr = SocketAddr::from_str("127.0.0.1:0")?;
+    let sock = bind_udp_socket(addr, false, Some("lo"))?;
+    assert_eq!(
+        socket2::SockRef::from(&sock).device()?,
+        Some(b"lo".to_vec())
+    );
+
+    // An unknown device is refused rather than silently ignored
+    assert!(bind_udp_socket(addr, false, Some("nosuchdev0")).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_default_routes() {
+    let ipv4_routes = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t00000000\t010200C0\t0003\t0\t0\t0\t00000000\t0\t0\t0
+eth0\t000200C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+docker0\t000011AC\t00000000\t0001\t0\t0\t0\t0000FFFF\t0\t0\t0
+";
+    let ipv6_routes = "\
+fe800000000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000002 00000000 00000001     eth0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 fd000000000000000000000000000001 00000400 00000001 00000000 00000003     wg0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo
+";
+
+    let names = parse_default_routes(ipv4_routes, ipv6_routes);
+    let mut names: Vec<&str> = names.iter().map(String::as_str).collect();
+    names.sort_unstable();
+    assert_eq!(names, vec!["eth0", "wg0"]);
+
+    assert!(parse_default_routes("", "").is_empty());
+}